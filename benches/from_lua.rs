@@ -0,0 +1,74 @@
+//! Compare `LuaMessage::from_lua` on a 10k-element array with a generic key enumeration.
+//!
+//! Run with `cargo bench --bench from_lua`.
+use actix_lua::dev::rlua::{Context, FromLua, Lua, Result, Table, Value};
+use actix_lua::LuaMessage;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 100;
+
+// Enumerate every key from Rust, then decide whether the table is an array.
+fn generic_from_lua<'lua>(t: Table<'lua>, ctx: Context<'lua>) -> Result<LuaMessage> {
+    let mut pairs = Vec::new();
+    for pair in t.pairs::<Value, LuaMessage>() {
+        pairs.push(pair?);
+    }
+
+    let is_sequence = !pairs.is_empty()
+        && pairs.iter().all(|(k, _)| match k {
+            Value::Integer(i) => *i >= 1 && *i as usize <= pairs.len(),
+            _ => false,
+        });
+    if is_sequence {
+        let mut arr = vec![LuaMessage::Nil; pairs.len()];
+        for (k, v) in pairs {
+            if let Value::Integer(i) = k {
+                arr[i as usize - 1] = v;
+            }
+        }
+        Ok(LuaMessage::Array(arr))
+    } else {
+        let mut map = HashMap::new();
+        for (k, v) in pairs {
+            map.insert(String::from_lua(k, ctx)?, v);
+        }
+        Ok(LuaMessage::Table(map))
+    }
+}
+
+fn measure<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let lua = Lua::new();
+    lua.context(|ctx| {
+        // what `LuaActorBuilder::with_sequence_arrays` sets in the actor's VM
+        ctx.set_named_registry_value("actix_lua.sequence_arrays", true)
+            .unwrap();
+        let t: Table = ctx
+            .load("local t = {} for i = 1, 10000 do t[i] = i end return t")
+            .eval()
+            .unwrap();
+
+        let generic = measure(|| {
+            generic_from_lua(t.clone(), ctx).unwrap();
+        });
+        let fast = measure(|| {
+            LuaMessage::from_lua(Value::Table(t.clone()), ctx).unwrap();
+        });
+
+        println!("generic enumeration: {:?}/iter", generic);
+        println!("from_lua:            {:?}/iter", fast);
+        println!(
+            "speedup:             {:.2}x",
+            generic.as_secs_f64() / fast.as_secs_f64()
+        );
+    });
+}
@@ -0,0 +1,61 @@
+//! Measure the heap used by a 10k-row array of uniform tables, with and without interned keys.
+//!
+//! Run with `cargo bench --bench intern --features intern`.
+use actix_lua::{KeyPool, LuaMessage};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Count the bytes currently allocated.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn rows(n: i64) -> LuaMessage {
+    LuaMessage::Array(
+        (0..n)
+            .map(|i| {
+                let mut t = HashMap::new();
+                t.insert("user_id".to_string(), LuaMessage::from(i));
+                t.insert("display_name".to_string(), LuaMessage::from("someone"));
+                t.insert("created_at".to_string(), LuaMessage::from(1_546_300_800));
+                t.insert("is_active".to_string(), LuaMessage::from(true));
+                LuaMessage::Table(t)
+            })
+            .collect(),
+    )
+}
+
+fn main() {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let msg = rows(10_000);
+    let plain = ALLOCATED.load(Ordering::SeqCst) - before;
+
+    let mut pool = KeyPool::new();
+    let interned = msg.intern(&mut pool);
+    let interned_bytes = ALLOCATED.load(Ordering::SeqCst) - before;
+
+    println!("plain keys:    {} bytes", plain);
+    println!("interned keys: {} bytes", interned_bytes);
+    println!(
+        "saved:         {:.1}%",
+        100.0 * (1.0 - interned_bytes as f64 / plain as f64)
+    );
+    drop(interned);
+}
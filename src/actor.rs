@@ -1,13 +1,70 @@
 use ::actix::prelude::*;
 use ::actix::ActorContext;
+use actix::dev::{MessageResponse, ResponseChannel};
+use futures::sync::oneshot;
+use futures::{Future, Sink};
 use rlua::Error as LuaError;
-use rlua::{FromLua, Function, Lua, MultiValue, ToLua, Value};
+use rlua::{
+    AnyUserData, FromLua, Function, Lua, MultiValue, Table, ToLua, UserData, Value, Variadic,
+};
 
-use crate::message::LuaMessage;
+use crate::cache::{ScriptCache, DEFAULT_EVAL_CACHE_SIZE};
+use crate::error::LuaActorError;
+use crate::escape::EscapePolicy;
+use crate::host::{fnv1a, named_args, CrashReport, Data, MetricsSink, ParamKind};
+use crate::limit::CoroutineLimit;
+use crate::message::{
+    count_keys, error_from_lua, release_function, FunctionHandle, LuaMessage, INHERITED_FIELDS,
+    SEQUENCE_ARRAYS, USERDATA_FORMATTERS,
+};
+use std::any::{Any, TypeId};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::str;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub(crate) type Precondition = Box<dyn Fn(&LuaMessage) -> Result<(), String> + Send>;
+pub(crate) type MemoryCallback = Box<dyn Fn(isize, usize) + Send>;
+pub(crate) type ModuleResolver = Box<dyn Fn(&str) -> Option<String> + Send>;
+pub(crate) type CrashReporter = Box<dyn Fn(CrashReport) + Send>;
+pub(crate) type StreamCallback = Box<dyn FnMut(LuaMessage, LuaMessage) + Send>;
+pub(crate) type VmSetup =
+    Box<dyn for<'lua> FnOnce(rlua::Context<'lua>) -> Result<(), LuaError> + Send>;
+
+/// Asynchronous work started by a host function registered with `LuaActor::add_async_fn`.
+///
+/// An error is reported to the caller as the future's result, like a successful value.
+pub type HostFuture = Box<dyn Future<Item = LuaMessage, Error = LuaMessage> + Send>;
+
+// Host futures started by the current `HandleAsync` message, `None` outside of it.
+type PendingFutures = Arc<Mutex<Option<Vec<HostFuture>>>>;
+
+// Collects the host futures started while it's alive, and clears `pending` even if the script panics.
+struct PendingGuard(PendingFutures);
+
+impl PendingGuard {
+    fn new(pending: &PendingFutures, futures: Vec<HostFuture>) -> Self {
+        *pending.lock().unwrap() = Some(futures);
+        PendingGuard(pending.clone())
+    }
+
+    fn finish(self) -> Vec<HostFuture> {
+        self.0.lock().unwrap().take().unwrap_or_default()
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+// A `HandleAsync` message whose handler is suspended by `ctx.send`, with the host futures it started so far.
+struct SuspendedAsync {
+    futures: Vec<HostFuture>,
+    tx: oneshot::Sender<(LuaMessage, Vec<HostFuture>)>,
+}
 
 /// Top level struct which holds a lua state for itself.
 ///
@@ -19,6 +76,26 @@ use std::time::Duration;
 /// ### `ctx.msg`
 /// The message sent to Lua actor.
 ///
+/// ### `ctx.seq`
+/// The number of the message run by `handle`, starting at 1, so scripts can check their order.
+/// It's kept for coroutines suspended by `ctx.send`, and lives in the actor's Lua state, so a new actor starts over.
+///
+/// ### `on_message` hook
+/// A script set with `LuaActorBuilder::on_message_with_lua` runs before `handle` for every message.
+/// If it returns a value other than `nil`, the value is the response and `handle` is skipped.
+///
+/// ### `on_error` hook
+/// A script set with `LuaActorBuilder::on_error_with_lua` runs when `handle` raises an error, receiving the error
+/// as its argument: `local err = ...`. Its return value is the response. If it raises an error as well,
+/// the response is an `Error` message carrying `LuaActorError::ErrorHook`.
+/// It also runs when `handle` raises an error after being resumed from `ctx.send`, with `ctx.msg` set to the
+/// message the handler was started for.
+///
+/// ### Crash reports
+/// With `LuaActorBuilder::with_crash_reporter`, an error raised by `handle`, including after it was resumed
+/// from `ctx.send`, is reported to the host as a `CrashReport` before the `on_error` hook runs. The `debug` library isn't loaded, so the report carries the
+/// script position of the error rather than a full traceback.
+///
 /// ### `ctx.notify(msg)`
 /// Send message `msg` to self.
 ///
@@ -31,20 +108,70 @@ use std::time::Duration;
 /// Calling `ctx.send` yield the current coroutine and returns a `ThreadYield(thread_id)` message.
 /// LuaActor will wait for the response and resume the yielded coroutine once the response is returned.
 ///
-/// Equivalent to `actix::Recipient.send`.
+/// Equivalent to `actix::Recipient.send`. If the message can't be delivered, e.g. because the recipient stopped,
+/// `ctx.send` returns `nil` and an error carrying `LuaActorError::Mailbox`: `local result, err = ctx.send(recipient, msg)`.
+///
+/// Use a `CoroutineLimit` (see `LuaActorBuilder::with_coroutine_limit`) to cap suspended coroutines across actors.
 ///
 /// ### `ctx.do_send(recipient, msg)`
 /// Send message `msg` to `recipient`.
 ///
 /// Equivalent to `actix::Recipient.do_send`.
 ///
+/// ### `ctx.stop()`
+/// Stop actor execution gracefully.
+///
+/// With `LuaActorBuilder::with_shutdown_timeout`, coroutines suspended by `ctx.send` can complete first.
+///
 /// ### `ctx.terminate()`
 /// Terminate actor execution.
 ///
+/// ### Memory accounting
+/// Every allocation of the Lua VM is accounted, see `LuaActor::used_memory`.
+/// A limit set with `LuaActorBuilder::with_memory_limit` makes allocations beyond it fail with a memory error,
+/// and a callback set with `LuaActorBuilder::with_memory_callback` is notified of the change after each message.
+///
+/// ### Tracing
+/// With the `tracing` feature, each message is handled in a `lua_actor_handle` span with the actor name
+/// (see `LuaActorBuilder::with_name`) and the message tag (see `LuaMessage::tag`).
+/// A `Batch` runs in a `lua_actor_batch` span enclosing the spans of its messages, and `Eval`, `CallFunction`
+/// and the resumption of a coroutine suspended by `ctx.send` run in `lua_actor_eval`, `lua_actor_call`
+/// and `lua_actor_resume` spans.
+/// Spans opened by host functions become its children.
+///
 /// [`LuaActorBuilder`]: struct.LuaActorBuilder.html
 pub struct LuaActor {
     vm: Lua,
     pub recipients: HashMap<String, Recipient<LuaMessage>>,
+    pub(crate) eval_cache: ScriptCache,
+    pub(crate) escape: Option<Box<dyn EscapePolicy + Send>>,
+    pub(crate) preconditions: Vec<Precondition>,
+    pub(crate) name: Option<String>,
+    pub(crate) memory_callback: Option<MemoryCallback>,
+    pub(crate) coroutine_limit: Option<CoroutineLimit>,
+    pub(crate) max_message_bytes: Option<usize>,
+    pub(crate) source_access: bool,
+    pub(crate) maintenance_interval: Option<Duration>,
+    pub(crate) shutdown_timeout: Option<Duration>,
+    // set once the actor is stopping with suspended coroutines
+    shutdown_deadline: Option<Instant>,
+    pub(crate) json_arrays: bool,
+    pub(crate) batch_mode: BatchMode,
+    pub(crate) raise_host_errors: bool,
+    pub(crate) mailbox_batch_size: Option<usize>,
+    // messages waiting for the next run of `handle` with mailbox batching
+    mailbox_batch: Vec<(LuaMessage, oneshot::Sender<LuaMessage>)>,
+    global_tracking: bool,
+    // messages held back until `Resume`, see `Pause`
+    paused: Option<Vec<Deferred>>,
+    pub(crate) pause_queue_size: usize,
+    pending: PendingFutures,
+    // callbacks of `HandleStreaming` messages whose handler is suspended by `ctx.send`, by thread id
+    streaming: HashMap<String, StreamCallback>,
+    // `HandleAsync` messages whose handler is suspended by `ctx.send`, by thread id
+    suspended_async: HashMap<String, SuspendedAsync>,
+    output: Option<Arc<Mutex<CapturedOutput>>>,
+    data: HashMap<TypeId, Box<dyn Any + Send>>,
 }
 
 impl LuaActor {
@@ -56,7 +183,18 @@ impl LuaActor {
     ) -> Result<LuaActor, LuaError> {
         let prelude = include_str!("lua/prelude.lua");
         vm.context(|ctx| {
-            ctx.load(prelude).set_name("Prelude")?.exec()?;
+            let formatters: Table = ctx.load(prelude).set_name("Prelude")?.eval()?;
+            ctx.set_named_registry_value(USERDATA_FORMATTERS, formatters)?;
+            // before scripts can replace the global `next`
+            count_keys(ctx)?;
+            let call_failed = ctx.create_function(|ctx, err: Value| {
+                Ok(match LuaMessage::from_lua(err, ctx)? {
+                    err @ LuaMessage::Error { .. } => err,
+                    LuaMessage::String(err) => LuaMessage::error(err),
+                    err => LuaMessage::error(err.to_string()),
+                })
+            })?;
+            ctx.globals().set("__call_failed", call_failed)?;
             {
                 let load: Function = ctx.globals().get("__load")?;
                 if let Some(script) = started {
@@ -87,6 +225,30 @@ impl LuaActor {
         Result::Ok(LuaActor {
             vm,
             recipients: HashMap::new(),
+            eval_cache: ScriptCache::new(DEFAULT_EVAL_CACHE_SIZE),
+            escape: None,
+            preconditions: vec![],
+            name: None,
+            memory_callback: None,
+            coroutine_limit: None,
+            max_message_bytes: None,
+            source_access: false,
+            maintenance_interval: None,
+            shutdown_timeout: None,
+            shutdown_deadline: None,
+            json_arrays: true,
+            batch_mode: BatchMode::default(),
+            raise_host_errors: false,
+            mailbox_batch_size: None,
+            mailbox_batch: vec![],
+            global_tracking: false,
+            paused: None,
+            pause_queue_size: DEFAULT_PAUSE_QUEUE_SIZE,
+            pending: Arc::new(Mutex::new(None)),
+            streaming: HashMap::new(),
+            suspended_async: HashMap::new(),
+            output: None,
+            data: HashMap::new(),
         })
     }
 
@@ -108,6 +270,424 @@ impl LuaActor {
     ) -> Option<Recipient<LuaMessage>> {
         self.recipients.insert(name.to_string(), rec)
     }
+
+    /// Register a formatter for userdata of type `T`.
+    ///
+    /// The formatter is used by `tostring` in Lua. Userdata of type `T` returned from Lua is converted
+    /// to `LuaMessage::UserData`, which is displayed with the same formatter but can't be converted back to Lua.
+    pub fn add_userdata_formatter<T, F>(&mut self, type_name: &str, f: F) -> Result<(), LuaError>
+    where
+        T: UserData + 'static,
+        F: Fn(&T) -> String + Send + 'static,
+    {
+        self.vm.context(|ctx| {
+            let format = ctx.create_function(move |_, ud: AnyUserData| match ud.borrow::<T>() {
+                Ok(v) => Ok(Some(f(&v))),
+                Err(_) => Ok(None),
+            })?;
+            let formatters: Table = ctx.named_registry_value(USERDATA_FORMATTERS)?;
+            formatters.set(type_name, format)
+        })
+    }
+
+    /// Count the coroutines suspended by `ctx.send` against `limit`, which may be shared with other actors.
+    pub fn set_coroutine_limit(&mut self, limit: CoroutineLimit) -> Result<(), LuaError> {
+        let acquire = limit.clone();
+        self.vm.context(|ctx| {
+            let f = ctx.create_function(move |_, ()| {
+                if acquire.try_acquire() {
+                    Ok(())
+                } else {
+                    Err(LuaError::external(LuaActorError::CoroutineLimit(
+                        acquire.max(),
+                    )))
+                }
+            })?;
+            ctx.globals().set("__acquire_coroutine", f)
+        })?;
+        self.coroutine_limit = Some(limit);
+        Ok(())
+    }
+
+    /// Replace `require` with a loader asking `resolver` for the source of modules, so scripts can only
+    /// load the modules provided by the host.
+    pub(crate) fn set_module_resolver(&mut self, resolver: ModuleResolver) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let resolve = ctx.create_function(move |_, name: String| Ok(resolver(&name)))?;
+            ctx.globals().set("__resolve_module", resolve)?;
+            let require: Function = ctx.globals().get("__require")?;
+            ctx.globals().set("require", require)
+        })
+    }
+
+    /// Serve the registered host functions through the metatable of the globals, recording accesses.
+    pub(crate) fn track_globals(&mut self) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let track: Function = ctx.globals().get("__track_globals")?;
+            track.call::<_, ()>(())
+        })?;
+        self.global_tracking = true;
+        Ok(())
+    }
+
+    // Whether coroutines suspended by `ctx.send` are waiting for their reply.
+    fn has_suspended(&self) -> bool {
+        self.vm
+            .context(|ctx| {
+                let threads: Table = ctx.globals().get("__threads")?;
+                Ok(threads.pairs::<Value, Value>().next().is_some())
+            })
+            .unwrap_or_else(|e: LuaError| panic!("lua actor shutdown failed {:?}", e))
+    }
+
+    // Drop the suspended coroutines, giving back their slots of the coroutine limit.
+    fn drop_suspended(&mut self) {
+        let dropped = self
+            .vm
+            .context(|ctx| {
+                let drop_threads: Function = ctx.globals().get("__drop_threads")?;
+                drop_threads.call::<_, usize>(())
+            })
+            .unwrap_or_else(|e| panic!("lua actor shutdown failed {:?}", e));
+        if let Some(limit) = &self.coroutine_limit {
+            for _ in 0..dropped {
+                limit.release();
+            }
+        }
+        self.streaming.clear();
+        self.suspended_async.clear();
+    }
+
+    // Evict the coroutines suspended since before the previous tick, giving back their slots of the coroutine limit.
+    fn maintain(&mut self) -> Result<(), LuaError> {
+        let evicted = self.vm.context(|ctx| {
+            let maintain: Function = ctx.globals().get("__maintain")?;
+            maintain.call::<_, Vec<i64>>(())
+        })?;
+        for id in evicted {
+            if let Some(limit) = &self.coroutine_limit {
+                limit.release();
+            }
+            self.streaming.remove(&id.to_string());
+            self.suspended_async.remove(&id.to_string());
+        }
+        Ok(())
+    }
+
+    // Whether the coroutine `thread_id` is suspended, rather than completed or evicted.
+    fn is_suspended(&self, thread_id: i64) -> bool {
+        self.vm
+            .context(|ctx| {
+                let threads: Table = ctx.globals().get("__threads")?;
+                threads.contains_key(thread_id)
+            })
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn set_metrics_sink(&mut self, sink: Arc<dyn MetricsSink>) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let metrics = ctx.create_table()?;
+            let increment_sink = sink.clone();
+            let increment = ctx.create_function(move |_, name: String| {
+                increment_sink.increment(&name);
+                Ok(())
+            })?;
+            metrics.set("increment", increment)?;
+            let timing = ctx.create_function(move |_, (name, ms): (String, f64)| {
+                sink.timing(&name, ms);
+                Ok(())
+            })?;
+            metrics.set("timing", timing)?;
+            ctx.globals().set("metrics", metrics)
+        })
+    }
+
+    pub(crate) fn capture_output(&mut self, max: usize) -> Result<(), LuaError> {
+        let output = Arc::new(Mutex::new(CapturedOutput::default()));
+        self.output = Some(output.clone());
+        self.vm.context(|ctx| {
+            let print = ctx.create_function(move |ctx, args: Variadic<Value>| {
+                let tostring: Function = ctx.globals().get("tostring")?;
+                let line = args
+                    .into_iter()
+                    .map(|v| tostring.call::<_, String>(v))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join("\t");
+                let mut output = output.lock().unwrap();
+                output.push(&line, max);
+                output.push("\n", max);
+                Ok(())
+            })?;
+            ctx.globals().set("print", print)
+        })
+    }
+
+    pub(crate) fn set_error_hook(&mut self, script: &str) -> Result<(), LuaError> {
+        self.load_script("on_error", script)?;
+        self.vm.context(|ctx| {
+            let failed = ctx.create_function(|_, err: String| {
+                Ok(LuaMessage::from_error(LuaActorError::ErrorHook(err)))
+            })?;
+            ctx.globals().set("__error_hook_failed", failed)
+        })
+    }
+
+    pub(crate) fn set_crash_reporter(
+        &mut self,
+        sensitive_keys: Vec<String>,
+        reporter: CrashReporter,
+    ) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let report = ctx.create_function(move |ctx, (msg, error): (Value, String)| {
+                let sources: Table = ctx.globals().get("__sources")?;
+                let source: String = sources.get("handle")?;
+                let keys: Vec<&str> = sensitive_keys.iter().map(String::as_str).collect();
+                let input = LuaMessage::from_lua(msg, ctx).unwrap_or(LuaMessage::Nil);
+                reporter(CrashReport {
+                    source_hash: fnv1a(source.as_bytes()),
+                    source,
+                    input: input.redact(&keys),
+                    error,
+                });
+                Ok(())
+            })?;
+            ctx.globals().set("__report_crash", report)
+        })
+    }
+
+    pub(crate) fn setup_vm(&mut self, setup: VmSetup) -> Result<(), LuaError> {
+        self.vm.context(setup)
+    }
+
+    pub(crate) fn set_string_sandbox(&mut self, max: usize) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let cap: Function = ctx.globals().get("__cap_string_rep")?;
+            cap.call::<_, ()>(max)
+        })
+    }
+
+    pub(crate) fn set_long_lived_handle(&mut self) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().set("__long_lived", true))
+    }
+
+    // Forget the generator `id`, which won't be resumed.
+    fn drop_generator(&mut self, id: LuaMessage) {
+        let dropped = self.vm.context(|ctx| {
+            let drop_generator: Function = ctx.globals().get("__drop_generator")?;
+            drop_generator.call::<_, ()>(id)
+        });
+        if let Err(e) = dropped {
+            log::warn!("failed dropping a generator: {}", e);
+        }
+    }
+
+    pub(crate) fn set_sequence_arrays(&mut self) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.set_named_registry_value(SEQUENCE_ARRAYS, true))
+    }
+
+    pub(crate) fn set_inherited_fields(&mut self) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.set_named_registry_value(INHERITED_FIELDS, true))
+    }
+
+    /// Make `ctx.state` read-only while the `handle` script runs, so writes to it raise an error.
+    pub(crate) fn set_read_only_state(&mut self) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().set("__read_only_state", true))
+    }
+
+    /// Bytes currently allocated by the actor's Lua VM.
+    pub fn used_memory(&self) -> usize {
+        self.vm.used_memory()
+    }
+
+    /// Register a host function callable from Lua as the global `name`.
+    ///
+    /// The function receives its first argument as a `LuaMessage`, or `Nil` if it is called without arguments.
+    pub fn add_fn<F>(&mut self, name: &str, f: F) -> Result<(), LuaError>
+    where
+        F: Fn(LuaMessage) -> LuaMessage + Send + 'static,
+    {
+        self.inject_fn(name, move |msg| Ok(f(msg)))
+    }
+
+    /// Register a host function returning several values, like `add_fn`.
+    ///
+    /// The elements of the returned `Vec` are the return values in Lua: `local a, b = name(msg)`.
+    /// With `LuaActorBuilder::with_raised_host_errors`, the first `Error` message among them is raised.
+    pub fn add_fn_multi<F>(&mut self, name: &str, f: F) -> Result<(), LuaError>
+    where
+        F: Fn(LuaMessage) -> Vec<LuaMessage> + Send + 'static,
+    {
+        self.inject_fn_multi(name, move |msg| Ok(f(msg)))
+    }
+
+    /// Store shared host state, replacing a previous `Data` of the same type.
+    ///
+    /// Host functions registered with `add_fn_with_data` receive it; it can also be fetched with `data`
+    /// and captured by other closures.
+    pub fn add_data<T: Send + Sync + 'static>(&mut self, data: Data<T>) {
+        self.data.insert(TypeId::of::<T>(), Box::new(data));
+    }
+
+    /// The shared host state of type `T` stored with `add_data`, if any.
+    pub fn data<T: Send + Sync + 'static>(&self) -> Option<Data<T>> {
+        self.data
+            .get(&TypeId::of::<T>())
+            .and_then(|data| data.downcast_ref::<Data<T>>())
+            .cloned()
+    }
+
+    /// Register a host function like `add_fn`, receiving the `Data<T>` stored with `add_data` as well.
+    ///
+    /// Returns an error if no `Data<T>` is stored.
+    pub fn add_fn_with_data<T, F>(&mut self, name: &str, f: F) -> Result<(), LuaError>
+    where
+        T: Send + Sync + 'static,
+        F: Fn(&Data<T>, LuaMessage) -> LuaMessage + Send + 'static,
+    {
+        let data = self.data::<T>().ok_or_else(|| {
+            LuaError::RuntimeError(format!("no data of type {}", std::any::type_name::<T>()))
+        })?;
+        self.add_fn(name, move |msg| f(&data, msg))
+    }
+
+    /// Register a host function starting asynchronous work, callable from Lua as the global `name`.
+    ///
+    /// The function is only available while a `HandleAsync` message is handled, elsewhere calling it raises an error.
+    /// It returns the 1-based index of its result in the `async` field of the `HandleAsync` response.
+    pub fn add_async_fn<F>(&mut self, name: &str, f: F) -> Result<(), LuaError>
+    where
+        F: Fn(LuaMessage) -> HostFuture + Send + 'static,
+    {
+        let pending = self.pending.clone();
+        self.vm.context(|ctx| {
+            let f = ctx.create_function(move |_, msg: LuaMessage| {
+                let unavailable = || {
+                    LuaError::RuntimeError(
+                        "async host functions are only available in HandleAsync".to_string(),
+                    )
+                };
+                if pending.lock().unwrap().is_none() {
+                    return Err(unavailable());
+                }
+                // started outside of the lock, so a panicking function doesn't poison it
+                let future = f(msg);
+                let mut pending = pending.lock().unwrap();
+                let futures = pending.as_mut().ok_or_else(unavailable)?;
+                futures.push(future);
+                Ok(futures.len())
+            })?;
+            inject_global(ctx, name, f)
+        })
+    }
+
+    /// Register a host function taking named parameters, called from Lua as `name{key = value, ...}`.
+    ///
+    /// Every parameter in `params` must be present with the given kind, otherwise the call raises
+    /// a `LuaActorError::Conversion` error. The function receives the declared fields only.
+    pub fn add_fn_with_params<F>(
+        &mut self,
+        name: &str,
+        params: &[(&str, ParamKind)],
+        f: F,
+    ) -> Result<(), LuaError>
+    where
+        F: Fn(HashMap<String, LuaMessage>) -> LuaMessage + Send + 'static,
+    {
+        let params: Vec<(String, ParamKind)> = params
+            .iter()
+            .map(|(name, kind)| (name.to_string(), *kind))
+            .collect();
+        self.inject_fn(name, move |msg| {
+            let args = named_args(&params, msg).map_err(LuaError::external)?;
+            Ok(f(args))
+        })
+    }
+
+    // Register a host function, raising its `Error` results if `raise_host_errors` is set.
+    fn inject_fn<F>(&mut self, name: &str, f: F) -> Result<(), LuaError>
+    where
+        F: Fn(LuaMessage) -> Result<LuaMessage, LuaError> + Send + 'static,
+    {
+        self.inject_fn_multi(name, move |msg| f(msg).map(|res| vec![res]))
+    }
+
+    // Register a host function returning several values, raising the first `Error` among them
+    // if `raise_host_errors` is set.
+    fn inject_fn_multi<F>(&mut self, name: &str, f: F) -> Result<(), LuaError>
+    where
+        F: Fn(LuaMessage) -> Result<Vec<LuaMessage>, LuaError> + Send + 'static,
+    {
+        let raise = self.raise_host_errors;
+        self.vm.context(|ctx| {
+            if raise {
+                let f = ctx.create_function(move |_, msg: LuaMessage| {
+                    let mut res = f(msg)?;
+                    let failed = res
+                        .iter()
+                        .position(|v| matches!(v, LuaMessage::Error { .. }));
+                    if let Some(i) = failed {
+                        res = vec![res.swap_remove(i)];
+                    }
+                    Ok((failed.is_some(), res.into_iter().collect::<Variadic<_>>()))
+                })?;
+                let raising: Function = ctx.globals().get("__raising")?;
+                inject_global(ctx, name, raising.call(f)?)
+            } else {
+                let f = ctx.create_function(move |_, msg: LuaMessage| {
+                    Ok(f(msg)?.into_iter().collect::<Variadic<_>>())
+                })?;
+                inject_global(ctx, name, f)
+            }
+        })
+    }
+
+    /// Limit the bytes the actor's Lua VM can allocate, `None` removes the limit.
+    ///
+    /// Allocations beyond the limit fail and raise a memory error in Lua.
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.vm.set_memory_limit(limit)
+    }
+}
+
+impl LuaActor {
+    // Compile `script` and keep it in `__scripts` as `name`.
+    pub(crate) fn load_script(&mut self, name: &str, script: &str) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let load: Function = ctx.globals().get("__load")?;
+            load.call::<(&str, &str), ()>((script, name))
+        })
+    }
+
+    fn check_size(&self, msg: &LuaMessage) -> Result<(), LuaActorError> {
+        match self.max_message_bytes {
+            Some(max) => {
+                let size = msg.approx_size();
+                if size > max {
+                    Err(LuaActorError::MessageTooLarge { size, max })
+                } else {
+                    Ok(())
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_preconditions(&self, msg: &LuaMessage) -> Result<(), LuaActorError> {
+        for check in &self.preconditions {
+            check(msg).map_err(LuaActorError::Precondition)?;
+        }
+        Ok(())
+    }
+
+    // Check an incoming message against the size limit and the preconditions.
+    fn check_message(&self, msg: &LuaMessage) -> Result<(), LuaActorError> {
+        self.check_size(msg)
+            .and_then(|_| self.check_preconditions(msg))
+    }
 }
 
 // Remove all `self` usage with a independent function `invoke`.
@@ -119,6 +699,24 @@ fn invoke(
     func_name: &str,
     args: Vec<LuaMessage>,
 ) -> Result<LuaMessage, LuaError> {
+    invoke_with(self_addr, ctx, vm, recs, func_name, args, |ret, lua_ctx| {
+        LuaMessage::from_lua(ret, lua_ctx)
+    })
+}
+
+// Same as `invoke`, but the returned Lua value is converted with `convert`.
+fn invoke_with<F>(
+    self_addr: &Recipient<SendAttempt>,
+    ctx: &mut Context<LuaActor>,
+    vm: &mut Lua,
+    recs: &mut HashMap<String, Recipient<LuaMessage>>,
+    func_name: &str,
+    args: Vec<LuaMessage>,
+    convert: F,
+) -> Result<LuaMessage, LuaError>
+where
+    F: for<'lua> FnOnce(Value<'lua>, rlua::Context<'lua>) -> Result<LuaMessage, LuaError>,
+{
     // `ctx` is used in multiple closure in the lua scope.
     // to create multiple borrow in closures, we use RefCell to move the borrow-checking to runtime.
     // Voliating the check will result in panic. Which shouldn't happend(I think) since lua is single-threaded.
@@ -164,9 +762,10 @@ fn invoke(
                     let recs = recs.borrow_mut();
                     let rec = recs.get(&recipient_name);
 
-                    // TODO: error handling?
                     if let Some(r) = rec {
-                        r.do_send(msg).unwrap();
+                        r.do_send(msg).map_err(|e| {
+                            LuaError::external(LuaActorError::Mailbox(e.to_string()))
+                        })?;
                     }
                     Ok(())
                 })?;
@@ -192,6 +791,13 @@ fn invoke(
             )?;
             globals.set("send", send)?;
 
+            let stop = scope.create_function_mut(|_, _: LuaMessage| {
+                let mut ctx = ctx.borrow_mut();
+                ctx.stop();
+                Ok(())
+            })?;
+            globals.set("stop", stop)?;
+
             let terminate = scope.create_function_mut(|_, _: LuaMessage| {
                 let mut ctx = ctx.borrow_mut();
                 ctx.terminate();
@@ -203,7 +809,7 @@ fn invoke(
             if let Ok(f) = lua_handle {
                 match f.call::<MultiValue, Value>(args) {
                     Err(e) => panic!("{:?}", e),
-                    Ok(ret) => Ok(LuaMessage::from_lua(ret, lua_ctx).unwrap()),
+                    Ok(ret) => Ok(convert(ret, lua_ctx).unwrap()),
                 }
             } else {
                 // return nil if handle is not defined
@@ -227,6 +833,30 @@ impl Actor for LuaActor {
         ) {
             panic!("lua actor started failed {:?}", e);
         }
+        if let Some(interval) = self.maintenance_interval {
+            ctx.run_interval(interval, |act, _| {
+                if let Err(e) = act.maintain() {
+                    log::warn!("lua actor maintenance failed: {}", e);
+                }
+            });
+        }
+    }
+
+    fn stopping(&mut self, ctx: &mut Context<Self>) -> Running {
+        let timeout = match self.shutdown_timeout {
+            Some(timeout) if self.shutdown_deadline.is_none() && self.has_suspended() => timeout,
+            _ => {
+                // the coroutines still suspended won't be resumed, give back their slots
+                self.drop_suspended();
+                return Running::Stop;
+            }
+        };
+        self.shutdown_deadline = Some(Instant::now() + timeout);
+        ctx.run_later(timeout, |act, ctx| {
+            act.drop_suspended();
+            ctx.stop();
+        });
+        Running::Continue
     }
 
     fn stopped(&mut self, ctx: &mut Context<Self>) {
@@ -243,116 +873,3463 @@ impl Actor for LuaActor {
     }
 }
 
-struct SendAttempt {
-    recipient_name: String,
-    msg: LuaMessage,
-    cb_thread_id: i64,
+/// Evaluate a dynamic script with the context API available.
+///
+/// Compiled scripts are cached by their source, so evaluating the same source again doesn't recompile it.
+/// The cache size can be configured with `LuaActorBuilder::with_eval_cache_size`.
+/// A compile or runtime error of the script is returned as an `Error` message.
+pub struct Eval {
+    pub src: String,
 }
 
-impl Message for SendAttempt {
+impl Message for Eval {
     type Result = LuaMessage;
 }
 
-struct SendAttemptResult {
-    msg: LuaMessage,
-    cb_thread_id: i64,
+/// Evaluate a dynamic script with `context` as its `ctx`, e.g. `return ctx.x + ctx.y`.
+///
+/// Other globals are shared with the actor, but `ctx.state` and the context API aren't available,
+/// so one-off computations can't touch the persistent state. The script isn't cached.
+pub struct EvalWith {
+    pub src: String,
+    pub context: LuaMessage,
 }
 
-impl Message for SendAttemptResult {
+impl Message for EvalWith {
     type Result = LuaMessage;
 }
 
-impl Handler<LuaMessage> for LuaActor {
-    type Result = LuaMessage;
-
-    fn handle(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> Self::Result {
-        if let Ok(res) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__run",
-            vec![LuaMessage::from("handle"), msg],
-        ) {
-            res
-        } else {
-            LuaMessage::Nil
-        }
-    }
+/// Handle `msg` like a `LuaMessage`, but stream a table result to `callback` entry by entry.
+///
+/// `callback` is called with the key and value of each top-level entry, so a large result is never
+/// converted as a whole. The response is `Nil` for table results; other results are returned as is.
+/// If the handler is suspended by `ctx.send`, the response is its `ThreadYield` and the final result
+/// is streamed to `callback` once the handler completes.
+pub struct HandleStreaming {
+    pub msg: LuaMessage,
+    pub callback: StreamCallback,
 }
 
-impl Handler<SendAttemptResult> for LuaActor {
+impl Message for HandleStreaming {
     type Result = LuaMessage;
+}
 
-    fn handle(&mut self, result: SendAttemptResult, ctx: &mut Context<Self>) -> Self::Result {
-        if let Ok(res) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__resume",
-            vec![LuaMessage::from(result.cb_thread_id), result.msg],
-        ) {
-            res
-        } else {
-            LuaMessage::Nil
-        }
-    }
+/// Run the `handle` script as a generator, forwarding each value passed to `coroutine.yield` to `sink`.
+///
+/// The script is resumed only once `sink` accepted the previous value, so a slow consumer pauses it.
+/// The actor keeps handling other messages meanwhile. The response is the value returned by the script.
+/// An error raised by the script is sent to `sink` as an `Error` message, and is the response as well.
+/// `ctx.send` isn't available in generators and raises an error. If `sink` fails, the generator is dropped.
+pub struct Generate {
+    pub msg: LuaMessage,
+    pub sink: GeneratorSink,
 }
 
-impl Handler<SendAttempt> for LuaActor {
-    type Result = LuaMessage;
+/// The output of a `Generate` message.
+pub type GeneratorSink = Box<dyn Sink<SinkItem = LuaMessage, SinkError = ()> + Send>;
 
-    fn handle(&mut self, attempt: SendAttempt, ctx: &mut Context<Self>) -> Self::Result {
-        let rec = &self.recipients[&attempt.recipient_name];
-        let self_addr = ctx.address().clone();
-        rec.send(attempt.msg.clone())
-            .into_actor(self)
-            .then(move |res, _, _| {
-                match res {
-                    Ok(msg) => self_addr.do_send(SendAttemptResult {
-                        msg,
-                        cb_thread_id: attempt.cb_thread_id,
-                    }),
-                    _ => {
-                        panic!("send attempt failed: {:?}", res);
-                    }
-                };
-                actix::fut::ok(())
-            })
-            .wait(ctx);
+impl Message for Generate {
+    type Result = Result<LuaMessage, ()>;
+}
 
-        LuaMessage::Nil
-    }
+/// Register a new version of the `handle` script.
+///
+/// The script is compiled and validated on registration, but isn't used until it is activated with `ActivateHandler`.
+pub struct RegisterHandler {
+    pub version: String,
+    pub script: String,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures_timer::Delay;
-    use std::collections::HashMap;
-    use std::time::Duration;
-    use tokio::prelude::Future;
+impl Message for RegisterHandler {
+    type Result = Result<(), LuaError>;
+}
 
-    use crate::builder::LuaActorBuilder;
+/// Switch the `handle` script to a registered version.
+///
+/// The previously active script is kept and can be restored with `RollbackHandler`.
+pub struct ActivateHandler {
+    pub version: String,
+}
 
-    fn lua_actor_with_handle(script: &str) -> LuaActor {
-        LuaActorBuilder::new()
-            .on_handle_with_lua(script)
-            .build()
-            .unwrap()
-    }
+impl Message for ActivateHandler {
+    type Result = Result<(), LuaError>;
+}
 
-    #[test]
-    fn lua_actor_basic() {
-        let system = System::new("test");
+/// Swap the active `handle` script with the previously active one.
+pub struct RollbackHandler;
 
-        let lua_addr = lua_actor_with_handle(r#"return ctx.msg + 1"#).start();
+impl Message for RollbackHandler {
+    type Result = Result<(), LuaError>;
+}
 
-        let l = lua_addr.send(LuaMessage::from(1));
+/// Call a Lua function returned by a script earlier, see `FunctionHandle`.
+///
+/// Functions keep their upvalues between calls. The context API is available, except `ctx.send`.
+/// The response is an `Error` message if the handle doesn't belong to this actor or the function raises an error.
+pub struct CallFunction {
+    pub function: FunctionHandle,
+    pub arg: LuaMessage,
+}
+
+impl Message for CallFunction {
+    type Result = LuaMessage;
+}
+
+/// Call a Lua function returned by a script earlier with `args` as its positional arguments.
+///
+/// Like `CallFunction`, the context API is available, except `ctx.send`. Errors raised by the function,
+/// including a handle that doesn't belong to this actor, are returned as `Error` messages.
+pub struct InvokeMulti {
+    pub function: FunctionHandle,
+    pub args: Vec<LuaMessage>,
+}
+
+impl Message for InvokeMulti {
+    type Result = LuaMessage;
+}
+
+/// Release a `FunctionHandle`, so its Lua function can be garbage collected.
+///
+/// Calling a released handle is an error. The response is `false` if the handle was already released
+/// or doesn't belong to this actor.
+pub struct ReleaseFunction(pub FunctionHandle);
+
+impl Message for ReleaseFunction {
+    type Result = bool;
+}
+
+/// Handle `msg` like a `LuaMessage`, with `request_id` set as a global while the script runs.
+///
+/// The id is kept for coroutines suspended by `ctx.send`, so a script can include it in logs and results.
+pub struct WithRequestId {
+    pub request_id: String,
+    pub msg: LuaMessage,
+}
+
+impl Message for WithRequestId {
+    type Result = LuaMessage;
+}
+
+/// Handle a `LuaMessage` with `ctx.state` read-only while the `handle` script runs, so writes to it raise
+/// an error.
+///
+/// Unlike `LuaActorBuilder::with_read_only_state`, only this message is affected. The state stays read-only
+/// for coroutines suspended by `ctx.send`.
+pub struct ReadOnly(pub LuaMessage);
+
+impl Message for ReadOnly {
+    type Result = LuaMessage;
+}
+
+/// Handle each of `msgs` like a `LuaMessage`, in order, responding with an `Array` of their results.
+///
+/// A result is an error if it is an `Error` message. With `BatchMode::FailFast`, the messages after the first
+/// error aren't handled and the response ends with that error; see `LuaActorBuilder::with_batch_mode`.
+pub struct Batch {
+    pub msgs: Vec<LuaMessage>,
+}
+
+impl Message for Batch {
+    type Result = LuaMessage;
+}
+
+/// How a `Batch` handles an element resulting in an error.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BatchMode {
+    /// Stop at the first error.
+    FailFast,
+    /// Handle every element, reporting errors in place. The default.
+    #[default]
+    CollectErrors,
+}
+
+/// Handle `msg` like a `LuaMessage`, then wait for the host futures started by the script.
+///
+/// Scripts start host futures by calling functions registered with `LuaActor::add_async_fn`.
+/// The response is a table with the script's return value as `result` and the futures' results,
+/// in call order, as the `async` array.
+///
+/// If the script is suspended by `ctx.send`, the response waits for it to complete, including the futures
+/// started after it's resumed. Its `result` is an error if the suspended script is dropped, e.g. by a maintenance tick.
+pub struct HandleAsync {
+    pub msg: LuaMessage,
+}
+
+impl Message for HandleAsync {
+    type Result = LuaMessage;
+}
+
+/// The response of a `HandleAsync` message, sent once the script and its host futures complete.
+pub struct AsyncResponse(Box<dyn Future<Item = LuaMessage, Error = ()>>);
+
+impl AsyncResponse {
+    fn new(result: LuaMessage, futures: Vec<HostFuture>) -> Self {
+        let futures = futures
+            .into_iter()
+            .map(|f| f.then(|res| Ok::<_, ()>(res.unwrap_or_else(|e| e))));
+        AsyncResponse(Box::new(futures::future::join_all(futures).map(
+            move |results| {
+                let mut t = HashMap::new();
+                t.insert("result".to_string(), result);
+                t.insert("async".to_string(), LuaMessage::Array(results));
+                LuaMessage::Table(t)
+            },
+        )))
+    }
+}
+
+impl MessageResponse<LuaActor, HandleAsync> for AsyncResponse {
+    fn handle<R: ResponseChannel<HandleAsync>>(self, _: &mut Context<LuaActor>, tx: Option<R>) {
+        Arbiter::spawn(self.0.map(move |res| {
+            if let Some(tx) = tx {
+                tx.send(res)
+            }
+        }));
+    }
+}
+
+/// Get the names of the host functions that weren't accessed during the last run of the `handle` script.
+///
+/// Useful to trim the functions registered on an actor. Names are sorted.
+/// The response is `None` unless the actor was built with `LuaActorBuilder::with_global_tracking`.
+pub struct UnusedGlobals;
+
+impl Message for UnusedGlobals {
+    type Result = Option<Vec<String>>;
+}
+
+/// Set the field `key` of `ctx.state` to `new` only if its current value equals `expected`, e.g. for counters
+/// updated by the host without a script.
+///
+/// A missing field equals `Nil`. The swap happens between messages, so it can't interleave with a script.
+/// The response is a `Boolean`, whether the field was set, or an `Error` message if the current value
+/// can't be converted or the state can't be accessed.
+pub struct Cas {
+    pub key: String,
+    pub expected: LuaMessage,
+    pub new: LuaMessage,
+}
+
+impl Message for Cas {
+    type Result = LuaMessage;
+}
+
+/// Get the names of the host functions registered on the actor, such as with `LuaActor::add_fn`, for introspection.
+///
+/// The response is an `Array` of the sorted names.
+pub struct HostFunctions;
+
+impl Message for HostFunctions {
+    type Result = LuaMessage;
+}
+
+/// Take the `print` output captured since the last `TakeOutput`, clearing it.
+///
+/// The response is `None` unless the actor was built with `LuaActorBuilder::with_max_output_bytes`.
+pub struct TakeOutput;
+
+impl Message for TakeOutput {
+    type Result = Option<CapturedOutput>;
+}
+
+/// Appended to captured output once it reaches the cap set with `LuaActorBuilder::with_max_output_bytes`.
+pub const OUTPUT_TRUNCATED: &str = "[output truncated]\n";
+
+/// The `print` output of an actor, see `TakeOutput`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapturedOutput {
+    pub text: String,
+    /// Whether output was dropped, in which case `text` ends with `OUTPUT_TRUNCATED`.
+    pub truncated: bool,
+}
+
+impl CapturedOutput {
+    // Append `s`, cut at a character boundary to keep `text` within `max` bytes.
+    fn push(&mut self, s: &str, max: usize) {
+        if self.truncated {
+            return;
+        }
+        let room = max - self.text.len();
+        if s.len() <= room {
+            self.text.push_str(s);
+            return;
+        }
+        let mut end = room;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.text.push_str(&s[..end]);
+        self.text.push_str(OUTPUT_TRUNCATED);
+        self.truncated = true;
+    }
+}
+
+/// Get the source of the active `handle` script, e.g. for debug endpoints.
+///
+/// The response is `None` unless the actor was built with `LuaActorBuilder::with_source_access`.
+pub struct Source;
+
+impl Message for Source {
+    type Result = Option<String>;
+}
+
+// Set a host function as the global `name`, through the prelude so accesses can be tracked.
+fn inject_global<'lua>(
+    ctx: rlua::Context<'lua>,
+    name: &str,
+    f: Function<'lua>,
+) -> Result<(), LuaError> {
+    let inject: Function = ctx.globals().get("__inject")?;
+    inject.call((name, f))
+}
+
+fn handler_script_name(version: &str) -> String {
+    format!("handle@{}", version)
+}
+
+struct SendAttempt {
+    recipient_name: String,
+    msg: LuaMessage,
+    cb_thread_id: i64,
+}
+
+impl Message for SendAttempt {
+    type Result = LuaMessage;
+}
+
+struct SendAttemptResult {
+    msg: LuaMessage,
+    cb_thread_id: i64,
+    // `msg` is an error to return as the second result of `ctx.send`
+    failed: bool,
+}
+
+impl Message for SendAttemptResult {
+    type Result = LuaMessage;
+}
+
+// Handle the messages batched so far, once the mailbox is drained.
+struct FlushMailboxBatch;
+
+impl Message for FlushMailboxBatch {
+    type Result = ();
+}
+
+// The default number of messages held back by a paused actor, the default capacity of an actix mailbox.
+pub(crate) const DEFAULT_PAUSE_QUEUE_SIZE: usize = 16;
+
+// A message held back by a paused actor, handled on `Resume`.
+type Deferred = Box<dyn FnOnce(&mut LuaActor, &mut Context<LuaActor>) + Send>;
+
+/// Stop running the `handle` script for incoming messages until `Resume`.
+///
+/// The messages running `handle`, i.e. `LuaMessage`, `Batch`, `WithRequestId`, `ReadOnly`, `HandleAsync`,
+/// `HandleStreaming` and `Generate`, are queued meanwhile. Up to 16 messages are queued by default,
+/// like in a default actix mailbox, see `LuaActorBuilder::with_pause_queue_size`; the response to further
+/// messages is a `LuaActorError::Mailbox` error. Other messages, such as `Eval`, are still handled.
+pub struct Pause;
+
+impl Message for Pause {
+    type Result = ();
+}
+
+/// Handle the messages queued since `Pause`, in order, and resume handling incoming messages.
+pub struct Resume;
+
+impl Message for Resume {
+    type Result = ();
+}
+
+/// The response to a message running `handle`, which is deferred while the actor is paused.
+#[doc(hidden)]
+pub enum Reply {
+    Ready(LuaMessage),
+    Queued(oneshot::Receiver<LuaMessage>),
+}
+
+impl<M> MessageResponse<LuaActor, M> for Reply
+where
+    M: Message<Result = LuaMessage> + 'static,
+{
+    fn handle<R: ResponseChannel<M>>(self, ctx: &mut Context<LuaActor>, tx: Option<R>) {
+        match self {
+            Reply::Ready(res) => {
+                if let Some(tx) = tx {
+                    tx.send(res);
+                }
+            }
+            Reply::Queued(rx) => {
+                ctx.spawn(actix::fut::wrap_future(
+                    rx.map(move |res| {
+                        if let Some(tx) = tx {
+                            tx.send(res);
+                        }
+                    })
+                    // the queue is dropped with the actor
+                    .map_err(|_| ()),
+                ));
+            }
+        }
+    }
+}
+
+impl Handler<Pause> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Pause, _: &mut Context<Self>) -> Self::Result {
+        if self.paused.is_none() {
+            self.paused = Some(vec![]);
+        }
+    }
+}
+
+impl Handler<Resume> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Resume, ctx: &mut Context<Self>) -> Self::Result {
+        for deferred in self.paused.take().unwrap_or_default() {
+            deferred(self, ctx);
+        }
+    }
+}
+
+impl LuaActor {
+    // Respond with the result of `f`, which runs once resumed if the actor is paused.
+    fn reply<F>(&mut self, ctx: &mut Context<Self>, f: F) -> Reply
+    where
+        F: FnOnce(&mut LuaActor, &mut Context<LuaActor>) -> LuaMessage + Send + 'static,
+    {
+        if self.paused.is_none() {
+            return Reply::Ready(f(self, ctx));
+        }
+        let (tx, rx) = oneshot::channel();
+        match self.defer(move |act, ctx| {
+            let _ = tx.send(f(act, ctx));
+        }) {
+            Ok(()) => Reply::Queued(rx),
+            Err(e) => Reply::Ready(e),
+        }
+    }
+
+    // Queue `f` until `Resume`, or return the error for a paused actor whose queue is full.
+    fn defer<F>(&mut self, f: F) -> Result<(), LuaMessage>
+    where
+        F: FnOnce(&mut LuaActor, &mut Context<LuaActor>) + Send + 'static,
+    {
+        let queue = self.paused.as_mut().expect("the actor isn't paused");
+        if queue.len() >= self.pause_queue_size {
+            return Err(LuaMessage::from_error(LuaActorError::Mailbox(
+                "actor is paused and its queue is full".to_string(),
+            )));
+        }
+        queue.push(Box::new(f));
+        Ok(())
+    }
+}
+
+impl Handler<LuaMessage> for LuaActor {
+    type Result = Reply;
+
+    fn handle(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> Self::Result {
+        match self.mailbox_batch_size {
+            Some(max) if self.paused.is_none() => {
+                let (tx, rx) = oneshot::channel();
+                self.mailbox_batch.push((msg, tx));
+                // notifications are handled after the messages already in the mailbox
+                if self.mailbox_batch.len() == 1 {
+                    ctx.notify(FlushMailboxBatch);
+                }
+                if self.mailbox_batch.len() >= max {
+                    self.flush_mailbox_batch(ctx);
+                }
+                Reply::Queued(rx)
+            }
+            _ => self.reply(ctx, move |act, ctx| act.handle_message(msg, ctx)),
+        }
+    }
+}
+
+impl Handler<FlushMailboxBatch> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: FlushMailboxBatch, ctx: &mut Context<Self>) -> Self::Result {
+        self.flush_mailbox_batch(ctx)
+    }
+}
+
+impl LuaActor {
+    // Run the `handle` script once for the batched messages and answer each of them.
+    fn flush_mailbox_batch(&mut self, ctx: &mut Context<Self>) {
+        if self.mailbox_batch.is_empty() {
+            return;
+        }
+        // a message rejected by the checks is answered on its own, the others are still batched
+        let (msgs, txs): (Vec<_>, Vec<_>) = std::mem::take(&mut self.mailbox_batch)
+            .into_iter()
+            .filter_map(|(msg, tx)| match self.check_message(&msg) {
+                Ok(()) => Some((msg, tx)),
+                Err(e) => {
+                    let _ = tx.send(LuaMessage::from_error(e));
+                    None
+                }
+            })
+            .unzip();
+        if msgs.is_empty() {
+            return;
+        }
+        match self.run_handle(LuaMessage::Array(msgs), ctx) {
+            LuaMessage::Array(results) if results.len() == txs.len() => {
+                for (tx, res) in txs.into_iter().zip(results) {
+                    let _ = tx.send(res);
+                }
+            }
+            res => {
+                let res = match res {
+                    LuaMessage::Error { .. } => res,
+                    res => LuaMessage::error(format!(
+                        "expect an array of {} responses, got {}",
+                        txs.len(),
+                        res
+                    )),
+                };
+                for tx in txs {
+                    let _ = tx.send(res.clone());
+                }
+            }
+        }
+    }
+
+    // Apply the array export, the escape policy and the size limit to the result of a script,
+    // before returning it to the sender.
+    fn respond(&self, res: LuaMessage) -> LuaMessage {
+        let res = if self.json_arrays {
+            res
+        } else {
+            res.into_indexed_tables()
+        };
+        let res = match &self.escape {
+            Some(policy) => res.escape(policy.as_ref()),
+            None => res,
+        };
+        match self.check_size(&res) {
+            Ok(()) => res,
+            Err(e) => LuaMessage::from_error(e),
+        }
+    }
+
+    // Run the `handle` script for `msg`.
+    fn handle_message(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> LuaMessage {
+        if let Err(e) = self.check_message(&msg) {
+            return LuaMessage::from_error(e);
+        }
+        self.run_handle(msg, ctx)
+    }
+
+    // Run the `handle` script for `msg`, which passed `check_message`.
+    fn run_handle(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> LuaMessage {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "lua_actor_handle",
+            actor = self.name.as_deref().unwrap_or(""),
+            tag = msg.tag().unwrap_or("")
+        );
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        let before = self.vm.used_memory();
+        let res = if let Ok(res) = invoke(
+            &ctx.address().recipient(),
+            ctx,
+            &mut self.vm,
+            &mut self.recipients,
+            "__run",
+            vec![LuaMessage::from("handle"), msg],
+        ) {
+            res
+        } else {
+            LuaMessage::Nil
+        };
+        let res = self.respond(res);
+
+        if let Some(callback) = &self.memory_callback {
+            let after = self.vm.used_memory();
+            callback(after as isize - before as isize, after);
+        }
+        res
+    }
+}
+
+impl Handler<CallFunction> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, msg: CallFunction, ctx: &mut Context<Self>) -> Self::Result {
+        #[cfg(feature = "tracing")]
+        let span =
+            tracing::info_span!("lua_actor_call", actor = self.name.as_deref().unwrap_or(""));
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        let function = LuaMessage::Function(msg.function);
+        let exists = self
+            .vm
+            .context(|lua_ctx| matches!(function.clone().to_lua(lua_ctx), Ok(Value::Function(_))));
+        if !exists {
+            return LuaMessage::error("unknown function handle");
+        }
+
+        if let Ok(res) = invoke(
+            &ctx.address().recipient(),
+            ctx,
+            &mut self.vm,
+            &mut self.recipients,
+            "__call",
+            vec![function, msg.arg],
+        ) {
+            self.respond(res)
+        } else {
+            LuaMessage::Nil
+        }
+    }
+}
+
+impl Handler<InvokeMulti> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, msg: InvokeMulti, ctx: &mut Context<Self>) -> Self::Result {
+        #[cfg(feature = "tracing")]
+        let span =
+            tracing::info_span!("lua_actor_call", actor = self.name.as_deref().unwrap_or(""));
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        let function = LuaMessage::Function(msg.function);
+        let exists = self
+            .vm
+            .context(|lua_ctx| matches!(function.clone().to_lua(lua_ctx), Ok(Value::Function(_))));
+        if !exists {
+            return LuaMessage::error("unknown function handle");
+        }
+
+        let mut args = msg.args;
+        args.insert(0, function);
+        if let Ok(res) = invoke(
+            &ctx.address().recipient(),
+            ctx,
+            &mut self.vm,
+            &mut self.recipients,
+            "__call_multi",
+            args,
+        ) {
+            self.respond(res)
+        } else {
+            LuaMessage::Nil
+        }
+    }
+}
+
+impl Handler<ReleaseFunction> for LuaActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: ReleaseFunction, _: &mut Context<Self>) -> Self::Result {
+        self.vm
+            .context(|ctx| release_function(ctx, msg.0))
+            .unwrap_or(false)
+    }
+}
+
+impl Handler<Eval> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, msg: Eval, ctx: &mut Context<Self>) -> Self::Result {
+        #[cfg(feature = "tracing")]
+        let span =
+            tracing::info_span!("lua_actor_eval", actor = self.name.as_deref().unwrap_or(""));
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        let cache = &mut self.eval_cache;
+        let name = match self.vm.context(|lua_ctx| cache.load(lua_ctx, &msg.src)) {
+            Ok(name) => name,
+            Err(e) => return LuaMessage::error(e.to_string()),
+        };
+
+        if let Ok(res) = invoke(
+            &ctx.address().recipient(),
+            ctx,
+            &mut self.vm,
+            &mut self.recipients,
+            "__eval",
+            vec![LuaMessage::from(name)],
+        ) {
+            self.respond(res)
+        } else {
+            LuaMessage::Nil
+        }
+    }
+}
+
+impl Handler<EvalWith> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, msg: EvalWith, _: &mut Context<Self>) -> Self::Result {
+        let EvalWith { src, context } = msg;
+        let res = self.vm.context(|ctx| {
+            let env = ctx.create_table()?;
+            env.set("ctx", context)?;
+            let meta = ctx.create_table()?;
+            meta.set("__index", ctx.globals())?;
+            env.set_metatable(Some(meta));
+            ctx.load(&src)
+                .set_name("eval_with")?
+                .set_environment(env)?
+                .eval::<LuaMessage>()
+        });
+        self.respond(res.unwrap_or_else(error_from_lua))
+    }
+}
+
+impl Handler<HandleStreaming> for LuaActor {
+    type Result = Reply;
+
+    fn handle(&mut self, msg: HandleStreaming, ctx: &mut Context<Self>) -> Self::Result {
+        self.reply(ctx, move |act, ctx| act.handle_streaming(msg, ctx))
+    }
+}
+
+impl LuaActor {
+    fn handle_streaming(&mut self, msg: HandleStreaming, ctx: &mut Context<Self>) -> LuaMessage {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "lua_actor_handle",
+            actor = self.name.as_deref().unwrap_or(""),
+            tag = msg.msg.tag().unwrap_or("")
+        );
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        if let Err(e) = self.check_message(&msg.msg) {
+            return LuaMessage::from_error(e);
+        }
+
+        let mut callback = msg.callback;
+        let before = self.vm.used_memory();
+        let res = invoke_with(
+            &ctx.address().recipient(),
+            ctx,
+            &mut self.vm,
+            &mut self.recipients,
+            "__run",
+            vec![LuaMessage::from("handle"), msg.msg],
+            |ret, lua_ctx| LuaMessage::from_lua_streaming(ret, lua_ctx, &mut callback),
+        )
+        .unwrap_or(LuaMessage::Nil);
+        if let LuaMessage::ThreadYield(thread_id) = &res {
+            self.streaming.insert(thread_id.clone(), callback);
+        }
+        let res = self.respond(res);
+
+        if let Some(callback) = &self.memory_callback {
+            let after = self.vm.used_memory();
+            callback(after as isize - before as isize, after);
+        }
+        res
+    }
+}
+
+impl Handler<Generate> for LuaActor {
+    type Result = ResponseActFuture<Self, LuaMessage, ()>;
+
+    fn handle(&mut self, msg: Generate, ctx: &mut Context<Self>) -> Self::Result {
+        if self.paused.is_none() {
+            return self.start_generator(msg, ctx);
+        }
+        let (tx, rx) = oneshot::channel();
+        let deferred = self.defer(move |act, ctx| {
+            let generator = act.start_generator(msg, ctx);
+            ctx.spawn(generator.then(move |res, _, _| {
+                let _ = tx.send(res);
+                actix::fut::ok(())
+            }));
+        });
+        match deferred {
+            Ok(()) => Box::new(rx.then(|res| res.unwrap_or(Err(()))).into_actor(self)),
+            Err(_) => Box::new(actix::fut::err(())),
+        }
+    }
+}
+
+impl LuaActor {
+    fn start_generator(
+        &mut self,
+        msg: Generate,
+        ctx: &mut Context<Self>,
+    ) -> ResponseActFuture<Self, LuaMessage, ()> {
+        match invoke(
+            &ctx.address().recipient(),
+            ctx,
+            &mut self.vm,
+            &mut self.recipients,
+            "__generate",
+            vec![LuaMessage::from("handle"), msg.msg],
+        ) {
+            Ok(id) => generate(id, msg.sink),
+            Err(_) => Box::new(actix::fut::err(())),
+        }
+    }
+}
+
+// Resume the generator `id` and wait for `sink` to accept the yielded value before resuming it again.
+fn generate(id: LuaMessage, sink: GeneratorSink) -> ResponseActFuture<LuaActor, LuaMessage, ()> {
+    Box::new(
+        actix::fut::ok(()).and_then(move |_, act: &mut LuaActor, ctx| {
+            let step = invoke(
+                &ctx.address().recipient(),
+                ctx,
+                &mut act.vm,
+                &mut act.recipients,
+                "__step",
+                vec![id.clone()],
+            );
+            let (done, failed, value) = match step {
+                Ok(LuaMessage::Table(mut t)) => (
+                    t.remove("done") == Some(LuaMessage::Boolean(true)),
+                    t.remove("failed") == Some(LuaMessage::Boolean(true)),
+                    t.remove("value").unwrap_or(LuaMessage::Nil),
+                ),
+                _ => return Box::new(actix::fut::err(())) as ResponseActFuture<_, _, _>,
+            };
+
+            if failed {
+                // the error is the last value and the response
+                Box::new(
+                    sink.send(value.clone())
+                        .and_then(|sink| sink.flush())
+                        .map(move |_| value)
+                        .into_actor(act),
+                )
+            } else if done {
+                Box::new(sink.flush().map(|_| value).into_actor(act))
+            } else {
+                Box::new(
+                    sink.send(value)
+                        .into_actor(act)
+                        .then(move |res, act, _| match res {
+                            Ok(sink) => generate(id, sink),
+                            Err(()) => {
+                                act.drop_generator(id);
+                                Box::new(actix::fut::err(()))
+                            }
+                        }),
+                )
+            }
+        }),
+    )
+}
+
+impl Handler<RegisterHandler> for LuaActor {
+    type Result = Result<(), LuaError>;
+
+    fn handle(&mut self, msg: RegisterHandler, _: &mut Context<Self>) -> Self::Result {
+        self.load_script(&handler_script_name(&msg.version), &msg.script)
+    }
+}
+
+impl Handler<ActivateHandler> for LuaActor {
+    type Result = Result<(), LuaError>;
+
+    fn handle(&mut self, msg: ActivateHandler, _: &mut Context<Self>) -> Self::Result {
+        self.vm.context(|ctx| {
+            let activate: Function = ctx.globals().get("__activate")?;
+            activate.call::<String, ()>(handler_script_name(&msg.version))
+        })
+    }
+}
+
+impl Handler<RollbackHandler> for LuaActor {
+    type Result = Result<(), LuaError>;
+
+    fn handle(&mut self, _: RollbackHandler, _: &mut Context<Self>) -> Self::Result {
+        self.vm.context(|ctx| {
+            let rollback: Function = ctx.globals().get("__rollback")?;
+            rollback.call::<(), ()>(())
+        })
+    }
+}
+
+impl Handler<WithRequestId> for LuaActor {
+    type Result = Reply;
+
+    fn handle(&mut self, msg: WithRequestId, ctx: &mut Context<Self>) -> Self::Result {
+        self.reply(ctx, move |act, ctx| act.handle_with_request_id(msg, ctx))
+    }
+}
+
+impl Handler<ReadOnly> for LuaActor {
+    type Result = Reply;
+
+    fn handle(&mut self, msg: ReadOnly, ctx: &mut Context<Self>) -> Self::Result {
+        self.reply(ctx, move |act, ctx| act.handle_read_only(msg, ctx))
+    }
+}
+
+impl Handler<Batch> for LuaActor {
+    type Result = Reply;
+
+    fn handle(&mut self, batch: Batch, ctx: &mut Context<Self>) -> Self::Result {
+        self.reply(ctx, move |act, ctx| act.handle_batch(batch, ctx))
+    }
+}
+
+impl Handler<HandleAsync> for LuaActor {
+    type Result = AsyncResponse;
+
+    fn handle(&mut self, msg: HandleAsync, ctx: &mut Context<Self>) -> Self::Result {
+        if self.paused.is_none() {
+            return self.handle_async(msg, ctx);
+        }
+        let (tx, rx) = oneshot::channel();
+        let deferred = self.defer(move |act, ctx| {
+            Arbiter::spawn(act.handle_async(msg, ctx).0.map(move |res| {
+                let _ = tx.send(res);
+            }));
+        });
+        match deferred {
+            Ok(()) => AsyncResponse(Box::new(rx.map_err(|_| ()))),
+            Err(e) => AsyncResponse::new(e, vec![]),
+        }
+    }
+}
+
+impl LuaActor {
+    fn handle_with_request_id(
+        &mut self,
+        msg: WithRequestId,
+        ctx: &mut Context<Self>,
+    ) -> LuaMessage {
+        let request_id = msg.request_id;
+        if let Err(e) = self
+            .vm
+            .context(|lua_ctx| lua_ctx.globals().set("request_id", request_id))
+        {
+            return LuaMessage::error(e.to_string());
+        }
+        let res = self.handle_message(msg.msg, ctx);
+        match self
+            .vm
+            .context(|lua_ctx| lua_ctx.globals().set("request_id", Value::Nil))
+        {
+            Ok(()) => res,
+            Err(e) => LuaMessage::error(e.to_string()),
+        }
+    }
+
+    fn handle_read_only(&mut self, msg: ReadOnly, ctx: &mut Context<Self>) -> LuaMessage {
+        if let Err(e) = self
+            .vm
+            .context(|lua_ctx| lua_ctx.globals().set("__read_only_message", true))
+        {
+            return LuaMessage::error(e.to_string());
+        }
+        let res = self.handle_message(msg.0, ctx);
+        match self
+            .vm
+            .context(|lua_ctx| lua_ctx.globals().set("__read_only_message", false))
+        {
+            Ok(()) => res,
+            Err(e) => LuaMessage::error(e.to_string()),
+        }
+    }
+
+    fn handle_batch(&mut self, batch: Batch, ctx: &mut Context<Self>) -> LuaMessage {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "lua_actor_batch",
+            actor = self.name.as_deref().unwrap_or(""),
+            size = batch.msgs.len()
+        );
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        let mut results = Vec::with_capacity(batch.msgs.len());
+        for msg in batch.msgs {
+            let res = self.handle_message(msg, ctx);
+            let failed = matches!(res, LuaMessage::Error { .. });
+            results.push(res);
+            if failed && self.batch_mode == BatchMode::FailFast {
+                break;
+            }
+        }
+        LuaMessage::Array(results)
+    }
+
+    fn handle_async(&mut self, msg: HandleAsync, ctx: &mut Context<Self>) -> AsyncResponse {
+        let guard = PendingGuard::new(&self.pending, vec![]);
+        let result = self.handle_message(msg.msg, ctx);
+        let futures = guard.finish();
+
+        // wait for the suspended script, see `Handler<SendAttemptResult>`
+        if let LuaMessage::ThreadYield(thread_id) = result {
+            let (tx, rx) = oneshot::channel();
+            self.suspended_async
+                .insert(thread_id, SuspendedAsync { futures, tx });
+            return AsyncResponse(Box::new(rx.then(|res| {
+                let (result, futures) = res.unwrap_or_else(|_| {
+                    (
+                        LuaMessage::error("the suspended script was dropped"),
+                        vec![],
+                    )
+                });
+                AsyncResponse::new(result, futures).0
+            })));
+        }
+        AsyncResponse::new(result, futures)
+    }
+}
+
+impl Handler<UnusedGlobals> for LuaActor {
+    type Result = Option<Vec<String>>;
+
+    fn handle(&mut self, _: UnusedGlobals, _: &mut Context<Self>) -> Self::Result {
+        if !self.global_tracking {
+            return None;
+        }
+        self.vm.context(|ctx| {
+            let unused: Function = ctx.globals().get("__unused_globals").ok()?;
+            unused.call(()).ok()
+        })
+    }
+}
+
+impl Handler<Cas> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, msg: Cas, _: &mut Context<Self>) -> Self::Result {
+        let Cas { key, expected, new } = msg;
+        self.vm.context(|ctx| {
+            let swap = || -> Result<bool, LuaError> {
+                let lua_ctx: Table = ctx.globals().get("ctx")?;
+                let state: Table = lua_ctx.get("state")?;
+                if state.get::<_, LuaMessage>(key.as_str())? != expected {
+                    return Ok(false);
+                }
+                state.set(key.as_str(), new)?;
+                Ok(true)
+            };
+            swap().map_or_else(LuaMessage::from_error, LuaMessage::from)
+        })
+    }
+}
+
+impl Handler<HostFunctions> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: HostFunctions, _: &mut Context<Self>) -> Self::Result {
+        let names = self.vm.context(|ctx| {
+            let injected: Table = ctx.globals().get("__injected")?;
+            injected
+                .pairs::<String, Value>()
+                .map(|pair| pair.map(|(name, _)| name))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        match names {
+            Ok(mut names) => {
+                names.sort();
+                LuaMessage::Array(names.into_iter().map(LuaMessage::from).collect())
+            }
+            Err(e) => LuaMessage::error(e.to_string()),
+        }
+    }
+}
+
+impl Handler<TakeOutput> for LuaActor {
+    type Result = Option<CapturedOutput>;
+
+    fn handle(&mut self, _: TakeOutput, _: &mut Context<Self>) -> Self::Result {
+        let output = self.output.as_ref()?;
+        Some(std::mem::take(&mut *output.lock().unwrap()))
+    }
+}
+
+impl Handler<Source> for LuaActor {
+    type Result = Option<String>;
+
+    fn handle(&mut self, _: Source, _: &mut Context<Self>) -> Self::Result {
+        if !self.source_access {
+            return None;
+        }
+        self.vm.context(|ctx| {
+            let sources: Table = ctx.globals().get("__sources").ok()?;
+            sources.get("handle").ok()
+        })
+    }
+}
+
+impl Handler<SendAttemptResult> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, result: SendAttemptResult, ctx: &mut Context<Self>) -> Self::Result {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "lua_actor_resume",
+            actor = self.name.as_deref().unwrap_or(""),
+            thread_id = result.cb_thread_id
+        );
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        // the reply arrived after the shutdown timeout
+        if let Some(deadline) = self.shutdown_deadline {
+            if Instant::now() >= deadline {
+                self.drop_suspended();
+                ctx.stop();
+                return LuaMessage::Nil;
+            }
+        }
+
+        // the slot of an evicted coroutine was released by the maintenance tick
+        if let Some(limit) = &self.coroutine_limit {
+            if self.is_suspended(result.cb_thread_id) {
+                limit.release();
+            }
+        }
+        let mut streaming = self.streaming.remove(&result.cb_thread_id.to_string());
+        // the handler of a `HandleAsync` message can start host futures after it's resumed
+        let suspended_async = self
+            .suspended_async
+            .remove(&result.cb_thread_id.to_string())
+            .map(|s| (PendingGuard::new(&self.pending, s.futures), s.tx));
+        let res = invoke_with(
+            &ctx.address().recipient(),
+            ctx,
+            &mut self.vm,
+            &mut self.recipients,
+            "__resume",
+            vec![
+                LuaMessage::from(result.cb_thread_id),
+                result.msg,
+                LuaMessage::from(result.failed),
+            ],
+            |ret, lua_ctx| match &mut streaming {
+                Some(callback) => LuaMessage::from_lua_streaming(ret, lua_ctx, callback),
+                None => LuaMessage::from_lua(ret, lua_ctx),
+            },
+        )
+        .unwrap_or(LuaMessage::Nil);
+        // the handler of a `HandleStreaming` message may be suspended again
+        if let (LuaMessage::ThreadYield(thread_id), Some(callback)) = (&res, streaming) {
+            self.streaming.insert(thread_id.clone(), callback);
+        }
+        if let Some((guard, tx)) = suspended_async {
+            let futures = guard.finish();
+            match &res {
+                LuaMessage::ThreadYield(thread_id) => {
+                    self.suspended_async
+                        .insert(thread_id.clone(), SuspendedAsync { futures, tx });
+                }
+                _ => {
+                    let _ = tx.send((res.clone(), futures));
+                }
+            }
+        }
+
+        if self.shutdown_deadline.is_some() && !self.has_suspended() {
+            ctx.stop();
+        }
+        res
+    }
+}
+
+impl Handler<SendAttempt> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, attempt: SendAttempt, ctx: &mut Context<Self>) -> Self::Result {
+        // resume the script with the error instead of sending a message over the limit
+        if let Err(e) = self.check_size(&attempt.msg) {
+            ctx.notify(SendAttemptResult {
+                msg: LuaMessage::from_error(e),
+                cb_thread_id: attempt.cb_thread_id,
+                failed: true,
+            });
+            return LuaMessage::Nil;
+        }
+
+        let rec = match self.recipients.get(&attempt.recipient_name) {
+            Some(rec) => rec,
+            None => {
+                ctx.notify(SendAttemptResult {
+                    msg: LuaMessage::from_error(LuaActorError::Mailbox(format!(
+                        "unknown recipient {}",
+                        attempt.recipient_name
+                    ))),
+                    cb_thread_id: attempt.cb_thread_id,
+                    failed: true,
+                });
+                return LuaMessage::Nil;
+            }
+        };
+        let self_addr = ctx.address().clone();
+        rec.send(attempt.msg.clone())
+            .into_actor(self)
+            .then(move |res, _, _| {
+                let (msg, failed) = match res {
+                    Ok(msg) => (msg, false),
+                    Err(e) => (LuaMessage::from(e), true),
+                };
+                self_addr.do_send(SendAttemptResult {
+                    msg,
+                    cb_thread_id: attempt.cb_thread_id,
+                    failed,
+                });
+                actix::fut::ok(())
+            })
+            .wait(ctx);
+
+        LuaMessage::Nil
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_timer::Delay;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::prelude::Future;
+
+    use crate::builder::LuaActorBuilder;
+
+    fn lua_actor_with_handle(script: &str) -> LuaActor {
+        LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn lua_actor_basic() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(r#"return ctx.msg + 1"#).start();
+
+        let l = lua_addr.send(LuaMessage::from(1));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(2));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    #[allow(clippy::redundant_pattern_matching)]
+    fn lua_actor_syntax_error() {
+        let res = LuaActorBuilder::new()
+            .on_handle_with_lua(r"return 1+")
+            .build();
+
+        if let Ok(_) = res {
+            panic!("should return Err(syntax_error)");
+        }
+    }
+
+    #[should_panic]
+    #[test]
+    fn lua_actor_user_error() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        print("before")
+        error("foo")
+        print("after")
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from(0));
+        Arbiter::spawn(
+            l.map(|_| {
+                // it should panic
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_return_table() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        return {x = 1}
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from(3));
+        Arbiter::spawn(
+            l.map(|res| {
+                let mut t = HashMap::new();
+                t.insert("x".to_string(), LuaMessage::from(1));
+
+                assert_eq!(res, LuaMessage::from(t));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_state() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        if not ctx.state.x then ctx.state.x = 0 end
+
+        ctx.state.x = ctx.state.x + 1
+        return ctx.state.x
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(res, LuaMessage::from(1));
+                let l2 = lua_addr.send(LuaMessage::Nil);
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(res, LuaMessage::from(2));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_notify() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.notify(100)
+            "#,
+            )
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 100 then
+                ctx.state.notified = ctx.msg
+            end
+
+            return ctx.msg + ctx.state.notified
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
+            let l = addr.send(LuaMessage::from(1));
+            Arbiter::spawn(
+                l.map(|res| {
+                    assert_eq!(res, LuaMessage::from(101));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_notify_later() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.notify_later(100, 1)
+            "#,
+            )
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 100 then
+                ctx.state.notified = ctx.msg
+            end
+
+            return ctx.msg + ctx.state.notified
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let delay = Delay::new(Duration::from_secs(2)).map(move |()| {
+            let l2 = addr.send(LuaMessage::from(1));
+            Arbiter::spawn(
+                l2.map(|res| {
+                    assert_eq!(res, LuaMessage::from(101));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send() {
+        use std::mem::discriminant;
+        let system = System::new("test");
+
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello");
+                    System::current().stop();
+                    LuaMessage::Boolean(true)
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+        let callback_addr = Callback.start();
+
+        let mut actor = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            local result = ctx.send("callback", "Hello")
+            print("result", "=", result)
+            "#,
+            )
+            .build()
+            .unwrap();
+
+        actor.add_recipients("callback", callback_addr.recipient());
+        actor.start();
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_thread_yield() {
+        use std::mem::discriminant;
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                LuaMessage::Nil
+            }
+        }
+
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local result = ctx.send("callback", "Hello")
+            print(result)
+            return result
+            "#,
+            )
+            .build()
+            .unwrap();
+
+        actor.add_recipients("callback", Callback.start().recipient());
+
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(
+                    discriminant(&res),
+                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_coroutine_limit() {
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                LuaMessage::Nil
+            }
+        }
+
+        let system = System::new("test");
+
+        let limit = CoroutineLimit::new(1);
+        // another actor of the pool holds the only slot
+        assert!(limit.try_acquire());
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local ok, err = pcall(ctx.send, "callback", "Hello")
+            return err
+            "#,
+            )
+            .with_coroutine_limit(&limit)
+            .build()
+            .unwrap();
+        actor.add_recipients("callback", Callback.start().recipient());
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(
+                    res.downcast_error::<LuaActorError>(),
+                    Some(&LuaActorError::CoroutineLimit(1))
+                );
+                assert_eq!(limit.suspended(), 1);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_is_send() {
+        // actors can be built on one thread and started on another, e.g. with `Arbiter::start`
+        fn assert_send<T: Send>() {}
+        assert_send::<LuaActor>();
+        assert_send::<LuaActorBuilder>();
+    }
+
+    #[test]
+    fn lua_actor_stop_releases_limit() {
+        struct Echo;
+        impl Actor for Echo {
+            type Context = Context<Self>;
+        }
+        impl Handler<LuaMessage> for Echo {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+                msg
+            }
+        }
+
+        let system = System::new("test");
+
+        let limit = CoroutineLimit::new(1);
+        // the actor stops before the reply to `ctx.send` resumes the coroutine
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            ctx.stop()
+            ctx.send("echo", ctx.msg)
+            "#,
+            )
+            .with_coroutine_limit(&limit)
+            .build()
+            .unwrap();
+        actor.add_recipients("echo", Echo.start().recipient());
+        let addr = actor.start();
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then(|_| Delay::new(Duration::from_millis(100)).map_err(|e| panic!("{}", e)))
+            .map(move |_| {
+                assert!(!addr.connected());
+                assert_eq!(limit.suspended(), 0);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send_to_stopped_peer() {
+        struct Peer;
+        impl Actor for Peer {
+            type Context = Context<Self>;
+
+            fn started(&mut self, ctx: &mut Context<Self>) {
+                ctx.stop();
+            }
+        }
+
+        impl Handler<LuaMessage> for Peer {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                msg
+            }
+        }
+
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local res, err = ctx.send("peer", "Hello")
+            ctx.state.caught = res == nil
+            ctx.state.err = err
+            "#,
+            )
+            .with_sequence_arrays(true)
+            .build()
+            .unwrap();
+        actor.add_recipients("peer", Peer.start().recipient());
+        let addr = actor.start();
+
+        let l = Delay::new(Duration::from_millis(50))
+            .map_err(|e| panic!("{}", e))
+            .and_then({
+                let addr = addr.clone();
+                move |_| addr.send(LuaMessage::Nil)
+            })
+            .and_then(|_| Delay::new(Duration::from_millis(50)).map_err(|e| panic!("{}", e)))
+            .and_then(move |_| {
+                addr.send(Eval {
+                    src: "return {ctx.state.caught, ctx.state.err}".to_string(),
+                })
+            });
+        Arbiter::spawn(
+            l.map(|res| {
+                let (caught, err) = match res {
+                    LuaMessage::Array(mut x) => (x.remove(0), x.remove(0)),
+                    x => panic!("unexpected result {:?}", x),
+                };
+                assert_eq!(caught, LuaMessage::from(true));
+                assert_eq!(
+                    err.downcast_error::<LuaActorError>(),
+                    Some(&LuaActorError::Mailbox("Mailbox has closed".to_string()))
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send_to_unknown_recipient() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local res, err = ctx.send("nobody", "Hello")
+            ctx.state.caught = res == nil
+            ctx.state.err = err
+            "#,
+            )
+            .with_sequence_arrays(true)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then(|_| Delay::new(Duration::from_millis(50)).map_err(|e| panic!("{}", e)))
+            .and_then(move |_| {
+                addr.send(Eval {
+                    src: "return {ctx.state.caught, ctx.state.err}".to_string(),
+                })
+            });
+        Arbiter::spawn(
+            l.map(|res| {
+                let (caught, err) = match res {
+                    LuaMessage::Array(mut x) => (x.remove(0), x.remove(0)),
+                    x => panic!("unexpected result {:?}", x),
+                };
+                assert_eq!(caught, LuaMessage::from(true));
+                assert_eq!(
+                    err.downcast_error::<LuaActorError>(),
+                    Some(&LuaActorError::Mailbox(
+                        "unknown recipient nobody".to_string()
+                    ))
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_thread_yield_and_callback_message() {
+        use std::mem::discriminant;
+
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello");
+                    LuaMessage::String(format!("{} from callback", s))
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+
+        struct Check;
+        impl Actor for Check {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Check {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello from callback");
+                    System::current().stop();
+                    LuaMessage::Nil
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+
+        let system = System::new("test");
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local result = ctx.send("callback", ctx.msg)
+            print("send result", "=", result)
+            ctx.send("check", result)
+            "#,
+            )
+            .build()
+            .unwrap();
+
+        actor.add_recipients("callback", Callback.start().recipient());
+        actor.add_recipients("check", Check.start().recipient());
+
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::String("Hello".to_string()));
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(
+                    discriminant(&res),
+                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_do_send() {
+        use std::mem::discriminant;
+
+        struct Check;
+        impl Actor for Check {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Check {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello");
+                    System::current().stop();
+                    LuaMessage::Nil
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local result = ctx.do_send("check", "Hello")
+            print("new actor addr name", rec, result)
+            return ctx.msg
+            "#,
+            )
+            .build()
+            .unwrap();
+        actor.add_recipients("check", Check.start().recipient());
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::Nil);
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_terminate() {
+        // TODO: validate on_stopped is called
+        let system = System::new("test");
+
+        let _ = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.terminate()
+            "#,
+            )
+            .on_stopped_with_lua(r#"print("stopped")"#)
+            .build()
+            .unwrap()
+            .start();
+        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
+            System::current().stop();
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    use std::env;
+
+    #[test]
+    fn lua_actor_require() {
+        let system = System::new("test");
+        env::set_var("LUA_PATH", "./src/?.lua;;");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                local m = require('lua/test/module')
+                return m.incr(ctx.msg)
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+        let l = addr.send(LuaMessage::from(1));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(2));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_module_resolver() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "missing" then
+                    local ok, err = pcall(require, "missing")
+                    return err
+                end
+                local utils = require("utils")
+                return utils.double(ctx.msg)
+            "#,
+            )
+            .with_module_resolver(|name| match name {
+                "utils" => Some("return { double = function(x) return x * 2 end }".to_string()),
+                _ => None,
+            })
+            .build()
+            .unwrap()
+            .start();
+        let l = addr
+            .send(LuaMessage::from(21))
+            .join(addr.send(LuaMessage::from("missing")));
+        Arbiter::spawn(
+            l.map(|(res, missing)| {
+                assert_eq!(res, LuaMessage::from(42));
+                assert!(missing
+                    .as_display_cow()
+                    .contains("module 'missing' not found"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_virtual_fs() {
+        use crate::host::VirtualFs;
+
+        let system = System::new("test");
+
+        let mut fs = VirtualFs::new();
+        fs.add("app", r#"return { run = require("util").double }"#)
+            .add("util", "return { double = function(x) return x * 2 end }")
+            .add("a", r#"return { b = require("b") }"#)
+            .add("b", r#"return { a = require("a") }"#);
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "cycle" then
+                    local ok, err = pcall(require, "a")
+                    return err
+                end
+                return require("app").run(ctx.msg)
+            "#,
+            )
+            .with_virtual_fs(fs)
+            .build()
+            .unwrap()
+            .start();
+        let l = addr
+            .send(LuaMessage::from(21))
+            .join(addr.send(LuaMessage::from("cycle")))
+            .join(addr.send(LuaMessage::from("cycle")));
+        Arbiter::spawn(
+            l.map(|((res, cycle), again)| {
+                assert_eq!(res, LuaMessage::from(42));
+                assert_eq!(cycle, LuaMessage::from("b:1: cyclic require: a -> b -> a"));
+                // a failed load isn't cached
+                assert_eq!(again, cycle);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_escape() {
+        use crate::escape::HtmlEscape;
+
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return { body = ctx.msg }"#)
+            .with_escape(HtmlEscape)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("<script>"))
+            .join(addr.send(Batch {
+                msgs: vec![LuaMessage::from("<b>")],
+            }))
+            .join(addr.send(Eval {
+                src: r#"return "<i>""#.to_string(),
+            }));
+        Arbiter::spawn(
+            l.map(|((res, batch), eval)| {
+                let mut t = HashMap::new();
+                t.insert("body".to_string(), LuaMessage::from("&lt;script&gt;"));
+                assert_eq!(res, LuaMessage::Table(t));
+                assert_eq!(batch, lua_array![lua_table! { "body" => "&lt;b&gt;" }]);
+                assert_eq!(eval, LuaMessage::from("&lt;i&gt;"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_on_message() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_message_with_lua(
+                r#"
+            if ctx.msg == "ping" then
+                return "pong"
+            end
+            "#,
+            )
+            .on_handle_with_lua(r#"return "handled " .. ctx.msg"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("ping"))
+            .join(addr.send(LuaMessage::from("hello")));
+        Arbiter::spawn(
+            l.map(|(ping, hello)| {
+                assert_eq!(ping, LuaMessage::from("pong"));
+                assert_eq!(hello, LuaMessage::from("handled hello"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_seq() {
+        let system = System::new("test");
+
+        struct Echo;
+        impl Actor for Echo {
+            type Context = Context<Self>;
+        }
+        impl Handler<LuaMessage> for Echo {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+                msg
+            }
+        }
+
+        // `seq` is an ordinary global, left alone by the actor
+        let mut actor = LuaActorBuilder::new()
+            .on_started_with_lua("seq = 'user'")
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "send" then
+                ctx.send("echo", ctx.msg)
+                ctx.state.resumed = { ctx.seq, seq }
+            end
+            return { ctx.seq, seq }
+            "#,
+            )
+            .with_sequence_arrays(true)
+            .build()
+            .unwrap();
+        actor.add_recipients("echo", Echo.start().recipient());
+        let addr = actor.start();
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .join3(addr.send(LuaMessage::Nil), addr.send(LuaMessage::Nil))
+            .and_then({
+                let addr = addr.clone();
+                move |seqs| addr.send(LuaMessage::from("send")).map(|_| seqs)
+            })
+            .and_then(|seqs| {
+                Delay::new(Duration::from_millis(50))
+                    .map(|_| seqs)
+                    .map_err(|e| panic!("{}", e))
+            })
+            .and_then(move |seqs| {
+                addr.send(Eval {
+                    src: "return ctx.state.resumed".to_string(),
+                })
+                .map(|resumed| (seqs, resumed))
+            })
+            .map(|(seqs, resumed)| {
+                assert_eq!(
+                    seqs,
+                    (
+                        lua_array![1, "user"],
+                        lua_array![2, "user"],
+                        lua_array![3, "user"]
+                    )
+                );
+                assert_eq!(resumed, lua_array![4, "user"]);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_mailbox_batching() {
+        let system = System::new("test");
+
+        let script = r#"
+            ctx.state.runs = (ctx.state.runs or 0) + 1
+            local res = {}
+            for i, msg in ipairs(ctx.msg) do
+                res[i] = { msg * 2, ctx.state.runs }
+            end
+            return res
+            "#;
+        let batching = |max| {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(script)
+                .with_sequence_arrays(true)
+                .with_mailbox_batching(max)
+                .build()
+                .unwrap()
+                .start()
+        };
+        let (addr, capped) = (batching(16), batching(2));
+        let send3 = |addr: &Addr<LuaActor>| {
+            addr.send(LuaMessage::from(1)).join3(
+                addr.send(LuaMessage::from(2)),
+                addr.send(LuaMessage::from(3)),
+            )
+        };
+
+        let l = send3(&addr).join(send3(&capped)).map(|(res, capped)| {
+            // all three messages are handled by one run
+            assert_eq!(res, (lua_array![2, 1], lua_array![4, 1], lua_array![6, 1]));
+            assert_eq!(
+                capped,
+                (lua_array![2, 1], lua_array![4, 1], lua_array![6, 2])
+            );
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_mailbox_batching_preconditions() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local res = {}
+            for i, msg in ipairs(ctx.msg) do
+                res[i] = msg * 2
+            end
+            return res
+            "#,
+            )
+            .with_sequence_arrays(true)
+            .with_mailbox_batching(16)
+            .with_precondition(|msg| match msg {
+                LuaMessage::Integer(x) if *x > 0 => Ok(()),
+                _ => Err("expect a positive integer".to_string()),
+            })
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from(1))
+            .join3(
+                addr.send(LuaMessage::from(-2)),
+                addr.send(LuaMessage::from(3)),
+            )
+            .map(|(one, two, three)| {
+                // only the rejected message fails, the others are handled together
+                assert_eq!(one, LuaMessage::from(2));
+                assert_eq!(
+                    two.downcast_error::<LuaActorError>(),
+                    Some(&LuaActorError::Precondition(
+                        "expect a positive integer".to_string()
+                    ))
+                );
+                assert_eq!(three, LuaMessage::from(6));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_on_error() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "bad" then
+                error("bad message")
+            end
+            return { ok = true }
+            "#,
+            )
+            .on_error_with_lua(
+                r#"
+            local err = ...
+            if ctx.msg == "bad" then
+                return { ok = false, error = err }
+            end
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+        let failing = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"error("bad message")"#)
+            .on_error_with_lua(r#"error("failed again")"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("bad"))
+            .join(addr.send(LuaMessage::from("good")))
+            .join(failing.send(LuaMessage::Nil));
+        Arbiter::spawn(
+            l.map(|((bad, good), failed)| {
+                assert_eq!(bad.path_get("ok"), Some(&LuaMessage::from(false)));
+                assert!(bad
+                    .path_get("error")
+                    .unwrap()
+                    .as_display_cow()
+                    .contains("bad message"));
+                assert_eq!(good, lua_table! { "ok" => true });
+                match failed.downcast_error::<LuaActorError>() {
+                    Some(LuaActorError::ErrorHook(e)) => assert!(e.contains("failed again")),
+                    e => panic!("unexpected error {:?}", e),
+                }
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_on_error_after_send() {
+        let system = System::new("test");
+
+        struct Echo;
+        impl Actor for Echo {
+            type Context = Context<Self>;
+        }
+        impl Handler<LuaMessage> for Echo {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+                msg
+            }
+        }
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let sink = reports.clone();
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local reply = ctx.send("echo", ctx.msg)
+            error("failed after " .. reply)
+            "#,
+            )
+            .on_error_with_lua(
+                r#"
+            local err = ...
+            ctx.state.handled = { msg = ctx.msg, error = err }
+            "#,
+            )
+            .with_crash_reporter(&[], move |report| sink.lock().unwrap().push(report))
+            .build()
+            .unwrap();
+        actor.add_recipients("echo", Echo.start().recipient());
+        let addr = actor.start();
+
+        let l = addr
+            .send(LuaMessage::from("ping"))
+            .and_then(|_| Delay::new(Duration::from_millis(50)).map_err(|e| panic!("{}", e)))
+            .and_then(move |_| {
+                addr.send(Eval {
+                    src: "return ctx.state.handled".to_string(),
+                })
+            })
+            .map(move |handled| {
+                assert_eq!(handled.path_get("msg"), Some(&LuaMessage::from("ping")));
+                // the crash is reported with the message the handler was started for
+                let reports = reports.lock().unwrap();
+                assert_eq!(reports.len(), 1);
+                assert_eq!(reports[0].input, LuaMessage::from("ping"));
+                assert!(reports[0].error.contains("failed after ping"));
+                assert!(handled
+                    .path_get("error")
+                    .unwrap()
+                    .as_display_cow()
+                    .contains("failed after ping"));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_crash_reporter() {
+        let system = System::new("test");
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let sink = reports.clone();
+        let script = r#"
+            if ctx.msg.__type == "charge" then
+                error("card declined")
+            end
+            return "ok"
+            "#;
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .on_error_with_lua(r#"return "failed""#)
+            .with_crash_reporter(&["card"], move |report| sink.lock().unwrap().push(report))
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(lua_table! { "__type" => "refund" })
+            .join(addr.send(lua_table! { "__type" => "charge", "card" => "4242" }));
+        Arbiter::spawn(
+            l.map(move |(refunded, charged)| {
+                assert_eq!(refunded, LuaMessage::from("ok"));
+                assert_eq!(charged, LuaMessage::from("failed"));
+                let reports = reports.lock().unwrap();
+                assert_eq!(reports.len(), 1);
+                let report = &reports[0];
+                assert_eq!(report.input.tag(), Some("charge"));
+                assert_eq!(
+                    report.input.path_get("card"),
+                    Some(&LuaMessage::from("***"))
+                );
+                assert!(report.error.contains("card declined"));
+                assert_eq!(report.source, script);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_string_sandbox() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local ok, err = pcall(string.rep, "x", ctx.msg)
+            if ok then
+                return #err
+            end
+            -- method calls go through the same function
+            local ok2 = pcall(function() return ("x"):rep(ctx.msg) end)
+            return { ok2, err }
+            "#,
+            )
+            .with_sequence_arrays(true)
+            .with_string_sandbox(1024)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from(1024))
+            .join(addr.send(LuaMessage::from(i64::MAX)));
+        Arbiter::spawn(
+            l.map(|(small, huge)| {
+                assert_eq!(small, LuaMessage::from(1024));
+                assert_eq!(huge.path_get("0"), Some(&LuaMessage::from(false)));
+                assert!(huge
+                    .path_get("1")
+                    .unwrap()
+                    .as_display_cow()
+                    .contains("string.rep result too large"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_max_message_bytes() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "big" then
+                return string.rep("x", 1000)
+            end
+            return #ctx.msg
+            "#,
+            )
+            .with_max_message_bytes(256)
+            .build()
+            .unwrap()
+            .start();
+
+        let big_table: Vec<LuaMessage> = (0..100).map(LuaMessage::from).collect();
+        let l = addr
+            .send(LuaMessage::Array(big_table))
+            .join(addr.send(LuaMessage::from("big")))
+            .join(addr.send(LuaMessage::from("small")));
+        Arbiter::spawn(
+            l.map(|((incoming, outgoing), small)| {
+                assert!(matches!(
+                    incoming.downcast_error::<LuaActorError>(),
+                    Some(LuaActorError::MessageTooLarge { max: 256, .. })
+                ));
+                assert!(matches!(
+                    outgoing.downcast_error::<LuaActorError>(),
+                    Some(LuaActorError::MessageTooLarge { max: 256, .. })
+                ));
+                assert_eq!(small, LuaMessage::from(5));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_precondition() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            ctx.state.runs = (ctx.state.runs or 0) + 1
+            return ctx.state.runs
+            "#,
+            )
+            .with_precondition(|msg| match msg {
+                LuaMessage::Integer(_) => Ok(()),
+                _ => Err("expect an integer".to_string()),
+            })
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("malformed"))
+            .join(addr.send(LuaMessage::from(1)));
+        Arbiter::spawn(
+            l.map(|(rejected, accepted)| {
+                assert_eq!(
+                    rejected.downcast_error::<LuaActorError>(),
+                    Some(&LuaActorError::Precondition(
+                        "expect an integer".to_string()
+                    ))
+                );
+                // the script didn't run for the rejected message
+                assert_eq!(accepted, LuaMessage::from(1));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_memory_accounting() {
+        let system = System::new("test");
+
+        let deltas = Arc::new(Mutex::new(vec![]));
+        let reported = deltas.clone();
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "alloc" then
+                local t = {}
+                for i = 1, 100000 do
+                    t[i] = i
+                end
+                ctx.state.big = t
+            else
+                ctx.state.big = nil
+                collectgarbage()
+            end
+            "#,
+            )
+            .with_memory_callback(move |delta, _| reported.lock().unwrap().push(delta))
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("alloc"))
+            .and_then(move |_| addr.send(LuaMessage::from("free")));
+        Arbiter::spawn(
+            l.map(move |_| {
+                let deltas = deltas.lock().unwrap();
+                assert_eq!(deltas.len(), 2);
+                // 100k integers take at least 800KB
+                assert!(deltas[0] > 800_000);
+                assert!(deltas[1] < -800_000);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_memory_limit() {
+        let actor = LuaActorBuilder::new()
+            .with_memory_limit(1024 * 1024)
+            .build()
+            .unwrap();
+        assert!(actor.used_memory() > 0);
+
+        let res = actor.vm.context(|ctx| {
+            ctx.load("local t = {} for i = 1, 1000000 do t[i] = i end")
+                .exec()
+        });
+        match res {
+            Err(LuaError::MemoryError(_)) => {}
+            res => panic!("expect a memory error, got {:?}", res),
+        }
+
+        actor.set_memory_limit(None);
+        let res = actor.vm.context(|ctx| {
+            ctx.load("local t = {} for i = 1, 1000000 do t[i] = i end")
+                .exec()
+        });
+        assert!(res.is_ok());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn lua_actor_tracing_span() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        #[derive(Clone, Default)]
+        struct Counter {
+            names: Arc<Mutex<Vec<&'static str>>>,
+            spans: Arc<AtomicUsize>,
+            entered: Arc<AtomicUsize>,
+            exited: Arc<AtomicUsize>,
+        }
+
+        impl tracing::Subscriber for Counter {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn new_span(&self, attrs: &Attributes) -> Id {
+                self.names.lock().unwrap().push(attrs.metadata().name());
+                Id::from_u64(self.spans.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+            }
+            fn record(&self, _: &Id, _: &Record) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &Event) {}
+            fn enter(&self, _: &Id) {
+                self.entered.fetch_add(1, Ordering::SeqCst);
+            }
+            fn exit(&self, _: &Id) {
+                self.exited.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        struct Echo;
+        impl Actor for Echo {
+            type Context = Context<Self>;
+        }
+        impl Handler<LuaMessage> for Echo {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+                msg
+            }
+        }
+
+        let counter = Counter::default();
+        let c = counter.clone();
+        tracing::subscriber::with_default(counter.clone(), || {
+            let system = System::new("test");
+
+            let mut actor = LuaActorBuilder::new()
+                .with_name("tracer")
+                .on_handle_with_lua(
+                    r#"
+                if ctx.msg == "send" then
+                    ctx.send("peer", 1)
+                end
+                return ctx.msg
+                "#,
+                )
+                .build()
+                .unwrap();
+            actor.add_recipients("peer", Echo.start().recipient());
+            let addr = actor.start();
+
+            let l = addr
+                .send(LuaMessage::from(1))
+                .join(addr.send(Batch {
+                    msgs: vec![LuaMessage::from(2), LuaMessage::from(3)],
+                }))
+                .join(addr.send(Eval {
+                    src: "return 4".to_string(),
+                }))
+                .join(addr.send(LuaMessage::from("send")))
+                .and_then(|_| Delay::new(Duration::from_millis(50)).map_err(|e| panic!("{}", e)));
+            Arbiter::spawn(
+                l.map(move |_| {
+                    assert_eq!(
+                        *c.names.lock().unwrap(),
+                        vec![
+                            "lua_actor_handle",
+                            "lua_actor_batch",
+                            "lua_actor_handle",
+                            "lua_actor_handle",
+                            "lua_actor_eval",
+                            "lua_actor_handle",
+                            "lua_actor_resume",
+                        ]
+                    );
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            );
+
+            system.run();
+        });
+
+        assert_eq!(counter.spans.load(Ordering::SeqCst), 7);
+        assert_eq!(counter.entered.load(Ordering::SeqCst), 7);
+        assert_eq!(counter.exited.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn lua_actor_eval() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_eval_cache_size(4)
+            .build()
+            .unwrap()
+            .start();
+
+        let eval = |src: &str| Eval {
+            src: src.to_string(),
+        };
+        let l = addr
+            .send(eval(
+                "ctx.state.x = (ctx.state.x or 0) + 1 return ctx.state.x",
+            ))
+            .join(addr.send(eval(
+                "ctx.state.x = (ctx.state.x or 0) + 1 return ctx.state.x",
+            )))
+            .join(addr.send(eval("return 1 +")))
+            .join(addr.send(eval("error('bad input')")))
+            .join(addr.send(eval("return ctx.state.x")));
+        Arbiter::spawn(
+            l.map(|((((first, second), broken), failed), after)| {
+                assert_eq!(first, LuaMessage::from(1));
+                assert_eq!(second, LuaMessage::from(2));
+                assert!(matches!(broken, LuaMessage::Error { .. }));
+                // a runtime error is the response, and the actor keeps running
+                match failed {
+                    LuaMessage::Error { message, .. } => assert!(message.contains("bad input")),
+                    _ => panic!("unexpected result {:?}", failed),
+                }
+                assert_eq!(after, LuaMessage::from(2));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_call_function() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            local total = 0
+            return function(n)
+                total = total + n
+                return total
+            end
+            "#,
+        )
+        .start();
+
+        let call = addr.clone();
+        let l = addr.send(LuaMessage::Nil).and_then(move |f| {
+            let function = match f {
+                LuaMessage::Function(x) => x,
+                x => panic!("expect a function, got {:?}", x),
+            };
+            call.send(CallFunction {
+                function,
+                arg: LuaMessage::from(1),
+            })
+            .join(call.send(CallFunction {
+                function,
+                arg: LuaMessage::from("x"),
+            }))
+            .join(call.send(CallFunction {
+                function,
+                arg: LuaMessage::from(2),
+            }))
+            .map(move |((first, failed), second)| {
+                // the error is the response, and the function can be called again
+                match failed {
+                    LuaMessage::Error { message, .. } => assert!(message.contains("arithmetic")),
+                    _ => panic!("unexpected result {:?}", failed),
+                }
+                (function, first, second)
+            })
+        });
+        let released = addr.clone();
+        let l = l.and_then(move |(function, first, second)| {
+            released
+                .send(ReleaseFunction(function))
+                .join(released.send(ReleaseFunction(function)))
+                .join(released.send(CallFunction {
+                    function,
+                    arg: LuaMessage::from(3),
+                }))
+                .map(move |res| (first, second, res))
+        });
+        Arbiter::spawn(
+            l.map(|(first, second, ((released, again), call))| {
+                assert_eq!(first, LuaMessage::from(1));
+                // the upvalue is kept between calls
+                assert_eq!(second, LuaMessage::from(3));
+                assert!(released);
+                assert!(!again);
+                assert_eq!(call, LuaMessage::error("unknown function handle"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_invoke_multi() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            return function(a, b)
+                return a .. "-" .. b
+            end
+            "#,
+        )
+        .start();
+
+        let call = addr.clone();
+        let l = addr.send(LuaMessage::Nil).and_then(move |f| {
+            let function = match f {
+                LuaMessage::Function(x) => x,
+                x => panic!("expect a function, got {:?}", x),
+            };
+            call.send(InvokeMulti {
+                function,
+                args: vec![LuaMessage::from("a"), LuaMessage::from(1)],
+            })
+            .join(call.send(InvokeMulti {
+                function,
+                args: vec![LuaMessage::from("a")],
+            }))
+        });
+        Arbiter::spawn(
+            l.map(|(res, missing)| {
+                assert_eq!(res, LuaMessage::from("a-1"));
+                // a missing argument is nil, which fails to concatenate
+                match missing {
+                    LuaMessage::Error { message, .. } => {
+                        assert!(message.contains("attempt to concatenate"), "{}", message)
+                    }
+                    x => panic!("expect an error, got {:?}", x),
+                }
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_add_fn_with_params() {
+        let mut actor = LuaActorBuilder::new().build().unwrap();
+        actor
+            .add_fn_with_params(
+                "connect",
+                &[("host", ParamKind::String), ("port", ParamKind::Integer)],
+                |args| {
+                    LuaMessage::from(format!(
+                        "{}:{}",
+                        args["host"].as_display_cow(),
+                        args["port"]
+                    ))
+                },
+            )
+            .unwrap();
+
+        actor.vm.context(|ctx| {
+            let res: LuaMessage = ctx
+                .load(r#"return connect{host="x", port=5432}"#)
+                .eval()
+                .unwrap();
+            assert_eq!(res, LuaMessage::from("x:5432"));
+
+            let err: Value = ctx
+                .load(r#"local ok, err = pcall(connect, {host="x"}) return err"#)
+                .eval()
+                .unwrap();
+            assert_eq!(
+                LuaMessage::from_lua(err, ctx)
+                    .unwrap()
+                    .downcast_error::<LuaActorError>(),
+                Some(&LuaActorError::Conversion(
+                    "missing parameter `port`".to_string()
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn lua_actor_add_fn_with_data() {
+        use std::sync::atomic::{AtomicI64, Ordering};
+
+        let mut actor = LuaActorBuilder::new().build().unwrap();
+        assert!(actor
+            .add_fn_with_data::<AtomicI64, _>("next_id", |_, _| LuaMessage::Nil)
+            .is_err());
+
+        let counter = Data::new(AtomicI64::new(41));
+        actor.add_data(counter.clone());
+        actor
+            .add_fn_with_data("next_id", |counter: &Data<AtomicI64>, _| {
+                LuaMessage::from(counter.fetch_add(1, Ordering::SeqCst) + 1)
+            })
+            .unwrap();
+
+        actor.vm.context(|ctx| {
+            let res: LuaMessage = ctx.load("return next_id()").eval().unwrap();
+            assert_eq!(res, LuaMessage::from(42));
+        });
+        assert_eq!(counter.load(Ordering::SeqCst), 42);
+        assert_eq!(
+            actor.data::<AtomicI64>().unwrap().load(Ordering::SeqCst),
+            42
+        );
+    }
+
+    #[test]
+    fn lua_actor_generate() {
+        use futures::sync::mpsc;
+        use futures::Stream;
+
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            for i = 1, 10 do
+                ctx.state.produced = i
+                coroutine.yield(i)
+            end
+            return "done"
+            "#,
+        )
+        .start();
+
+        // a channel holding at most 2 values until drained
+        let (tx, rx) = mpsc::channel(1);
+        let generated = addr.send(Generate {
+            msg: LuaMessage::Nil,
+            sink: Box::new(tx.sink_map_err(|_| ())),
+        });
+        let drained = Delay::new(Duration::from_millis(100))
+            .map_err(|e| panic!("{}", e))
+            .and_then(move |_| {
+                addr.send(Eval {
+                    src: "return ctx.state.produced".to_string(),
+                })
+            })
+            .and_then(move |produced| {
+                // the generator is paused until the channel is drained
+                assert!(matches!(produced, LuaMessage::Integer(n) if n < 4));
+                rx.collect().map_err(|_| unreachable!())
+            });
+        Arbiter::spawn(
+            generated
+                .join(drained)
+                .map(|(res, values)| {
+                    assert_eq!(res, Ok(LuaMessage::from("done")));
+                    let expected: Vec<LuaMessage> = (1..=10).map(LuaMessage::from).collect();
+                    assert_eq!(values, expected);
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handle_streaming() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            local result = {}
+            for i = 1, ctx.msg do
+                result[i] = i
+            end
+            return result
+            "#,
+        )
+        .start();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let sum = Arc::new(AtomicUsize::new(0));
+        let (c, s) = (count.clone(), sum.clone());
+        let l = addr.send(HandleStreaming {
+            msg: LuaMessage::from(10000),
+            callback: Box::new(move |_, v| {
+                c.fetch_add(1, Ordering::SeqCst);
+                if let LuaMessage::Integer(v) = v {
+                    s.fetch_add(v as usize, Ordering::SeqCst);
+                }
+            }),
+        });
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(res, LuaMessage::Nil);
+                assert_eq!(count.load(Ordering::SeqCst), 10000);
+                assert_eq!(sum.load(Ordering::SeqCst), 10000 * 10001 / 2);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handle_streaming_response() {
+        use crate::escape::HtmlEscape;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let system = System::new("test");
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua("return ctx.msg")
+            .with_escape(HtmlEscape)
+            .with_memory_callback(move |_, _| {
+                c.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(HandleStreaming {
+            msg: LuaMessage::from("<b>"),
+            callback: Box::new(|_, _| {}),
+        });
+        Arbiter::spawn(
+            l.map(move |res| {
+                // the final result is a response like the one of `handle`
+                assert_eq!(res, LuaMessage::from("&lt;b&gt;"));
+                assert_eq!(calls.load(Ordering::SeqCst), 1);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handle_streaming_after_send() {
+        use std::mem::discriminant;
+
+        let system = System::new("test");
+
+        let mut actor = lua_actor_with_handle(
+            r#"
+            local doubled = ctx.send("double", ctx.msg)
+            return { value = doubled }
+            "#,
+        );
+        actor.add_recipients(
+            "double",
+            lua_actor_with_handle("return ctx.msg * 2")
+                .start()
+                .recipient(),
+        );
+        let addr = actor.start();
+
+        let l = addr.send(HandleStreaming {
+            msg: LuaMessage::from(21),
+            callback: Box::new(move |k, v| {
+                assert_eq!(k, LuaMessage::from("value"));
+                assert_eq!(v, LuaMessage::from(42));
+                System::current().stop();
+            }),
+        });
         Arbiter::spawn(
             l.map(|res| {
-                assert_eq!(res, LuaMessage::from(2));
+                assert_eq!(
+                    discriminant(&res),
+                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_generate_errors() {
+        use futures::sync::mpsc;
+        use futures::Stream;
+
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "send" then
+                ctx.send("peer", 1)
+            end
+            coroutine.yield(1)
+            coroutine.yield(2)
+            error("boom")
+            "#,
+        )
+        .start();
+
+        let (tx, rx) = mpsc::channel(4);
+        let failed = addr.send(Generate {
+            msg: LuaMessage::Nil,
+            sink: Box::new(tx.sink_map_err(|_| ())),
+        });
+        let (send_tx, send_rx) = mpsc::channel(4);
+        let sent = addr.send(Generate {
+            msg: LuaMessage::from("send"),
+            sink: Box::new(send_tx.sink_map_err(|_| ())),
+        });
+        // the receiver is dropped, so the first value fails to be sent
+        let (closed_tx, _) = mpsc::channel(4);
+        let closed = addr.send(Generate {
+            msg: LuaMessage::Nil,
+            sink: Box::new(closed_tx.sink_map_err(|_| ())),
+        });
+
+        let check = addr.clone();
+        let l = failed
+            .join(rx.collect().map_err(|_| unreachable!()))
+            .join(sent.join(send_rx.collect().map_err(|_| unreachable!())))
+            .join(closed)
+            .and_then(move |res| {
+                check
+                    .send(Eval {
+                        src: "return next(__generators) == nil".to_string(),
+                    })
+                    .map(move |cleared| (res, cleared))
+            });
+        Arbiter::spawn(
+            l.map(
+                |((((res, values), (sent, sent_values)), closed), cleared)| {
+                    let is_error = |msg: &LuaMessage, text: &str| match msg {
+                        LuaMessage::Error { message, .. } => message.contains(text),
+                        _ => false,
+                    };
+                    assert!(is_error(res.as_ref().unwrap(), "boom"));
+                    assert_eq!(values.len(), 3);
+                    assert_eq!(values[..2], [LuaMessage::from(1), LuaMessage::from(2)]);
+                    assert!(is_error(&values[2], "boom"));
+
+                    let unavailable = "ctx.send isn't available in generators";
+                    assert!(is_error(sent.as_ref().unwrap(), unavailable));
+                    assert_eq!(sent_values.len(), 1);
+                    assert!(is_error(&sent_values[0], unavailable));
+
+                    assert_eq!(closed, Err(()));
+                    assert_eq!(cleared, LuaMessage::from(true));
+                    System::current().stop();
+                },
+            )
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handler_versions() {
+        fn register(version: &str, script: &str) -> RegisterHandler {
+            RegisterHandler {
+                version: version.to_string(),
+                script: script.to_string(),
+            }
+        }
+        fn activate(version: &str) -> ActivateHandler {
+            ActivateHandler {
+                version: version.to_string(),
+            }
+        }
+
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(r#"return "v0""#).start();
+
+        let l = addr
+            .send(register("v1", r#"return "v1""#))
+            .and_then(move |res| {
+                assert!(res.is_ok());
+                addr.send(register("v2", r#"return "v2""#))
+                    .map(move |res| (addr, res))
+            })
+            .and_then(|(a, res)| {
+                assert!(res.is_ok());
+                a.send(register("broken", "return 1 +"))
+                    .map(move |res| (a, res))
+            })
+            .and_then(|(a, res)| {
+                assert!(res.is_err());
+                a.send(activate("v1")).map(move |res| (a, res))
+            })
+            .and_then(|(a, res)| {
+                assert!(res.is_ok());
+                a.send(LuaMessage::Nil).map(move |res| (a, res))
+            })
+            .and_then(|(a, res)| {
+                assert_eq!(res, LuaMessage::from("v1"));
+                a.send(activate("v2")).map(move |res| (a, res))
+            })
+            .and_then(|(a, res)| {
+                assert!(res.is_ok());
+                a.send(LuaMessage::Nil).map(move |res| (a, res))
+            })
+            .and_then(|(a, res)| {
+                assert_eq!(res, LuaMessage::from("v2"));
+                a.send(RollbackHandler).map(move |res| (a, res))
+            })
+            .and_then(|(a, res)| {
+                assert!(res.is_ok());
+                a.send(LuaMessage::Nil)
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from("v1"));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_read_only_state() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.state.x = 1")
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "read" then
+                return ctx.state.x
+            elseif ctx.msg == "dump" then
+                return ctx.state
+            end
+            local ok, err = pcall(function() ctx.state.x = 2 end)
+            return {ok, err, ctx.state.x}
+            "#,
+            )
+            .with_sequence_arrays(true)
+            .with_read_only_state(true)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("read"))
+            .join(addr.send(LuaMessage::from("write")))
+            .join(addr.send(LuaMessage::from("dump")));
+        Arbiter::spawn(
+            l.map(|((read, write), dump)| {
+                assert_eq!(read, LuaMessage::from(1));
+                assert_eq!(dump, lua_table! { "x" => 1 });
+                match write {
+                    LuaMessage::Array(x) => {
+                        assert_eq!(x[0], LuaMessage::from(false));
+                        assert!(x[1].to_string().contains("state is read-only"));
+                        assert_eq!(x[2], LuaMessage::from(1));
+                    }
+                    _ => panic!("unexpected result {:?}", write),
+                }
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_read_only_message() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "state" then
+                return ctx.state
+            end
+            local ok = pcall(function() ctx.state.x = ctx.msg end)
+            return ok
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from(1))
+            .join(addr.send(ReadOnly(LuaMessage::from(2))))
+            .join(addr.send(ReadOnly(LuaMessage::from("state"))))
+            .join(addr.send(LuaMessage::from("state")))
+            .join(addr.send(LuaMessage::from(3)));
+        Arbiter::spawn(
+            l.map(
+                |((((plain, read_only), read_only_state), state), plain_again)| {
+                    assert_eq!(plain, LuaMessage::from(true));
+                    assert_eq!(read_only, LuaMessage::from(false));
+                    // the fields are still there, in and after a read-only message
+                    assert_eq!(read_only_state, lua_table! { "x" => 1 });
+                    assert_eq!(state, lua_table! { "x" => 1 });
+                    assert_eq!(plain_again, LuaMessage::from(true));
+                    System::current().stop();
+                },
+            )
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_request_id() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return {ctx.msg, request_id}"#)
+            .with_sequence_arrays(true)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(WithRequestId {
+                request_id: "req-1".to_string(),
+                msg: LuaMessage::from("ping"),
+            })
+            .join(addr.send(LuaMessage::from("pong")));
+        Arbiter::spawn(
+            l.map(|(traced, plain)| {
+                assert_eq!(
+                    traced,
+                    LuaMessage::Array(vec![LuaMessage::from("ping"), LuaMessage::from("req-1")])
+                );
+                assert_eq!(plain, LuaMessage::Array(vec![LuaMessage::from("pong")]));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handle_async() {
+        let system = System::new("test");
+
+        let mut actor = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "sync" then
+                local ok, err = pcall(fetch, "a")
+                return ok
+            end
+            local first = fetch(ctx.msg)
+            local second = fetch("missing")
+            return first + second
+            "#,
+        );
+        actor
+            .add_async_fn("fetch", |msg| {
+                Box::new(
+                    Delay::new(Duration::from_millis(10))
+                        .map_err(|e| LuaMessage::error(e.to_string()))
+                        .and_then(move |()| match msg {
+                            LuaMessage::String(ref key) if key == "missing" => {
+                                Err(LuaMessage::error("not found"))
+                            }
+                            msg => Ok(LuaMessage::from(format!(
+                                "fetched {}",
+                                msg.scalar_to_string().unwrap_or_default()
+                            ))),
+                        }),
+                )
+            })
+            .unwrap();
+        let addr = actor.start();
+
+        let l = addr
+            .send(HandleAsync {
+                msg: LuaMessage::from("key"),
+            })
+            .join(addr.send(LuaMessage::from("sync")));
+        Arbiter::spawn(
+            l.map(|(res, sync)| {
+                assert_eq!(res.path_get("result"), Some(&LuaMessage::from(3)));
+                assert_eq!(
+                    res.path_get("async.0"),
+                    Some(&LuaMessage::from("fetched key"))
+                );
+                assert_eq!(
+                    res.path_get("async.1"),
+                    Some(&LuaMessage::error("not found"))
+                );
+                assert_eq!(sync, LuaMessage::from(false));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handle_async_after_send() {
+        let system = System::new("test");
+
+        struct Echo;
+        impl Actor for Echo {
+            type Context = Context<Self>;
+        }
+        impl Handler<LuaMessage> for Echo {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+                msg
+            }
+        }
+
+        let mut actor = lua_actor_with_handle(
+            r#"
+            local first = fetch("before")
+            local reply = ctx.send("echo", "after")
+            return first + fetch(reply)
+            "#,
+        );
+        actor
+            .add_async_fn("fetch", |msg| {
+                Box::new(futures::future::ok(LuaMessage::from(format!(
+                    "fetched {}",
+                    msg.scalar_to_string().unwrap_or_default()
+                ))))
+            })
+            .unwrap();
+        actor.add_recipients("echo", Echo.start().recipient());
+        let addr = actor.start();
+
+        let l = addr
+            .send(HandleAsync {
+                msg: LuaMessage::Nil,
+            })
+            .join(addr.send(Eval {
+                src: "return fetch ~= nil and pcall(fetch, 'outside')".to_string(),
+            }));
+        Arbiter::spawn(
+            l.map(|(res, outside)| {
+                assert_eq!(res.path_get("result"), Some(&LuaMessage::from(3)));
+                assert_eq!(
+                    res.path_get("async.0"),
+                    Some(&LuaMessage::from("fetched before"))
+                );
+                assert_eq!(
+                    res.path_get("async.1"),
+                    Some(&LuaMessage::from("fetched after"))
+                );
+                // `pending` was cleared after the resumption
+                assert_eq!(outside, LuaMessage::from(false));
                 System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
@@ -362,34 +4339,229 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_syntax_error() {
-        let res = LuaActorBuilder::new()
-            .on_handle_with_lua(r"return 1+")
-            .build();
+    fn lua_actor_maintenance_interval() {
+        let system = System::new("test");
+
+        // the coroutine yields like `ctx.send`, but no reply ever resumes it
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"coroutine.yield("__suspended__" .. ctx.thread_id)"#)
+            .with_maintenance_interval(Duration::from_millis(20))
+            .build()
+            .unwrap()
+            .start();
+        let count = || Eval {
+            src: "local n = 0 for _ in pairs(__threads) do n = n + 1 end return n".to_string(),
+        };
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .join(addr.send(LuaMessage::Nil))
+            .and_then({
+                let addr = addr.clone();
+                move |_| addr.send(count())
+            })
+            .and_then(|before| {
+                assert_eq!(before, LuaMessage::from(2));
+                Delay::new(Duration::from_millis(100)).map_err(|e| panic!("{}", e))
+            })
+            .and_then(move |_| addr.send(count()))
+            .map(|after| {
+                assert_eq!(after, LuaMessage::from(0));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_maintenance_releases_limit() {
+        let system = System::new("test");
+
+        let limit = CoroutineLimit::new(2);
+        // the coroutine takes a slot and yields like `ctx.send`, but no reply ever resumes it
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            __acquire_coroutine()
+            coroutine.yield("__suspended__" .. ctx.thread_id)
+            "#,
+            )
+            .with_coroutine_limit(&limit)
+            .with_maintenance_interval(Duration::from_millis(50))
+            .build()
+            .unwrap()
+            .start();
+        let thread_id = |res: LuaMessage| match res {
+            LuaMessage::ThreadYield(id) => id.parse::<i64>().unwrap(),
+            res => panic!("unexpected result {:?}", res),
+        };
+
+        let (evicted, resumed) = (limit.clone(), limit.clone());
+        let l = addr
+            .send(LuaMessage::Nil)
+            .map(thread_id)
+            .and_then(|id| {
+                Delay::new(Duration::from_millis(150))
+                    .map(move |_| id)
+                    .map_err(|e| panic!("{}", e))
+            })
+            .and_then({
+                let addr = addr.clone();
+                move |id| {
+                    // the coroutine was evicted and gave back its slot
+                    assert_eq!(evicted.suspended(), 0);
+                    addr.send(LuaMessage::Nil).map(move |_| id)
+                }
+            })
+            .and_then(move |id| {
+                // a late reply to the evicted coroutine
+                addr.send(SendAttemptResult {
+                    msg: LuaMessage::Nil,
+                    cb_thread_id: id,
+                    failed: false,
+                })
+            })
+            .map(move |_| {
+                // only the slot of the coroutine suspended since is taken
+                assert_eq!(resumed.suspended(), 1);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_shutdown_timeout() {
+        // replies with the message after as many milliseconds
+        struct Peer;
+        impl Actor for Peer {
+            type Context = Context<Self>;
+        }
+
+        struct Delayed(LuaMessage);
+        impl MessageResponse<Peer, LuaMessage> for Delayed {
+            fn handle<R: ResponseChannel<LuaMessage>>(self, _: &mut Context<Peer>, tx: Option<R>) {
+                let ms = match self.0 {
+                    LuaMessage::Integer(ms) => ms as u64,
+                    _ => 0,
+                };
+                let msg = self.0;
+                Arbiter::spawn(
+                    Delay::new(Duration::from_millis(ms))
+                        .map(move |_| {
+                            if let Some(tx) = tx {
+                                tx.send(msg);
+                            }
+                        })
+                        .map_err(|e| panic!("{}", e)),
+                );
+            }
+        }
+
+        impl Handler<LuaMessage> for Peer {
+            type Result = Delayed;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                Delayed(msg)
+            }
+        }
+
+        struct Log(Arc<Mutex<Vec<LuaMessage>>>);
+        impl Actor for Log {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Log {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                self.0.lock().unwrap().push(msg);
+                LuaMessage::Nil
+            }
+        }
+
+        let system = System::new("test");
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let peer = Peer.start().recipient();
+        let log_addr = Log(log.clone()).start().recipient();
+        let start = || {
+            let mut actor = LuaActorBuilder::new()
+                .on_handle_with_lua(
+                    r#"
+                if ctx.msg == "stop" then
+                    ctx.stop()
+                    return
+                end
+                local res = ctx.send("peer", ctx.msg)
+                ctx.do_send("log", res)
+                "#,
+                )
+                .with_shutdown_timeout(Duration::from_millis(100))
+                .build()
+                .unwrap();
+            actor.add_recipients("peer", peer.clone());
+            actor.add_recipients("log", log_addr.clone());
+            actor.start()
+        };
+        let within = start();
+        let past = start();
+
+        within.do_send(LuaMessage::from(20));
+        within.do_send(LuaMessage::from("stop"));
+        past.do_send(LuaMessage::from(300));
+        past.do_send(LuaMessage::from("stop"));
+
+        let l = Delay::new(Duration::from_millis(500)).map(move |_| {
+            assert!(!within.connected());
+            assert!(!past.connected());
+            // the coroutine resumed past the timeout never logged its reply
+            assert_eq!(*log.lock().unwrap(), vec![LuaMessage::from(20)]);
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| panic!("{}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_metrics() {
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<Vec<(String, f64)>>>);
+        impl MetricsSink for Recorder {
+            fn increment(&self, name: &str) {
+                self.0.lock().unwrap().push((name.to_string(), 1.0));
+            }
 
-        if let Ok(_) = res {
-            panic!("should return Err(syntax_error)");
+            fn timing(&self, name: &str, ms: f64) {
+                self.0.lock().unwrap().push((name.to_string(), ms));
+            }
         }
-    }
 
-    #[should_panic]
-    #[test]
-    fn lua_actor_user_error() {
         let system = System::new("test");
 
-        let lua_addr = lua_actor_with_handle(
-            r#"
-        print("before")
-        error("foo")
-        print("after")
-        "#,
-        )
-        .start();
+        let recorder = Recorder::default();
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            metrics.increment("requests")
+            metrics.timing("latency", 12.5)
+            "#,
+            )
+            .with_metrics_sink(recorder.clone())
+            .build()
+            .unwrap()
+            .start();
 
-        let l = lua_addr.send(LuaMessage::from(0));
+        let l = addr.send(LuaMessage::Nil);
         Arbiter::spawn(
-            l.map(|_| {
-                // it should panic
+            l.map(move |_| {
+                assert_eq!(
+                    *recorder.0.lock().unwrap(),
+                    vec![("requests".to_string(), 1.0), ("latency".to_string(), 12.5)]
+                );
                 System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
@@ -399,23 +4571,24 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_return_table() {
+    fn lua_actor_eval_with() {
         let system = System::new("test");
 
-        let lua_addr = lua_actor_with_handle(
-            r#"
-        return {x = 1}
-        "#,
-        )
-        .start();
+        let addr = lua_actor_with_handle("return ctx.state.n").start();
 
-        let l = lua_addr.send(LuaMessage::from(3));
+        let l = addr
+            .send(EvalWith {
+                src: "ctx.state = 1 return ctx.x + ctx.y".to_string(),
+                context: lua_table! { "x" => 1, "y" => 2 },
+            })
+            .join(addr.send(Eval {
+                src: "return type(ctx.state) == \"table\"".to_string(),
+            }));
         Arbiter::spawn(
-            l.map(|res| {
-                let mut t = HashMap::new();
-                t.insert("x".to_string(), LuaMessage::from(1));
-
-                assert_eq!(res, LuaMessage::from(t));
+            l.map(|(res, state)| {
+                assert_eq!(res, LuaMessage::from(3));
+                // the persistent state wasn't replaced
+                assert_eq!(state, LuaMessage::from(true));
                 System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
@@ -425,31 +4598,20 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_state() {
+    fn lua_actor_eval_with_error() {
         let system = System::new("test");
 
-        let lua_addr = lua_actor_with_handle(
-            r#"
-        if not ctx.state.x then ctx.state.x = 0 end
-
-        ctx.state.x = ctx.state.x + 1
-        return ctx.state.x
-        "#,
-        )
-        .start();
+        let addr = lua_actor_with_handle("return 1").start();
 
-        let l = lua_addr.send(LuaMessage::Nil);
+        let l = addr.send(EvalWith {
+            src: "error(\"boom\")".to_string(),
+            context: LuaMessage::Nil,
+        });
         Arbiter::spawn(
-            l.map(move |res| {
-                assert_eq!(res, LuaMessage::from(1));
-                let l2 = lua_addr.send(LuaMessage::Nil);
-                Arbiter::spawn(
-                    l2.map(|res| {
-                        assert_eq!(res, LuaMessage::from(2));
-                        System::current().stop();
-                    })
-                    .map_err(|e| println!("actor dead {}", e)),
-                );
+            l.map(|res| {
+                // without the "runtime error: " prefix of rlua
+                assert_eq!(res, LuaMessage::error("[string \"eval_with\"]:1: boom"));
+                System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
         );
@@ -458,166 +4620,413 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_notify() {
+    fn lua_actor_long_lived_handle() {
         let system = System::new("test");
 
         let addr = LuaActorBuilder::new()
-            .on_started_with_lua(
-                r#"
-            ctx.notify(100)
-            "#,
-            )
             .on_handle_with_lua(
                 r#"
-            if ctx.msg == 100 then
-                ctx.state.notified = ctx.msg
+            local msg = ...
+            local sum = 0
+            while true do
+                sum = sum + msg
+                msg = coroutine.yield(sum)
             end
-
-            return ctx.msg + ctx.state.notified
             "#,
             )
+            .with_long_lived_handle(true)
             .build()
             .unwrap()
             .start();
 
-        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
-            let l = addr.send(LuaMessage::from(1));
-            Arbiter::spawn(
-                l.map(|res| {
-                    assert_eq!(res, LuaMessage::from(101));
-                    System::current().stop();
-                })
-                .map_err(|e| println!("actor dead {}", e)),
+        let l = addr
+            .send(LuaMessage::from(1))
+            .join3(
+                addr.send(LuaMessage::from(2)),
+                addr.send(LuaMessage::from(3)),
             )
-        });
-        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+            .and_then(move |sums| {
+                assert_eq!(
+                    sums,
+                    (
+                        LuaMessage::from(1),
+                        LuaMessage::from(3),
+                        LuaMessage::from(6)
+                    )
+                );
+                // no state outside of the coroutine
+                addr.send(Eval {
+                    src: "return next(ctx.state) == nil and next(__threads) == nil".to_string(),
+                })
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from(true));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_notify_later() {
+    fn lua_actor_inherited_fields() {
+        let system = System::new("test");
+
+        let script = r#"
+            local defaults = setmetatable({ color = "red" }, { __index = { size = 1 } })
+            return setmetatable({ name = "foo", size = 2 }, { __index = defaults })
+            "#;
+        let own = lua_actor_with_handle(script).start();
+        let inherited = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .with_inherited_fields(true)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = own
+            .send(LuaMessage::Nil)
+            .join(inherited.send(LuaMessage::Nil));
+        Arbiter::spawn(
+            l.map(|(own, inherited)| {
+                assert_eq!(own, lua_table! { "name" => "foo", "size" => 2 });
+                assert_eq!(
+                    inherited,
+                    lua_table! { "name" => "foo", "size" => 2, "color" => "red" }
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_sequence_arrays_ignore_next() {
         let system = System::new("test");
 
         let addr = LuaActorBuilder::new()
-            .on_started_with_lua(
-                r#"
-            ctx.notify_later(100, 1)
-            "#,
-            )
             .on_handle_with_lua(
                 r#"
-            if ctx.msg == 100 then
-                ctx.state.notified = ctx.msg
+            if ctx.msg == "nil" then
+                next = nil
+            else
+                next = function() return nil end
             end
-
-            return ctx.msg + ctx.state.notified
+            return {1, 2}
             "#,
             )
+            .with_sequence_arrays(true)
             .build()
             .unwrap()
             .start();
 
-        let delay = Delay::new(Duration::from_secs(2)).map(move |()| {
-            let l2 = addr.send(LuaMessage::from(1));
-            Arbiter::spawn(
-                l2.map(|res| {
-                    assert_eq!(res, LuaMessage::from(101));
-                    System::current().stop();
-                })
-                .map_err(|e| println!("actor dead {}", e)),
-            )
-        });
-        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+        let l = addr
+            .send(LuaMessage::from("nil"))
+            .join(addr.send(LuaMessage::from("redefined")));
+        Arbiter::spawn(
+            l.map(|(removed, redefined)| {
+                assert_eq!(removed, lua_array![1, 2]);
+                assert_eq!(redefined, lua_array![1, 2]);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_send() {
-        use std::mem::discriminant;
+    fn lua_actor_json_arrays() {
         let system = System::new("test");
 
-        struct Callback;
-        impl Actor for Callback {
-            type Context = Context<Self>;
-        }
-
-        impl Handler<LuaMessage> for Callback {
-            type Result = LuaMessage;
+        let script = "return {10, {20}}";
+        let arrays = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .with_sequence_arrays(true)
+            .build()
+            .unwrap()
+            .start();
+        let tables = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .with_sequence_arrays(true)
+            .with_json_arrays(false)
+            .build()
+            .unwrap()
+            .start();
 
-            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
+        let l = arrays
+            .send(LuaMessage::Nil)
+            .join(tables.send(LuaMessage::Nil))
+            .join(tables.send(Eval {
+                src: script.to_string(),
+            }));
+        Arbiter::spawn(
+            l.map(|((arrays, tables), eval)| {
+                assert_eq!(arrays, lua_array![10, lua_array![20]]);
                 assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
+                    tables,
+                    lua_table! { "1" => 10, "2" => lua_table! { "1" => 20 } }
                 );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello");
-                    System::current().stop();
-                    LuaMessage::Boolean(true)
-                } else {
-                    unimplemented!()
-                }
-            }
-        }
-        let callback_addr = Callback.start();
+                assert_eq!(eval, tables);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_unused_globals() {
+        let system = System::new("test");
 
         let mut actor = LuaActorBuilder::new()
-            .on_started_with_lua(
-                r#"
-            local result = ctx.send("callback", "Hello")
-            print("result", "=", result)
-            "#,
+            .on_handle_with_lua("return used(ctx.msg)")
+            .with_global_tracking(true)
+            .build()
+            .unwrap();
+        actor.add_fn("used", |msg| msg).unwrap();
+        actor.add_fn("unused", |msg| msg).unwrap();
+        let addr = actor.start();
+        let untracked = lua_actor_with_handle("return 1").start();
+
+        let l = addr
+            .send(LuaMessage::from(1))
+            .and_then(move |res| {
+                assert_eq!(res, LuaMessage::from(1));
+                addr.send(UnusedGlobals)
+            })
+            .join(untracked.send(UnusedGlobals))
+            .map(|(unused, untracked)| {
+                assert_eq!(unused, Some(vec!["unused".to_string()]));
+                assert_eq!(untracked, None);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_fn_multi() {
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r##"
+            local a, b = pair(ctx.msg)
+            return { a, b, select("#", pair(ctx.msg)) }
+            "##,
             )
+            .with_sequence_arrays(true)
+            .build()
+            .unwrap();
+        actor
+            .add_fn_multi("pair", |msg| vec![LuaMessage::from(1), msg])
+            .unwrap();
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::from(2)).map(|res| {
+            assert_eq!(res, lua_array![1, 2, 2]);
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_raised_host_errors() {
+        let system = System::new("test");
+
+        let script = r#"
+            local ok, err = pcall(find, ctx.msg)
+            if ok then
+                return "found"
+            end
+            return err.code
+            "#;
+        let mut raised = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .with_raised_host_errors(true)
             .build()
             .unwrap();
+        let mut returned = lua_actor_with_handle(script);
+        for actor in [&mut raised, &mut returned].iter_mut() {
+            actor
+                .add_fn("find", |_| LuaMessage::error_with_code(404, "not found"))
+                .unwrap();
+            actor
+                .add_fn_multi("find_all", |msg| match msg {
+                    LuaMessage::Nil => vec![
+                        LuaMessage::from("partial"),
+                        LuaMessage::error_with_code(410, "gone"),
+                    ],
+                    _ => vec![LuaMessage::from("a"), LuaMessage::from("b")],
+                })
+                .unwrap();
+            actor.add_fn("found", |msg| msg).unwrap();
+        }
+        let (raised, returned) = (raised.start(), returned.start());
+        let multi = Eval {
+            src: r##"
+            local ok, err = pcall(find_all)
+            local a, b = find_all("x")
+            local n = select("#", found(1))
+            return { ok = ok, code = ok and "" or err.code, a = a, b = b, n = n }
+            "##
+            .to_string(),
+        };
+
+        let l = raised
+            .send(LuaMessage::from("foo"))
+            .join(returned.send(LuaMessage::from("foo")))
+            .join(raised.send(multi))
+            .map(|((raised, returned), multi)| {
+                assert_eq!(raised, LuaMessage::from(404));
+                assert_eq!(returned, LuaMessage::from("found"));
+                assert_eq!(
+                    multi,
+                    lua_table! { "ok" => false, "code" => 410, "a" => "a", "b" => "b", "n" => 1 }
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_max_output_bytes() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"print("héllo", ctx.msg)"#)
+            .with_max_output_bytes(11)
+            .build()
+            .unwrap()
+            .start();
+        let uncaptured = lua_actor_with_handle("return 1").start();
+
+        let l = addr
+            .send(LuaMessage::from(1))
+            .and_then({
+                let addr = addr.clone();
+                move |_| addr.send(TakeOutput)
+            })
+            .and_then({
+                let addr = addr.clone();
+                move |output| {
+                    let output = output.unwrap();
+                    assert_eq!(output.text, "héllo\t1\n");
+                    assert!(!output.truncated);
+                    addr.send(LuaMessage::from(2))
+                        .join(addr.send(LuaMessage::from(3)))
+                }
+            })
+            .and_then(move |_| addr.send(TakeOutput))
+            .join(uncaptured.send(TakeOutput))
+            .map(|(output, uncaptured)| {
+                // the cap falls inside "é" of the second line
+                let output = output.unwrap();
+                assert_eq!(output.text, format!("héllo\t2\nh{}", OUTPUT_TRUNCATED));
+                assert!(output.truncated);
+                assert_eq!(uncaptured, None);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
-        actor.add_recipients("callback", callback_addr.recipient());
-        actor.start();
         system.run();
     }
 
     #[test]
-    fn lua_actor_thread_yield() {
-        use std::mem::discriminant;
-        struct Callback;
-        impl Actor for Callback {
-            type Context = Context<Self>;
-        }
+    fn lua_actor_cas() {
+        let system = System::new("test");
 
-        impl Handler<LuaMessage> for Callback {
-            type Result = LuaMessage;
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.state.thread = coroutine.create(function() end)")
+            .on_handle_with_lua("return ctx.state.count")
+            .build()
+            .unwrap()
+            .start();
+        let cas = |key: &str, expected: LuaMessage, new: i64| Cas {
+            key: key.to_string(),
+            expected,
+            new: LuaMessage::from(new),
+        };
 
-            fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                LuaMessage::Nil
-            }
-        }
+        let l = addr
+            .send(cas("count", LuaMessage::Nil, 1))
+            .join(addr.send(cas("count", LuaMessage::from(1), 2)))
+            .join(addr.send(cas("count", LuaMessage::from(1), 3)))
+            .join(addr.send(cas("thread", LuaMessage::Nil, 1)))
+            .join(addr.send(LuaMessage::Nil))
+            .map(|((((first, second), stale), thread), count)| {
+                assert_eq!(first, LuaMessage::from(true));
+                assert_eq!(second, LuaMessage::from(true));
+                assert_eq!(stale, LuaMessage::from(false));
+                // a coroutine can't be converted, so it's an error rather than a failed compare
+                match thread {
+                    LuaMessage::Error { .. } => {}
+                    _ => panic!("unexpected result {:?}", thread),
+                }
+                assert_eq!(count, LuaMessage::from(2));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
 
+    #[test]
+    fn lua_actor_host_functions() {
         let system = System::new("test");
 
-        let mut actor = LuaActorBuilder::new()
-            .on_handle_with_lua(
-                r#"
-            local result = ctx.send("callback", "Hello")
-            print(result)
-            return result
-            "#,
-            )
-            .build()
+        let mut actor = lua_actor_with_handle("return 1");
+        actor.add_fn("upper", |msg| msg).unwrap();
+        actor
+            .add_fn_with_params("greet", &[("name", ParamKind::String)], |_| LuaMessage::Nil)
             .unwrap();
+        let addr = actor.start();
 
-        actor.add_recipients("callback", Callback.start().recipient());
+        let l = addr.send(HostFunctions).map(|names| {
+            assert_eq!(names, lua_array!["greet", "upper"]);
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
-        let addr = actor.start();
+        system.run();
+    }
 
-        let l = addr.send(LuaMessage::Nil);
+    #[test]
+    fn lua_actor_batch() {
+        let system = System::new("test");
+
+        let script = r#"
+            if ctx.msg == 2 then
+                return { __lua_error = true, code = 400, message = "bad" }
+            end
+            return ctx.msg * 10
+            "#;
+        let collect = lua_actor_with_handle(script).start();
+        let fail_fast = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .with_batch_mode(BatchMode::FailFast)
+            .build()
+            .unwrap()
+            .start();
+        let batch = || Batch {
+            msgs: vec![1.into(), 2.into(), 3.into()],
+        };
+
+        let l = collect.send(batch()).join(fail_fast.send(batch()));
         Arbiter::spawn(
-            l.map(move |res| {
-                assert_eq!(
-                    discriminant(&res),
-                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
-                );
+            l.map(|(collected, failed)| {
+                let bad = LuaMessage::error_with_code(400, "bad");
+                assert_eq!(collected, lua_array![10, bad.clone(), 30]);
+                assert_eq!(failed, lua_array![10, bad]);
                 System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
@@ -627,184 +5036,281 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_thread_yield_and_callback_message() {
-        use std::mem::discriminant;
-
-        struct Callback;
-        impl Actor for Callback {
-            type Context = Context<Self>;
-        }
+    fn lua_actor_pause() {
+        let system = System::new("test");
 
-        impl Handler<LuaMessage> for Callback {
-            type Result = LuaMessage;
+        let addr = lua_actor_with_handle(
+            r#"
+            ctx.state.n = (ctx.state.n or 0) + 1
+            return ctx.state.n
+            "#,
+        )
+        .start();
 
-            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
+        addr.do_send(Pause);
+        let queued = addr
+            .send(LuaMessage::Nil)
+            .join3(addr.send(LuaMessage::Nil), addr.send(LuaMessage::Nil));
+        let l = addr
+            .send(Eval {
+                src: "return ctx.state.n".to_string(),
+            })
+            .and_then(move |before| {
+                // the handler didn't run while paused
+                assert_eq!(before, LuaMessage::Nil);
+                addr.send(Resume)
+            })
+            .and_then(|_| queued)
+            .map(|results| {
                 assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
+                    results,
+                    (
+                        LuaMessage::from(1),
+                        LuaMessage::from(2),
+                        LuaMessage::from(3)
+                    )
                 );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello");
-                    LuaMessage::String(format!("{} from callback", s))
-                } else {
-                    unimplemented!()
-                }
-            }
-        }
-
-        struct Check;
-        impl Actor for Check {
-            type Context = Context<Self>;
-        }
-
-        impl Handler<LuaMessage> for Check {
-            type Result = LuaMessage;
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
-            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
-                assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
-                );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello from callback");
-                    System::current().stop();
-                    LuaMessage::Nil
-                } else {
-                    unimplemented!()
-                }
-            }
-        }
+        system.run();
+    }
 
+    #[test]
+    fn lua_actor_pause_queue() {
         let system = System::new("test");
-        let mut actor = LuaActorBuilder::new()
+
+        let addr = LuaActorBuilder::new()
             .on_handle_with_lua(
                 r#"
-            local result = ctx.send("callback", ctx.msg)
-            print("send result", "=", result)
-            ctx.send("check", result)
+            ctx.state.n = (ctx.state.n or 0) + 1
+            return ctx.state.n
             "#,
             )
+            .with_pause_queue_size(3)
             .build()
-            .unwrap();
+            .unwrap()
+            .start();
 
-        actor.add_recipients("callback", Callback.start().recipient());
-        actor.add_recipients("check", Check.start().recipient());
+        addr.do_send(Pause);
+        let queued = addr
+            .send(Batch {
+                msgs: vec![LuaMessage::Nil],
+            })
+            .join3(
+                addr.send(WithRequestId {
+                    request_id: "r".to_string(),
+                    msg: LuaMessage::Nil,
+                }),
+                addr.send(HandleAsync {
+                    msg: LuaMessage::Nil,
+                }),
+            );
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then(move |full| {
+                // the queue holds 3 messages
+                match full {
+                    LuaMessage::Error { message, .. } => {
+                        assert!(message.contains("queue is full"))
+                    }
+                    _ => panic!("unexpected result {:?}", full),
+                }
+                addr.send(Resume)
+            })
+            .and_then(|_| queued)
+            .map(|(batch, with_request_id, handle_async)| {
+                assert_eq!(batch, lua_array![1]);
+                assert_eq!(with_request_id, LuaMessage::from(2));
+                assert_eq!(handle_async.path_get("result"), Some(&LuaMessage::from(3)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
-        let addr = actor.start();
+        system.run();
+    }
 
-        let l = addr.send(LuaMessage::String("Hello".to_string()));
-        Arbiter::spawn(
-            l.map(move |res| {
-                assert_eq!(
-                    discriminant(&res),
-                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
-                );
+    #[test]
+    fn lua_actor_source() {
+        let system = System::new("test");
+
+        let script = r#"return "v0""#;
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .with_source_access(true)
+            .build()
+            .unwrap()
+            .start();
+        let hidden = lua_actor_with_handle(script).start();
+
+        let l = addr
+            .send(Source)
+            .and_then(move |res| {
+                assert_eq!(res.as_deref(), Some(script));
+                addr.send(RegisterHandler {
+                    version: "v1".to_string(),
+                    script: r#"return "v1""#.to_string(),
+                })
+                .map(move |_| addr)
             })
-            .map_err(|e| println!("actor dead {}", e)),
-        );
+            .and_then(|a| {
+                a.send(ActivateHandler {
+                    version: "v1".to_string(),
+                })
+                .map(move |_| a)
+            })
+            .and_then(|a| a.send(Source).map(move |res| (a, res)))
+            .and_then(|(a, res)| {
+                assert_eq!(res, Some(r#"return "v1""#.to_string()));
+                a.send(RollbackHandler).map(move |_| a)
+            })
+            .and_then(|a| a.send(Source))
+            .and_then(move |res| {
+                assert_eq!(res.as_deref(), Some(script));
+                hidden.send(Source)
+            })
+            .map(|res| {
+                assert_eq!(res, None);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_do_send() {
-        use std::mem::discriminant;
+    fn lua_actor_userdata_types() {
+        use rlua::UserDataMethods;
 
-        struct Check;
-        impl Actor for Check {
-            type Context = Context<Self>;
+        struct Point(i64, i64);
+        impl UserData for Point {
+            fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(_: &mut M) {}
         }
-
-        impl Handler<LuaMessage> for Check {
-            type Result = LuaMessage;
-
-            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
-                assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
-                );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello");
-                    System::current().stop();
-                    LuaMessage::Nil
-                } else {
-                    unimplemented!()
-                }
-            }
+        struct Color(&'static str);
+        impl UserData for Color {
+            fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(_: &mut M) {}
         }
+
         let system = System::new("test");
 
         let mut actor = LuaActorBuilder::new()
-            .on_handle_with_lua(
-                r#"
-            local result = ctx.do_send("check", "Hello")
-            print("new actor addr name", rec, result)
-            return ctx.msg
-            "#,
-            )
+            .on_handle_with_lua(r#"if ctx.msg == "point" then return p end return c"#)
+            .with_vm_setup(|ctx| {
+                ctx.globals().set("p", ctx.create_userdata(Point(1, 2))?)?;
+                ctx.globals().set("c", ctx.create_userdata(Color("red"))?)
+            })
             .build()
             .unwrap();
-        actor.add_recipients("check", Check.start().recipient());
+        actor
+            .add_userdata_formatter("Point", |p: &Point| format!("({}, {})", p.0, p.1))
+            .unwrap();
+        actor
+            .add_userdata_formatter("Color", |c: &Color| c.0.to_string())
+            .unwrap();
         let addr = actor.start();
 
-        let l = addr.send(LuaMessage::Nil);
-        Arbiter::spawn(
-            l.map(|res| {
-                assert_eq!(res, LuaMessage::Nil);
-            })
-            .map_err(|e| println!("actor dead {}", e)),
-        );
+        let describe = |msg: LuaMessage| match msg.userdata_type() {
+            Some("Point") => format!("point at {}", msg),
+            Some("Color") => format!("{} color", msg),
+            _ => panic!("unexpected {:?}", msg),
+        };
+        let l = addr
+            .send(LuaMessage::from("point"))
+            .join(addr.send(LuaMessage::from("color")))
+            .map(move |(point, color)| {
+                assert_eq!(describe(point), "point at (1, 2)");
+                assert_eq!(describe(color), "red color");
+                assert_eq!(LuaMessage::from(1).userdata_type(), None);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_terminate() {
-        // TODO: validate on_stopped is called
+    fn lua_actor_userdata_formatter() {
+        use rlua::UserDataMethods;
+
+        struct Point(i64, i64);
+        impl UserData for Point {
+            fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(_: &mut M) {}
+        }
+
         let system = System::new("test");
 
-        let _ = LuaActorBuilder::new()
-            .on_started_with_lua(
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            ctx.globals()
+                .set("p", ctx.create_userdata(Point(1, 2)).unwrap())
+                .unwrap();
+        });
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
                 r#"
-            ctx.terminate()
+            if ctx.msg == "tostring" then
+                return tostring(p)
+            end
+            if ctx.msg == "global" then
+                return __userdata_formatters == nil
+            end
+            return p
             "#,
             )
-            .on_stopped_with_lua(r#"print("stopped")"#)
-            .build()
-            .unwrap()
-            .start();
-        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
-            System::current().stop();
-        });
-        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+            .build_with_vm(vm)
+            .unwrap();
+        actor
+            .add_userdata_formatter("Point", |p: &Point| format!("Point({}, {})", p.0, p.1))
+            .unwrap();
+        let addr = actor.start();
+
+        let l = addr
+            .send(LuaMessage::from("tostring"))
+            .join(addr.send(LuaMessage::from("global")));
+        Arbiter::spawn(
+            l.map(move |(res, hidden)| {
+                assert_eq!(res, LuaMessage::from("Point(1, 2)"));
+                // the formatters aren't visible to scripts
+                assert_eq!(hidden, LuaMessage::from(true));
+                let l2 = addr.send(LuaMessage::Nil);
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(
+                            res,
+                            LuaMessage::UserData {
+                                type_name: "Point".to_string(),
+                                repr: "Point(1, 2)".to_string()
+                            }
+                        );
+                        assert_eq!(res.to_string(), "Point(1, 2)");
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
 
         system.run();
     }
 
-    use std::env;
-
     #[test]
-    fn lua_actor_require() {
+    fn lua_actor_vm_setup() {
         let system = System::new("test");
-        env::set_var("LUA_PATH", "./src/?.lua;;");
 
         let addr = LuaActorBuilder::new()
-            .on_handle_with_lua(
-                r#"
-                local m = require('lua/test/module')
-                return m.incr(ctx.msg)
-            "#,
-            )
+            .on_handle_with_lua("return greeting .. ctx.msg")
+            .with_vm_setup(|ctx| ctx.globals().set("greeting", "Hello, "))
             .build()
             .unwrap()
             .start();
-        let l = addr.send(LuaMessage::from(1));
+
+        let l = addr.send(LuaMessage::from("World"));
         Arbiter::spawn(
             l.map(|res| {
-                assert_eq!(res, LuaMessage::from(2));
+                assert_eq!(res, LuaMessage::from("Hello, World"));
                 System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
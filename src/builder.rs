@@ -1,14 +1,53 @@
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::actor::LuaActor;
-use rlua::{Error as LuaError, Lua};
+use crate::actor::{
+    BatchMode, CrashReporter, LuaActor, MemoryCallback, ModuleResolver, Precondition, VmSetup,
+    DEFAULT_PAUSE_QUEUE_SIZE,
+};
+use crate::cache::{ScriptCache, DEFAULT_EVAL_CACHE_SIZE};
+use crate::escape::EscapePolicy;
+use crate::host::{CrashReport, MetricsSink, VirtualFs};
+use crate::limit::CoroutineLimit;
+use crate::message::LuaMessage;
+use rlua::{Context, Error as LuaError, Lua};
 
 /// `LuaActorBuilder` creates a new `LuaActor` with given Lua script.
 pub struct LuaActorBuilder {
     started: Option<String>,
     handle: Option<String>,
+    on_message: Option<String>,
+    on_error: Option<String>,
     stopped: Option<String>,
+    eval_cache_size: usize,
+    escape: Option<Box<dyn EscapePolicy + Send>>,
+    preconditions: Vec<Precondition>,
+    name: Option<String>,
+    memory_limit: Option<usize>,
+    memory_callback: Option<MemoryCallback>,
+    coroutine_limit: Option<CoroutineLimit>,
+    max_message_bytes: Option<usize>,
+    source_access: bool,
+    read_only_state: bool,
+    maintenance_interval: Option<Duration>,
+    shutdown_timeout: Option<Duration>,
+    json_arrays: bool,
+    module_resolver: Option<ModuleResolver>,
+    global_tracking: bool,
+    inherited_fields: bool,
+    sequence_arrays: bool,
+    long_lived_handle: bool,
+    metrics_sink: Option<Arc<dyn MetricsSink>>,
+    batch_mode: BatchMode,
+    raise_host_errors: bool,
+    max_output_bytes: Option<usize>,
+    vm_setup: Option<VmSetup>,
+    mailbox_batch_size: Option<usize>,
+    pause_queue_size: usize,
+    string_sandbox: Option<usize>,
+    crash_reporter: Option<(Vec<String>, CrashReporter)>,
 }
 
 impl Default for LuaActorBuilder {
@@ -17,7 +56,36 @@ impl Default for LuaActorBuilder {
         LuaActorBuilder {
             started: noop.clone(),
             handle: noop.clone(),
+            on_message: None,
+            on_error: None,
             stopped: noop.clone(),
+            eval_cache_size: DEFAULT_EVAL_CACHE_SIZE,
+            escape: None,
+            preconditions: vec![],
+            name: None,
+            memory_limit: None,
+            memory_callback: None,
+            coroutine_limit: None,
+            max_message_bytes: None,
+            source_access: false,
+            read_only_state: false,
+            maintenance_interval: None,
+            shutdown_timeout: None,
+            json_arrays: true,
+            module_resolver: None,
+            global_tracking: false,
+            inherited_fields: false,
+            sequence_arrays: false,
+            long_lived_handle: false,
+            metrics_sink: None,
+            batch_mode: BatchMode::default(),
+            raise_host_errors: false,
+            max_output_bytes: None,
+            vm_setup: None,
+            mailbox_batch_size: None,
+            pause_queue_size: DEFAULT_PAUSE_QUEUE_SIZE,
+            string_sandbox: None,
+            crash_reporter: None,
         }
     }
 }
@@ -52,6 +120,30 @@ impl LuaActorBuilder {
         self
     }
 
+    /// run the given lua file before `handle`, a non-`nil` result answers the message
+    pub fn on_message(mut self, filename: &str) -> Self {
+        self.on_message = Some(read_to_string(filename));
+        self
+    }
+
+    /// run the given lua script before `handle`, a non-`nil` result answers the message
+    pub fn on_message_with_lua(mut self, script: &str) -> Self {
+        self.on_message = Some(script.to_string());
+        self
+    }
+
+    /// run the given lua file when `handle` raises an error, its result is the response
+    pub fn on_error(mut self, filename: &str) -> Self {
+        self.on_error = Some(read_to_string(filename));
+        self
+    }
+
+    /// run the given lua script when `handle` raises an error, its result is the response
+    pub fn on_error_with_lua(mut self, script: &str) -> Self {
+        self.on_error = Some(script.to_string());
+        self
+    }
+
     /// create a `stopped` hook with given lua file.
     pub fn on_stopped(mut self, filename: &str) -> Self {
         self.stopped = Some(read_to_string(filename));
@@ -64,25 +156,268 @@ impl LuaActorBuilder {
         self
     }
 
+    /// set how many compiled `Eval` scripts are cached. Defaults to 16.
+    pub fn with_eval_cache_size(mut self, size: usize) -> Self {
+        self.eval_cache_size = size;
+        self
+    }
+
+    /// escape strings in the responses with the given policy, such as `HtmlEscape`
+    pub fn with_escape<P: EscapePolicy + Send + 'static>(mut self, policy: P) -> Self {
+        self.escape = Some(Box::new(policy));
+        self
+    }
+
+    /// validate each incoming message before the `handle` script runs
+    pub fn with_precondition<F>(mut self, check: F) -> Self
+    where
+        F: Fn(&LuaMessage) -> Result<(), String> + Send + 'static,
+    {
+        self.preconditions.push(Box::new(check));
+        self
+    }
+
+    /// name the actor, e.g. for the `actor` field of its tracing spans
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// limit the bytes the Lua VM can allocate, allocations beyond it raise a memory error
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// report the memory delta and total after each message handled by `handle`
+    pub fn with_memory_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(isize, usize) + Send + 'static,
+    {
+        self.memory_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// cap the coroutines suspended by `ctx.send`, shared with the actors using a clone of `limit`
+    pub fn with_coroutine_limit(mut self, limit: &CoroutineLimit) -> Self {
+        self.coroutine_limit = Some(limit.clone());
+        self
+    }
+
+    /// reject incoming messages and responses larger than `max` bytes
+    pub fn with_max_message_bytes(mut self, max: usize) -> Self {
+        self.max_message_bytes = Some(max);
+        self
+    }
+
+    /// allow reading the source of the active `handle` script with the `Source` message
+    pub fn with_source_access(mut self, enabled: bool) -> Self {
+        self.source_access = enabled;
+        self
+    }
+
+    /// make the top-level fields of `ctx.state` read-only while `handle` runs
+    pub fn with_read_only_state(mut self, enabled: bool) -> Self {
+        self.read_only_state = enabled;
+        self
+    }
+
+    /// run garbage collection and evict stale suspended coroutines every `interval`
+    pub fn with_maintenance_interval(mut self, interval: Duration) -> Self {
+        self.maintenance_interval = Some(interval);
+        self
+    }
+
+    /// let suspended coroutines complete for up to `timeout` when the actor is stopping
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// return `Array`s as JSON arrays instead of 1-based `Table`s, defaults to `true`
+    pub fn with_json_arrays(mut self, enabled: bool) -> Self {
+        self.json_arrays = enabled;
+        self
+    }
+
+    /// resolve `require` with `resolver` instead of the filesystem
+    pub fn with_module_resolver<F>(mut self, resolver: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + 'static,
+    {
+        self.module_resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// resolve `require` with the modules of `fs`, see `with_module_resolver`
+    pub fn with_virtual_fs(self, fs: VirtualFs) -> Self {
+        self.with_module_resolver(move |name| fs.get(name).map(str::to_string))
+    }
+
+    /// record which host functions registered on the actor are accessed, see the `UnusedGlobals` message
+    pub fn with_global_tracking(mut self, enabled: bool) -> Self {
+        self.global_tracking = enabled;
+        self
+    }
+
+    /// convert Lua sequences to `LuaMessage::Array`, defaults to `false`
+    pub fn with_sequence_arrays(mut self, enabled: bool) -> Self {
+        self.sequence_arrays = enabled;
+        self
+    }
+
+    /// include the fields inherited through `__index` metatables in converted tables
+    pub fn with_inherited_fields(mut self, enabled: bool) -> Self {
+        self.inherited_fields = enabled;
+        self
+    }
+
+    /// run the `handle` script as one coroutine across messages
+    pub fn with_long_lived_handle(mut self, enabled: bool) -> Self {
+        self.long_lived_handle = enabled;
+        self
+    }
+
+    /// forward the metrics emitted with the global `metrics` table to `sink`
+    pub fn with_metrics_sink<S: MetricsSink + 'static>(mut self, sink: S) -> Self {
+        self.metrics_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// choose whether a `Batch` stops at the first error or handles every message, defaults to `BatchMode::CollectErrors`
+    pub fn with_batch_mode(mut self, mode: BatchMode) -> Self {
+        self.batch_mode = mode;
+        self
+    }
+
+    /// raise the `Error` results of host functions as Lua errors
+    pub fn with_raised_host_errors(mut self, enabled: bool) -> Self {
+        self.raise_host_errors = enabled;
+        self
+    }
+
+    /// capture the output of `print`, keeping at most `max` bytes
+    pub fn with_max_output_bytes(mut self, max: usize) -> Self {
+        self.max_output_bytes = Some(max);
+        self
+    }
+
+    /// run `setup` on the Lua state before the `started` script
+    pub fn with_vm_setup<F>(mut self, setup: F) -> Self
+    where
+        F: for<'lua> FnOnce(Context<'lua>) -> Result<(), LuaError> + Send + 'static,
+    {
+        self.vm_setup = Some(Box::new(setup));
+        self
+    }
+
+    /// handle up to `max` queued messages together with a single run of `handle`
+    pub fn with_mailbox_batching(mut self, max: usize) -> Self {
+        self.mailbox_batch_size = Some(max.max(1));
+        self
+    }
+
+    /// set how many messages are queued while the actor is paused, see `Pause`. Defaults to 16.
+    pub fn with_pause_queue_size(mut self, size: usize) -> Self {
+        self.pause_queue_size = size;
+        self
+    }
+
+    /// make `string.rep` raise an error instead of building a string longer than `max` bytes
+    pub fn with_string_sandbox(mut self, max: usize) -> Self {
+        self.string_sandbox = Some(max);
+        self
+    }
+
+    /// deliver a `CrashReport` to `reporter` whenever `handle` raises an error
+    pub fn with_crash_reporter<F>(mut self, sensitive_keys: &[&str], reporter: F) -> Self
+    where
+        F: Fn(CrashReport) + Send + 'static,
+    {
+        let keys = sensitive_keys.iter().map(|k| k.to_string()).collect();
+        self.crash_reporter = Some((keys, Box::new(reporter)));
+        self
+    }
+
     /// build the actor with a preconfigured lua VM
     ///
     /// It's important to use the `rlua` interface exported by `actix-lua` with `use actix_lua::dev::rlua::*`
+    ///
+    /// The actor takes ownership of `vm`, which can't be used by other actors: a Lua state isn't thread-safe and
+    /// the actor relies on being the only one running scripts in it. The actor defines the `ctx` global and
+    /// globals prefixed with `__`, replacing any global of the same name, and these must not be modified.
     pub fn build_with_vm(self, vm: Lua) -> Result<LuaActor, LuaError> {
-        LuaActor::new_with_vm(
+        let mut actor = LuaActor::new_with_vm(
             vm,
             self.started.clone(),
             self.handle.clone(),
             self.stopped.clone(),
-        )
+        )?;
+        if let Some(script) = &self.on_message {
+            actor.load_script("on_message", script)?;
+        }
+        if let Some(script) = &self.on_error {
+            actor.set_error_hook(script)?;
+        }
+        actor.eval_cache = ScriptCache::new(self.eval_cache_size);
+        actor.escape = self.escape;
+        actor.preconditions = self.preconditions;
+        actor.name = self.name;
+        actor.memory_callback = self.memory_callback;
+        actor.max_message_bytes = self.max_message_bytes;
+        actor.source_access = self.source_access;
+        actor.maintenance_interval = self.maintenance_interval;
+        actor.shutdown_timeout = self.shutdown_timeout;
+        actor.json_arrays = self.json_arrays;
+        actor.batch_mode = self.batch_mode;
+        actor.mailbox_batch_size = self.mailbox_batch_size;
+        actor.pause_queue_size = self.pause_queue_size;
+        actor.raise_host_errors = self.raise_host_errors;
+        if self.memory_limit.is_some() {
+            actor.set_memory_limit(self.memory_limit);
+        }
+        if let Some(limit) = self.coroutine_limit {
+            actor.set_coroutine_limit(limit)?;
+        }
+        if let Some(resolver) = self.module_resolver {
+            actor.set_module_resolver(resolver)?;
+        }
+        if let Some(max) = self.max_output_bytes {
+            actor.capture_output(max)?;
+        }
+        if self.global_tracking {
+            actor.track_globals()?;
+        }
+        if let Some(sink) = self.metrics_sink {
+            actor.set_metrics_sink(sink)?;
+        }
+        if self.long_lived_handle {
+            actor.set_long_lived_handle()?;
+        }
+        if self.sequence_arrays {
+            actor.set_sequence_arrays()?;
+        }
+        if self.inherited_fields {
+            actor.set_inherited_fields()?;
+        }
+        if self.read_only_state {
+            actor.set_read_only_state()?;
+        }
+        if let Some(max) = self.string_sandbox {
+            actor.set_string_sandbox(max)?;
+        }
+        if let Some((keys, reporter)) = self.crash_reporter {
+            actor.set_crash_reporter(keys, reporter)?;
+        }
+        if let Some(setup) = self.vm_setup {
+            actor.setup_vm(setup)?;
+        }
+        Ok(actor)
     }
 
     /// build the actor
     pub fn build(self) -> Result<LuaActor, LuaError> {
-        LuaActor::new(
-            self.started.clone(),
-            self.handle.clone(),
-            self.stopped.clone(),
-        )
+        self.build_with_vm(Lua::new())
     }
 }
 
@@ -115,5 +450,4 @@ mod tests {
             panic!("should return error");
         }
     }
-
 }
@@ -0,0 +1,103 @@
+use lru_cache::LruCache;
+use rlua::{Context, Error as LuaError, Function};
+
+/// Default number of compiled `Eval` scripts kept by a `LuaActor`.
+pub const DEFAULT_EVAL_CACHE_SIZE: usize = 16;
+
+/// A LRU cache of compiled dynamic scripts, keyed by their source.
+///
+/// Compiled scripts live in the Lua `__scripts` table; the cache only keeps their names
+/// and unloads them when they are evicted.
+pub(crate) struct ScriptCache {
+    scripts: LruCache<String, String>,
+    compiles: usize,
+}
+
+impl ScriptCache {
+    pub fn new(capacity: usize) -> Self {
+        ScriptCache {
+            scripts: LruCache::new(capacity.max(1)),
+            compiles: 0,
+        }
+    }
+
+    /// Returns the name of the compiled script for `src`, compiling it if it isn't cached.
+    pub fn load(&mut self, ctx: Context, src: &str) -> Result<String, LuaError> {
+        if let Some(name) = self.scripts.get_mut(src) {
+            return Ok(name.clone());
+        }
+
+        // numbered by compilation, so each cached source has its own compiled script
+        let name = format!("eval@{}", self.compiles);
+        let load: Function = ctx.globals().get("__load")?;
+        load.call::<(&str, &str), ()>((src, &name))?;
+        self.compiles += 1;
+
+        if self.scripts.len() == self.scripts.capacity() {
+            if let Some((_, evicted)) = self.scripts.remove_lru() {
+                let unload: Function = ctx.globals().get("__unload")?;
+                unload.call::<String, ()>(evicted)?;
+            }
+        }
+        self.scripts.insert(src.to_string(), name.clone());
+
+        Ok(name)
+    }
+
+    /// Number of times a script was compiled by this cache.
+    #[cfg(test)]
+    pub fn compiles(&self) -> usize {
+        self.compiles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    fn lua_with_prelude() -> Lua {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            ctx.load(include_str!("lua/prelude.lua")).exec().unwrap();
+        });
+        lua
+    }
+
+    #[test]
+    fn compile_once() {
+        let lua = lua_with_prelude();
+        let mut cache = ScriptCache::new(2);
+        lua.context(|ctx| {
+            let a = cache.load(ctx, "return 1").unwrap();
+            let b = cache.load(ctx, "return 1").unwrap();
+            assert_eq!(a, b);
+            assert_eq!(cache.compiles(), 1);
+            assert_ne!(cache.load(ctx, "return 2").unwrap(), a);
+        });
+    }
+
+    #[test]
+    fn evict_lru() {
+        let lua = lua_with_prelude();
+        let mut cache = ScriptCache::new(2);
+        lua.context(|ctx| {
+            let one = cache.load(ctx, "return 1").unwrap();
+            cache.load(ctx, "return 2").unwrap();
+            cache.load(ctx, "return 1").unwrap();
+            // evicts "return 2", the least recently used script
+            let three = cache.load(ctx, "return 3").unwrap();
+            assert_eq!(cache.compiles(), 3);
+
+            cache.load(ctx, "return 1").unwrap();
+            assert_eq!(cache.compiles(), 3);
+            cache.load(ctx, "return 2").unwrap();
+            assert_eq!(cache.compiles(), 4);
+
+            // "return 3" was evicted and unloaded
+            let scripts: rlua::Table = ctx.globals().get("__scripts").unwrap();
+            assert!(scripts.contains_key(one).unwrap());
+            assert!(!scripts.contains_key(three).unwrap());
+        });
+    }
+}
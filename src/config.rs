@@ -0,0 +1,123 @@
+//! `config::Value` conversions for `LuaMessage`, enabled with the `config` feature.
+use config::{Map, Value, ValueKind};
+
+use crate::error::LuaActorError;
+use crate::message::LuaMessage;
+
+use std::convert::TryFrom;
+
+impl LuaMessage {
+    /// Convert a value loaded with the `config` crate, e.g. `Config::cache`, so it can be handed to scripts.
+    ///
+    /// 128-bit and unsigned integers that don't fit in an `Integer` become a `Number`.
+    pub fn from_config_value(v: &Value) -> LuaMessage {
+        match &v.kind {
+            ValueKind::Nil => LuaMessage::Nil,
+            ValueKind::Boolean(x) => LuaMessage::Boolean(*x),
+            ValueKind::I64(x) => LuaMessage::Integer(*x),
+            ValueKind::I128(x) => match i64::try_from(*x) {
+                Ok(x) => LuaMessage::Integer(x),
+                Err(_) => LuaMessage::Number(*x as f64),
+            },
+            ValueKind::U64(x) => match i64::try_from(*x) {
+                Ok(x) => LuaMessage::Integer(x),
+                Err(_) => LuaMessage::Number(*x as f64),
+            },
+            ValueKind::U128(x) => match i64::try_from(*x) {
+                Ok(x) => LuaMessage::Integer(x),
+                Err(_) => LuaMessage::Number(*x as f64),
+            },
+            ValueKind::Float(x) => LuaMessage::Number(*x),
+            ValueKind::String(x) => LuaMessage::String(x.clone()),
+            ValueKind::Table(x) => LuaMessage::Table(
+                x.iter()
+                    .map(|(k, v)| (k.clone(), LuaMessage::from_config_value(v)))
+                    .collect(),
+            ),
+            ValueKind::Array(x) => {
+                LuaMessage::Array(x.iter().map(LuaMessage::from_config_value).collect())
+            }
+        }
+    }
+
+    /// Convert the message into a `config::Value` without an origin.
+    ///
+    /// `Bytes` must be valid UTF-8. Errors, userdata, functions and thread yields can't be converted.
+    pub fn to_config_value(&self) -> Result<Value, LuaActorError> {
+        let kind = match self {
+            LuaMessage::Nil => ValueKind::Nil,
+            LuaMessage::Boolean(x) => ValueKind::Boolean(*x),
+            LuaMessage::Integer(x) => ValueKind::I64(*x),
+            LuaMessage::Number(x) => ValueKind::Float(*x),
+            LuaMessage::String(x) => ValueKind::String(x.clone()),
+            LuaMessage::Bytes(x) => match String::from_utf8(x.clone()) {
+                Ok(s) => ValueKind::String(s),
+                Err(_) => {
+                    return Err(LuaActorError::Conversion(
+                        "config strings must be valid UTF-8".to_string(),
+                    ))
+                }
+            },
+            LuaMessage::Table(x) => ValueKind::Table(
+                x.iter()
+                    .map(|(k, v)| Ok((k.clone(), v.to_config_value()?)))
+                    .collect::<Result<Map<_, _>, LuaActorError>>()?,
+            ),
+            LuaMessage::Array(x) => ValueKind::Array(
+                x.iter()
+                    .map(LuaMessage::to_config_value)
+                    .collect::<Result<_, _>>()?,
+            ),
+            v => {
+                return Err(LuaActorError::Conversion(format!(
+                    "can't convert {} to a config value",
+                    v
+                )))
+            }
+        };
+        Ok(Value::new(None, kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+
+    #[test]
+    fn config_round_trip() {
+        let config = Config::builder()
+            .set_override("name", "app")
+            .unwrap()
+            .set_override("db.host", "localhost")
+            .unwrap()
+            .set_override("db.port", 5432)
+            .unwrap()
+            .set_override("db.ratio", 0.5)
+            .unwrap()
+            .set_override("db.replicas", vec!["a", "b"])
+            .unwrap()
+            .set_override("debug", true)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let msg = LuaMessage::from_config_value(&config.cache);
+        assert_eq!(msg.path_get("db.port"), Some(&LuaMessage::from(5432)));
+        assert_eq!(msg.path_get("db.replicas.1"), Some(&LuaMessage::from("b")));
+        assert_eq!(msg.path_get("debug"), Some(&LuaMessage::from(true)));
+
+        let value = msg.to_config_value().unwrap();
+        assert_eq!(LuaMessage::from_config_value(&value), msg);
+    }
+
+    #[test]
+    fn config_errors() {
+        assert!(LuaMessage::error("x").to_config_value().is_err());
+        assert!(LuaMessage::Bytes(vec![0xff]).to_config_value().is_err());
+        assert_eq!(
+            LuaMessage::from_config_value(&Value::new(None, ValueKind::U64(u64::MAX))),
+            LuaMessage::Number(u64::MAX as f64)
+        );
+    }
+}
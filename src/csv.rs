@@ -0,0 +1,101 @@
+//! CSV export of `LuaMessage` rows, enabled with the `csv` feature.
+use crate::error::LuaActorError;
+use crate::message::{conversion_error, LuaMessage};
+
+impl LuaMessage {
+    /// Export an `Array` of flat tables as CSV, with a header row of their keys, sorted.
+    ///
+    /// Every row must have the keys of the first one. Values are scalars, stringified with `scalar_to_string`.
+    /// An empty array, which Lua can't tell apart from an empty table, gives empty output.
+    pub fn to_csv(&self) -> Result<Vec<u8>, LuaActorError> {
+        let rows = match self {
+            LuaMessage::Array(x) => x.as_slice(),
+            LuaMessage::Table(x) if x.is_empty() => &[],
+            _ => return Err(conversion_error("an array", self)),
+        };
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        let mut header: Option<Vec<&String>> = None;
+        for (i, row) in rows.iter().enumerate() {
+            let row = match row {
+                LuaMessage::Table(x) => x,
+                v => {
+                    return Err(LuaActorError::Conversion(format!(
+                        "row {} is not a table: {}",
+                        i, v
+                    )))
+                }
+            };
+            let keys = match &header {
+                Some(keys) => {
+                    if row.len() != keys.len() || keys.iter().any(|k| !row.contains_key(*k)) {
+                        return Err(LuaActorError::Conversion(format!(
+                            "row {} doesn't have the keys of the first row",
+                            i
+                        )));
+                    }
+                    keys
+                }
+                None => {
+                    let mut keys: Vec<_> = row.keys().collect();
+                    keys.sort();
+                    writer.write_record(&keys).map_err(csv_error)?;
+                    header.get_or_insert(keys)
+                }
+            };
+            let record = keys
+                .iter()
+                .map(|k| {
+                    row[*k].scalar_to_string().ok_or_else(|| {
+                        LuaActorError::Conversion(format!(
+                            "value of {} in row {} is not a scalar",
+                            k, i
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            writer.write_record(&record).map_err(csv_error)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| LuaActorError::Conversion(e.to_string()))
+    }
+}
+
+fn csv_error(e: csv::Error) -> LuaActorError {
+    LuaActorError::Conversion(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_csv() {
+        let rows = lua_array![
+            lua_table! { "name" => "foo", "score" => 1.5, "id" => 1 },
+            lua_table! { "name" => "bar, baz", "score" => 2, "id" => 2 },
+            lua_table! { "name" => "qux", "score" => 0, "id" => 3 }
+        ];
+        let csv = String::from_utf8(rows.to_csv().unwrap()).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "id,name,score");
+        assert_eq!(lines[1], "1,foo,1.5");
+        assert_eq!(lines[2], r#"2,"bar, baz",2"#);
+
+        assert!(lua_table! {}.to_csv().unwrap().is_empty());
+        assert!(lua_array![lua_table! { "a" => 1 }, lua_table! { "b" => 1 }]
+            .to_csv()
+            .is_err());
+        assert!(
+            lua_array![lua_table! { "a" => 1 }, lua_table! { "a" => 1, "b" => 1 }]
+                .to_csv()
+                .is_err()
+        );
+        assert!(lua_array![lua_table! { "a" => lua_array![] }]
+            .to_csv()
+            .is_err());
+        assert!(LuaMessage::from("a").to_csv().is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! `rust_decimal::Decimal` conversions for `LuaMessage`, enabled with the `decimal` feature.
+//!
+//! Lua numbers are 64-bit integers or floats and can't hold arbitrary-precision decimals, so a `Decimal`
+//! is carried as a `String`, e.g. `"10.25"`. Scripts should pass such values through, or hand them back
+//! to the host for arithmetic, rather than doing arithmetic on them in Lua.
+use rust_decimal::Decimal;
+
+use crate::error::LuaActorError;
+use crate::message::{conversion_error, LuaMessage};
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+impl From<Decimal> for LuaMessage {
+    fn from(d: Decimal) -> Self {
+        LuaMessage::String(d.to_string())
+    }
+}
+
+/// `String`s and `Integer`s convert exactly. A `Number` converts from its shortest decimal form,
+/// e.g. `0.1` becomes exactly `0.1`, which may differ from the binary value of the float.
+impl TryFrom<LuaMessage> for Decimal {
+    type Error = LuaActorError;
+
+    fn try_from(msg: LuaMessage) -> Result<Decimal, LuaActorError> {
+        let parsed = match &msg {
+            LuaMessage::String(x) => Decimal::from_str(x),
+            LuaMessage::Integer(x) => return Ok(Decimal::from(*x)),
+            LuaMessage::Number(x) if x.is_finite() => Decimal::from_str(&x.to_string()),
+            _ => return Err(conversion_error("a decimal", &msg)),
+        };
+        parsed.map_err(|e| LuaActorError::Conversion(format!("invalid decimal {}: {}", msg, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decimal_round_trip() {
+        let d = Decimal::from_str("12345678901234567890.123456789").unwrap();
+        let msg = LuaMessage::from(d);
+        assert_eq!(msg, LuaMessage::from("12345678901234567890.123456789"));
+        assert_eq!(Decimal::try_from(msg), Ok(d));
+
+        assert_eq!(
+            Decimal::try_from(LuaMessage::from(0.1)),
+            Ok(Decimal::from_str("0.1").unwrap())
+        );
+        assert_eq!(
+            Decimal::try_from(LuaMessage::from(-3)),
+            Ok(Decimal::from(-3))
+        );
+        assert!(Decimal::try_from(LuaMessage::from("1.2.3")).is_err());
+        assert!(Decimal::try_from(LuaMessage::Nil).is_err());
+    }
+}
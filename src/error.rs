@@ -0,0 +1,38 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Errors returned by `actix-lua` APIs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LuaActorError {
+    /// A `LuaMessage` doesn't have the shape required by a conversion.
+    Conversion(String),
+    /// An incoming message was rejected by a precondition before the script ran.
+    Precondition(String),
+    /// `ctx.send` was called while the shared `CoroutineLimit` was reached.
+    CoroutineLimit(usize),
+    /// A message is larger than the actor's limit, by `LuaMessage::approx_size`.
+    MessageTooLarge { size: usize, max: usize },
+    /// A message couldn't be delivered to another actor, e.g. because it stopped.
+    Mailbox(String),
+    /// The `on_error` hook raised an error while handling an error of `handle`.
+    ErrorHook(String),
+}
+
+impl fmt::Display for LuaActorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaActorError::Conversion(msg) => write!(f, "conversion error: {}", msg),
+            LuaActorError::Precondition(msg) => write!(f, "precondition failed: {}", msg),
+            LuaActorError::CoroutineLimit(max) => {
+                write!(f, "too many suspended coroutines (limit {})", max)
+            }
+            LuaActorError::MessageTooLarge { size, max } => {
+                write!(f, "message too large: {} bytes (limit {})", size, max)
+            }
+            LuaActorError::Mailbox(msg) => write!(f, "mailbox error: {}", msg),
+            LuaActorError::ErrorHook(msg) => write!(f, "error hook failed: {}", msg),
+        }
+    }
+}
+
+impl StdError for LuaActorError {}
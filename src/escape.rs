@@ -0,0 +1,102 @@
+//! Escape policies for the strings returned by scripts, see `LuaActorBuilder::with_escape`.
+use crate::message::LuaMessage;
+
+/// A policy for escaping strings returned from scripts, e.g. before embedding them in HTML or SQL.
+///
+/// Any `Fn(&str) -> String` can be used as a policy.
+pub trait EscapePolicy {
+    fn escape(&self, s: &str) -> String;
+}
+
+impl<F: Fn(&str) -> String> EscapePolicy for F {
+    fn escape(&self, s: &str) -> String {
+        self(s)
+    }
+}
+
+/// Encode `&`, `<`, `>`, `"` and `'` as HTML entities.
+pub struct HtmlEscape;
+
+impl EscapePolicy for HtmlEscape {
+    fn escape(&self, s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&#x27;"),
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+}
+
+/// Quote strings as SQL string literals, doubling single quotes.
+pub struct SqlEscape;
+
+impl EscapePolicy for SqlEscape {
+    fn escape(&self, s: &str) -> String {
+        format!("'{}'", s.replace('\'', "''"))
+    }
+}
+
+impl LuaMessage {
+    /// Escape all `String` values with `policy`, recursively. Table keys are left unchanged.
+    pub fn escape(self, policy: &dyn EscapePolicy) -> LuaMessage {
+        match self {
+            LuaMessage::String(x) => LuaMessage::String(policy.escape(&x)),
+            LuaMessage::Table(x) => {
+                LuaMessage::Table(x.into_iter().map(|(k, v)| (k, v.escape(policy))).collect())
+            }
+            LuaMessage::Array(x) => {
+                LuaMessage::Array(x.into_iter().map(|v| v.escape(policy)).collect())
+            }
+            x => x,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn escape_html() {
+        let mut t = HashMap::new();
+        t.insert(
+            "body".to_string(),
+            LuaMessage::Array(vec![LuaMessage::from("<script>alert('x')</script>")]),
+        );
+        t.insert("count".to_string(), LuaMessage::from(1));
+
+        let mut expected = HashMap::new();
+        expected.insert(
+            "body".to_string(),
+            LuaMessage::Array(vec![LuaMessage::from(
+                "&lt;script&gt;alert(&#x27;x&#x27;)&lt;/script&gt;",
+            )]),
+        );
+        expected.insert("count".to_string(), LuaMessage::from(1));
+
+        assert_eq!(
+            LuaMessage::Table(t).escape(&HtmlEscape),
+            LuaMessage::Table(expected)
+        );
+    }
+
+    #[test]
+    fn escape_sql_and_custom() {
+        assert_eq!(
+            LuaMessage::from("O'Brien").escape(&SqlEscape),
+            LuaMessage::from("'O''Brien'")
+        );
+        assert_eq!(
+            LuaMessage::from("foo").escape(&|s: &str| s.to_uppercase()),
+            LuaMessage::from("FOO")
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! `http::HeaderMap` conversions for `LuaMessage`, enabled with the `http` feature.
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::error::LuaActorError;
+use crate::message::LuaMessage;
+
+use std::collections::HashMap;
+
+impl LuaMessage {
+    /// Convert a string-keyed `Table` into a `HeaderMap`.
+    ///
+    /// Values are strings, bytes or other scalars stringified with `scalar_to_string`.
+    /// An `Array` value adds one header value per element. Invalid header names or values are an error.
+    pub fn to_header_map(&self) -> Result<HeaderMap, LuaActorError> {
+        let table = match self {
+            LuaMessage::Table(x) => x,
+            _ => return Err(LuaActorError::Conversion("expect a table".to_string())),
+        };
+
+        let mut headers = HeaderMap::with_capacity(table.len());
+        for (k, v) in table {
+            let name = HeaderName::from_bytes(k.as_bytes())
+                .map_err(|_| LuaActorError::Conversion(format!("invalid header name {:?}", k)))?;
+            match v {
+                LuaMessage::Array(values) => {
+                    for v in values {
+                        headers.append(name.clone(), header_value(k, v)?);
+                    }
+                }
+                v => {
+                    headers.insert(name, header_value(k, v)?);
+                }
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Convert a `HeaderMap` into a `Table` keyed by lowercase header names.
+    ///
+    /// A header with several values becomes an `Array`. Values that aren't valid UTF-8 become `Bytes`.
+    pub fn from_header_map(headers: &HeaderMap) -> LuaMessage {
+        let mut table = HashMap::with_capacity(headers.keys_len());
+        for name in headers.keys() {
+            let mut values: Vec<LuaMessage> = headers
+                .get_all(name)
+                .iter()
+                .map(|v| match v.to_str() {
+                    Ok(s) => LuaMessage::from(s),
+                    Err(_) => LuaMessage::Bytes(v.as_bytes().to_vec()),
+                })
+                .collect();
+            let value = if values.len() == 1 {
+                values.remove(0)
+            } else {
+                LuaMessage::Array(values)
+            };
+            table.insert(name.as_str().to_string(), value);
+        }
+        LuaMessage::Table(table)
+    }
+}
+
+fn header_value(name: &str, v: &LuaMessage) -> Result<HeaderValue, LuaActorError> {
+    let invalid =
+        || LuaActorError::Conversion(format!("invalid value for header {:?}: {}", name, v));
+    match v {
+        LuaMessage::Bytes(x) => HeaderValue::from_bytes(x).map_err(|_| invalid()),
+        v => match v.scalar_to_string() {
+            Some(s) => HeaderValue::from_str(&s).map_err(|_| invalid()),
+            None => Err(invalid()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_map_round_trip() {
+        let mut t = HashMap::new();
+        t.insert("content-type".to_string(), LuaMessage::from("text/html"));
+        t.insert("content-length".to_string(), LuaMessage::from("42"));
+        t.insert(
+            "set-cookie".to_string(),
+            LuaMessage::Array(vec![LuaMessage::from("a=1"), LuaMessage::from("b=2")]),
+        );
+        let msg = LuaMessage::Table(t);
+
+        let headers = msg.to_header_map().unwrap();
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers["content-type"], "text/html");
+        assert_eq!(LuaMessage::from_header_map(&headers), msg);
+    }
+
+    #[test]
+    fn header_map_errors() {
+        let header = |k: &str, v: LuaMessage| {
+            let mut t = HashMap::new();
+            t.insert(k.to_string(), v);
+            LuaMessage::Table(t)
+        };
+
+        assert!(header("bad name", LuaMessage::from("x"))
+            .to_header_map()
+            .is_err());
+        assert!(header("x-bad", LuaMessage::from("a\nb"))
+            .to_header_map()
+            .is_err());
+        assert!(header("x-nested", header("a", LuaMessage::from("b")))
+            .to_header_map()
+            .is_err());
+        assert!(LuaMessage::from("x").to_header_map().is_err());
+
+        // names are case-insensitive and normalized to lowercase
+        let headers = header("X-Id", LuaMessage::from(1)).to_header_map().unwrap();
+        assert_eq!(
+            LuaMessage::from_header_map(&headers),
+            header("x-id", LuaMessage::from("1"))
+        );
+    }
+}
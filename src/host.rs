@@ -0,0 +1,207 @@
+use crate::error::LuaActorError;
+use crate::message::LuaMessage;
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Shared host state, such as a connection pool, for host functions. See `LuaActor::add_data`.
+///
+/// Clones share the same value, so one `Data` can be handed to several actors.
+#[derive(Debug)]
+pub struct Data<T>(Arc<T>);
+
+impl<T> Data<T> {
+    pub fn new(v: T) -> Self {
+        Data(Arc::new(v))
+    }
+}
+
+impl<T> Clone for Data<T> {
+    fn clone(&self) -> Self {
+        Data(self.0.clone())
+    }
+}
+
+impl<T> Deref for Data<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> From<Arc<T>> for Data<T> {
+    fn from(v: Arc<T>) -> Self {
+        Data(v)
+    }
+}
+
+/// Receives the application metrics emitted by scripts, see `LuaActorBuilder::with_metrics_sink`.
+///
+/// Scripts call `metrics.increment(name)` and `metrics.timing(name, ms)`.
+pub trait MetricsSink: Send + Sync {
+    /// Increment the counter `name` by one.
+    fn increment(&self, name: &str);
+
+    /// Record a duration of `ms` milliseconds for `name`.
+    fn timing(&self, name: &str, ms: f64);
+}
+
+/// A reproducible report of a `handle` error, see `LuaActorBuilder::with_crash_reporter`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashReport {
+    /// The source of the active `handle` script.
+    pub source: String,
+    /// A 64-bit FNV-1a hash of `source`, to group reports by script version.
+    ///
+    /// The hash is stable across processes and Rust releases, so reports can be grouped over time.
+    pub source_hash: u64,
+    /// The message `handle` failed on, with the sensitive keys redacted.
+    pub input: LuaMessage,
+    /// The error raised by `handle`, including the script position it was raised at.
+    pub error: String,
+}
+
+// 64-bit FNV-1a, unlike `DefaultHasher` its output is specified and never changes.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// An in-memory set of Lua modules by name, to bundle a multi-file script with the host.
+/// See `LuaActorBuilder::with_virtual_fs`.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualFs {
+    modules: HashMap<String, String>,
+}
+
+impl VirtualFs {
+    pub fn new() -> Self {
+        VirtualFs::default()
+    }
+
+    /// Add the module `name` with its source, replacing a previous module of the same name.
+    pub fn add(&mut self, name: &str, source: &str) -> &mut Self {
+        self.modules.insert(name.to_string(), source.to_string());
+        self
+    }
+
+    /// The source of the module `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.modules.get(name).map(String::as_str)
+    }
+}
+
+/// The expected kind of a named parameter of a host function, see `LuaActor::add_fn_with_params`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamKind {
+    String,
+    Integer,
+    /// An `Integer` or a `Number`.
+    Number,
+    Boolean,
+    Table,
+    Array,
+    /// Any value.
+    Any,
+}
+
+impl ParamKind {
+    fn accepts(self, msg: &LuaMessage) -> bool {
+        matches!(
+            (self, msg),
+            (ParamKind::String, LuaMessage::String(_))
+                | (ParamKind::Integer, LuaMessage::Integer(_))
+                | (ParamKind::Number, LuaMessage::Integer(_))
+                | (ParamKind::Number, LuaMessage::Number(_))
+                | (ParamKind::Boolean, LuaMessage::Boolean(_))
+                | (ParamKind::Table, LuaMessage::Table(_))
+                | (ParamKind::Array, LuaMessage::Array(_))
+                | (ParamKind::Any, _)
+        )
+    }
+}
+
+/// Check that `msg` is a table holding every parameter with its kind, and return the declared fields.
+pub(crate) fn named_args(
+    params: &[(String, ParamKind)],
+    msg: LuaMessage,
+) -> Result<HashMap<String, LuaMessage>, LuaActorError> {
+    let mut table = match msg {
+        LuaMessage::Table(x) => x,
+        _ => {
+            return Err(LuaActorError::Conversion(
+                "expect a table of named parameters".to_string(),
+            ))
+        }
+    };
+
+    let mut args = HashMap::with_capacity(params.len());
+    for (name, kind) in params {
+        match table.remove(name) {
+            None | Some(LuaMessage::Nil) => {
+                return Err(LuaActorError::Conversion(format!(
+                    "missing parameter `{}`",
+                    name
+                )))
+            }
+            Some(v) if kind.accepts(&v) => {
+                args.insert(name.clone(), v);
+            }
+            Some(v) => {
+                return Err(LuaActorError::Conversion(format!(
+                    "parameter `{}` should be {:?}, got {}",
+                    name, kind, v
+                )))
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn named_args_validation() {
+        let params = vec![
+            ("host".to_string(), ParamKind::String),
+            ("port".to_string(), ParamKind::Integer),
+        ];
+        let msg = |port: LuaMessage| {
+            let mut t = HashMap::new();
+            t.insert("host".to_string(), LuaMessage::from("x"));
+            t.insert("port".to_string(), port);
+            t.insert("extra".to_string(), LuaMessage::from(true));
+            LuaMessage::Table(t)
+        };
+
+        let args = named_args(&params, msg(LuaMessage::from(5432))).unwrap();
+        assert_eq!(args.len(), 2);
+        assert_eq!(args["port"], LuaMessage::from(5432));
+
+        assert_eq!(
+            named_args(&params, msg(LuaMessage::from("5432"))),
+            Err(LuaActorError::Conversion(
+                "parameter `port` should be Integer, got \"5432\"".to_string()
+            ))
+        );
+        assert_eq!(
+            named_args(&params, msg(LuaMessage::Nil)),
+            Err(LuaActorError::Conversion(
+                "missing parameter `port`".to_string()
+            ))
+        );
+        assert!(named_args(&params, LuaMessage::from(1)).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! JSON conversions for `LuaMessage`, enabled with the `json` feature.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::message::LuaMessage;
+
+// length prefix of a frame
+const FRAME_HEADER: usize = 4;
+
+impl LuaMessage {
+    /// Serialize the message into JSON bytes.
+    pub fn to_json_vec(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    /// Serialize the message into canonical JSON, identical for equal messages, e.g. to hash or sign results.
+    ///
+    /// Object keys are sorted at every depth and there is no whitespace. Numbers use the shortest form
+    /// that reads back to the same value, with `-0.0` written as `0.0`; integers stay distinct from floats.
+    pub fn to_canonical_json(&self) -> Result<String, serde_json::Error> {
+        let mut v = serde_json::to_value(self)?;
+        canonicalize(&mut v);
+        serde_json::to_string(&v)
+    }
+
+    /// Deserialize a message from JSON bytes.
+    pub fn from_json_slice(v: &[u8]) -> Result<LuaMessage, serde_json::Error> {
+        serde_json::from_slice(v)
+    }
+
+    /// Serialize the message into a frame for stream transports such as raw TCP: the JSON bytes
+    /// prefixed with their length as a big-endian `u32`.
+    pub fn to_framed(&self) -> Result<Vec<u8>, serde_json::Error> {
+        let body = self.to_json_vec()?;
+        let len = u32::try_from(body.len()).map_err(|_| {
+            serde_json::Error::io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message too large for a frame",
+            ))
+        })?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER + body.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+
+    /// Read one frame written by `to_framed`, advancing `buf` past it.
+    ///
+    /// `buf` is left untouched if it doesn't hold a whole frame yet.
+    pub fn from_framed(buf: &mut &[u8]) -> Result<LuaMessage, serde_json::Error> {
+        let incomplete = || {
+            serde_json::Error::io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incomplete frame",
+            ))
+        };
+        if buf.len() < FRAME_HEADER {
+            return Err(incomplete());
+        }
+        let (header, rest) = buf.split_at(FRAME_HEADER);
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if rest.len() < len {
+            return Err(incomplete());
+        }
+        let (body, rest) = rest.split_at(len);
+        let msg = LuaMessage::from_json_slice(body)?;
+        *buf = rest;
+        Ok(msg)
+    }
+
+    /// Convert any serializable Rust value, going through its JSON representation.
+    ///
+    /// Enums tagged with `#[serde(tag = "type", content = "data")]` become `{ type = "Variant", data = {...} }`
+    /// tables, the usual tagged-union convention in Lua.
+    pub fn from_serialize<T: Serialize>(v: &T) -> Result<LuaMessage, serde_json::Error> {
+        serde_json::from_value(serde_json::to_value(v)?)
+    }
+
+    /// Convert the message into a deserializable Rust value, the reverse of `from_serialize`.
+    pub fn to_deserialize<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(serde_json::to_value(self)?)
+    }
+}
+
+// Object keys are already sorted, as `serde_json::Map` is ordered; only negative zeros need rewriting.
+fn canonicalize(v: &mut serde_json::Value) {
+    match v {
+        serde_json::Value::Number(n) if n.as_f64() == Some(0.0) && n.is_f64() => {
+            *v = serde_json::json!(0.0);
+        }
+        serde_json::Value::Array(x) => x.iter_mut().for_each(canonicalize),
+        serde_json::Value::Object(x) => {
+            // sorted explicitly, as `serde_json::Map` keeps the insertion order with `preserve_order`
+            let sorted: BTreeMap<String, serde_json::Value> = std::mem::take(x)
+                .into_iter()
+                .map(|(k, mut v)| {
+                    canonicalize(&mut v);
+                    (k, v)
+                })
+                .collect();
+            *x = sorted.into_iter().collect();
+        }
+        _ => {}
+    }
+}
+
+/// Numbers that are integers in JSON and fit in an `i64` become `Integer`, other numbers become `Number`.
+impl From<serde_json::Value> for LuaMessage {
+    fn from(v: serde_json::Value) -> LuaMessage {
+        // deserializing a `LuaMessage` accepts any JSON value
+        LuaMessage::deserialize(v).expect("any JSON value converts to a LuaMessage")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::SEQUENCE_ARRAYS;
+    use std::collections::HashMap;
+
+    #[test]
+    fn json_bytes_round_trip() {
+        let mut inner = HashMap::new();
+        inner.insert("name".to_string(), LuaMessage::from("foo"));
+        inner.insert("score".to_string(), LuaMessage::from(1.5));
+        inner.insert("ok".to_string(), LuaMessage::from(true));
+        let mut t = HashMap::new();
+        t.insert(
+            "items".to_string(),
+            LuaMessage::Array(vec![LuaMessage::Table(inner), LuaMessage::Nil]),
+        );
+        t.insert("count".to_string(), LuaMessage::from(1));
+        let msg = LuaMessage::Table(t);
+
+        let bytes = msg.to_json_vec().unwrap();
+        assert_eq!(LuaMessage::from_json_slice(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn json_boolean_round_trip() {
+        for &b in &[true, false] {
+            let bytes = LuaMessage::from(b).to_json_vec().unwrap();
+            assert_eq!(bytes, b.to_string().into_bytes());
+            assert_eq!(
+                LuaMessage::from_json_slice(&bytes).unwrap(),
+                LuaMessage::Boolean(b)
+            );
+        }
+        // numbers stay numbers
+        assert_eq!(
+            LuaMessage::from_json_slice(b"[1, true, 0, false]").unwrap(),
+            LuaMessage::Array(vec![
+                LuaMessage::Integer(1),
+                LuaMessage::Boolean(true),
+                LuaMessage::Integer(0),
+                LuaMessage::Boolean(false),
+            ])
+        );
+    }
+
+    #[test]
+    fn from_json_value_keeps_integers() {
+        let v: serde_json::Value = serde_json::from_str(r#"{"a":1,"b":1.5,"c":2.0}"#).unwrap();
+        let msg = LuaMessage::from(v);
+        assert_eq!(msg.path_get("a"), Some(&LuaMessage::Integer(1)));
+        assert_eq!(msg.path_get("b"), Some(&LuaMessage::Number(1.5)));
+        assert_eq!(msg.path_get("c"), Some(&LuaMessage::Number(2.0)));
+
+        assert_eq!(
+            LuaMessage::from(serde_json::json!(u64::MAX)),
+            LuaMessage::Number(u64::MAX as f64)
+        );
+        assert_eq!(
+            LuaMessage::from(serde_json::json!(-3)),
+            LuaMessage::Integer(-3)
+        );
+    }
+
+    #[test]
+    fn json_lua_round_trip_is_stable() {
+        use rlua::{FromLua, Lua, ToLua, Value};
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            ctx.set_named_registry_value(SEQUENCE_ARRAYS, true).unwrap();
+            for src in &["return {'a', 'b', 'c'}", "return {[1] = 'a', [3] = 'c'}"] {
+                let v: Value = ctx.load(src).eval().unwrap();
+                let msg = LuaMessage::from_lua(v, ctx).unwrap();
+                let first = msg.to_json_vec().unwrap();
+
+                let mut json = first.clone();
+                for _ in 0..2 {
+                    let decoded = LuaMessage::from_json_slice(&json).unwrap();
+                    let v = decoded.to_lua(ctx).unwrap();
+                    json = LuaMessage::from_lua(v, ctx).unwrap().to_json_vec().unwrap();
+                    assert_eq!(json, first);
+                }
+            }
+
+            let v: Value = ctx.load("return {'a', 'b'}").eval().unwrap();
+            let json = LuaMessage::from_lua(v, ctx).unwrap().to_json_vec().unwrap();
+            assert_eq!(json, br#"["a","b"]"#.to_vec());
+            let v: Value = ctx.load("return {[2] = 'b'}").eval().unwrap();
+            let json = LuaMessage::from_lua(v, ctx).unwrap().to_json_vec().unwrap();
+            assert_eq!(json, br#"{"2":"b"}"#.to_vec());
+        });
+    }
+
+    #[test]
+    fn tagged_enum_round_trip() {
+        use rlua::{Function, Lua};
+        use serde::Deserialize;
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(tag = "type", content = "data")]
+        enum Shape {
+            Circle { r: f64 },
+            Rect { w: i64, h: i64 },
+        }
+
+        let circle = LuaMessage::from_serialize(&Shape::Circle { r: 1.5 }).unwrap();
+        assert_eq!(circle.tag_in("type"), Some("Circle"));
+        assert_eq!(circle.path_get("data.r"), Some(&LuaMessage::from(1.5)));
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            // a script builds the other variant
+            let f: Function = ctx
+                .load(
+                    r#"
+                return function(shape)
+                    assert(shape.type == "Circle")
+                    return { type = "Rect", data = { w = 2, h = math.floor(shape.data.r * 2) } }
+                end
+                "#,
+                )
+                .eval()
+                .unwrap();
+            let rect: LuaMessage = f.call(circle.clone()).unwrap();
+            assert_eq!(
+                rect.to_deserialize::<Shape>().unwrap(),
+                Shape::Rect { w: 2, h: 3 }
+            );
+        });
+        assert_eq!(
+            circle.to_deserialize::<Shape>().unwrap(),
+            Shape::Circle { r: 1.5 }
+        );
+    }
+
+    #[test]
+    fn canonical_json() {
+        let mut a = HashMap::new();
+        a.insert("b".to_string(), LuaMessage::from(1));
+        a.insert("a".to_string(), lua_table! { "y" => -0.0, "x" => 1.5 });
+        let mut b = HashMap::new();
+        b.insert("a".to_string(), lua_table! { "x" => 1.5, "y" => 0.0 });
+        b.insert("b".to_string(), LuaMessage::from(1));
+        let (a, b) = (LuaMessage::Table(a), LuaMessage::Table(b));
+        assert_eq!(a, b);
+
+        let json = a.to_canonical_json().unwrap();
+        assert_eq!(json, b.to_canonical_json().unwrap());
+        assert_eq!(json, r#"{"a":{"x":1.5,"y":0.0},"b":1}"#);
+
+        // keys inserted in reverse order
+        let mut reversed = serde_json::Map::new();
+        for k in ["c", "b", "a"].iter() {
+            reversed.insert(k.to_string(), serde_json::json!({ "z": 1, "y": 2 }));
+        }
+        let mut reversed = serde_json::Value::Object(reversed);
+        canonicalize(&mut reversed);
+        assert_eq!(
+            serde_json::to_string(&reversed).unwrap(),
+            r#"{"a":{"y":2,"z":1},"b":{"y":2,"z":1},"c":{"y":2,"z":1}}"#
+        );
+        assert!(LuaMessage::ThreadYield("1".to_string())
+            .to_canonical_json()
+            .is_err());
+    }
+
+    #[test]
+    fn json_bytes_errors() {
+        assert!(LuaMessage::ThreadYield("1".to_string())
+            .to_json_vec()
+            .is_err());
+        assert!(LuaMessage::from_json_slice(b"{").is_err());
+    }
+
+    #[test]
+    fn framed_round_trip() {
+        let a = lua_table! { "name" => "foo", "items" => lua_array![1, 2.5, true] };
+        let b = LuaMessage::from("bar");
+        let mut stream = a.to_framed().unwrap();
+        stream.extend(b.to_framed().unwrap());
+        assert_eq!(
+            &stream[..4],
+            &[0, 0, 0, a.to_json_vec().unwrap().len() as u8]
+        );
+
+        let mut buf = &stream[..];
+        assert_eq!(LuaMessage::from_framed(&mut buf).unwrap(), a);
+        assert_eq!(LuaMessage::from_framed(&mut buf).unwrap(), b);
+        assert!(buf.is_empty());
+
+        // a cut short frame is left in the buffer
+        let mut buf = &stream[..stream.len() - 1];
+        LuaMessage::from_framed(&mut buf).unwrap();
+        let rest = buf.len();
+        assert!(LuaMessage::from_framed(&mut buf).is_err());
+        assert_eq!(buf.len(), rest);
+    }
+
+    #[test]
+    fn json_error_round_trip() {
+        let err = LuaMessage::error("not found");
+        let bytes = err.to_json_vec().unwrap();
+        assert_eq!(bytes, br#"{"__lua_error":"not found"}"#.to_vec());
+        assert_eq!(LuaMessage::from_json_slice(&bytes).unwrap(), err);
+
+        let nested = LuaMessage::Array(vec![LuaMessage::from(1), err]);
+        let bytes = nested.to_json_vec().unwrap();
+        assert_eq!(LuaMessage::from_json_slice(&bytes).unwrap(), nested);
+
+        let coded = LuaMessage::error_with_code(404, "not found");
+        let bytes = coded.to_json_vec().unwrap();
+        assert_eq!(
+            bytes,
+            br#"{"__lua_error":{"code":404,"message":"not found"}}"#.to_vec()
+        );
+        assert_eq!(LuaMessage::from_json_slice(&bytes).unwrap(), coded);
+
+        // only a single-key table with a string message is an error
+        let table = LuaMessage::from_json_slice(br#"{"__lua_error":"x","code":1}"#).unwrap();
+        assert!(matches!(table, LuaMessage::Table(_)));
+    }
+}
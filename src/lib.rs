@@ -26,6 +26,7 @@
 //!
 //! [`LuaActor`] can only send/receive messages with type [`LuaMessage`].
 //! It can be converted from/to primitive types such as `i64`, `String`, and `HashMap` with `LuaMessage::from`.
+//! Tables and arrays can be built with the `lua_table!` and `lua_array!` macros.
 //!
 //! [actix]: https://github.com/actix/actix
 //! [Lua programming language]: https://www.lua.org
@@ -35,13 +36,56 @@
 #[cfg(test)]
 extern crate futures_timer;
 
+#[macro_use]
+mod macros;
+
 mod actor;
 mod builder;
+mod cache;
+#[cfg(feature = "config")]
+mod config;
+#[cfg(feature = "csv")]
+mod csv;
+#[cfg(feature = "decimal")]
+mod decimal;
+mod error;
+mod escape;
+#[cfg(feature = "http")]
+mod headers;
+mod host;
+#[cfg(feature = "intern")]
+mod intern;
+#[cfg(feature = "json")]
+mod json;
+mod limit;
 mod message;
+#[cfg(feature = "parse")]
+mod parse;
+#[cfg(feature = "ron-format")]
+mod ron;
+#[cfg(feature = "serde")]
+mod serialize;
+mod typed;
+#[cfg(feature = "value")]
+mod value;
+#[cfg(feature = "actix-web")]
+mod web;
 
-pub use crate::actor::LuaActor;
+pub use crate::actor::{
+    ActivateHandler, AsyncResponse, Batch, BatchMode, CallFunction, CapturedOutput, Cas, Eval,
+    EvalWith, Generate, GeneratorSink, HandleAsync, HandleStreaming, HostFunctions, HostFuture,
+    InvokeMulti, LuaActor, Pause, ReadOnly, RegisterHandler, ReleaseFunction, Resume,
+    RollbackHandler, Source, TakeOutput, UnusedGlobals, WithRequestId, OUTPUT_TRUNCATED,
+};
 pub use crate::builder::LuaActorBuilder;
-pub use crate::message::LuaMessage;
+pub use crate::error::LuaActorError;
+pub use crate::escape::{EscapePolicy, HtmlEscape, SqlEscape};
+pub use crate::host::{CrashReport, Data, MetricsSink, ParamKind, VirtualFs};
+#[cfg(feature = "intern")]
+pub use crate::intern::{InternedMessage, KeyPool};
+pub use crate::limit::CoroutineLimit;
+pub use crate::message::{FunctionHandle, HostError, LuaMessage, RoundMode, ERROR_KEY, TAG_FIELD};
+pub use crate::typed::TypedLuaActor;
 
 /// Re-export `rlua` interface for library developers
 pub mod dev {
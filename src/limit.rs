@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A cap on the coroutines suspended by `ctx.send`, shared by every actor built with it.
+///
+/// Clones share the same count, so one limit can bound a whole pool of actors.
+/// Once the cap is reached, `ctx.send` fails fast with `LuaActorError::CoroutineLimit`
+/// instead of suspending another coroutine.
+#[derive(Debug, Clone)]
+pub struct CoroutineLimit {
+    max: usize,
+    suspended: Arc<AtomicUsize>,
+}
+
+impl CoroutineLimit {
+    /// Allow at most `max` suspended coroutines.
+    pub fn new(max: usize) -> Self {
+        CoroutineLimit {
+            max,
+            suspended: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The maximum number of suspended coroutines.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Number of coroutines currently suspended.
+    pub fn suspended(&self) -> usize {
+        self.suspended.load(Ordering::SeqCst)
+    }
+
+    /// Take a slot for a coroutine about to be suspended. Returns `false` if the cap is reached.
+    pub(crate) fn try_acquire(&self) -> bool {
+        self.suspended
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < self.max {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// Give back the slot of a resumed coroutine.
+    pub(crate) fn release(&self) {
+        let _ = self
+            .suspended
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acquire_and_release() {
+        let limit = CoroutineLimit::new(2);
+        let shared = limit.clone();
+        assert!(limit.try_acquire());
+        assert!(shared.try_acquire());
+        assert!(!limit.try_acquire());
+        assert_eq!(shared.suspended(), 2);
+
+        shared.release();
+        assert!(limit.try_acquire());
+        limit.release();
+        limit.release();
+        limit.release();
+        assert_eq!(limit.suspended(), 0);
+    }
+}
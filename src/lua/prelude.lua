@@ -1,58 +1,480 @@
 __threads = {}
 __thread_id_seq = 0
 __scripts = {}
+__sources = {}
+__generators = {}
+__generator_id_seq = 0
+-- returned to the host, which keeps it in the registry out of reach of scripts
+local userdata_formatters = {}
+-- incremented by each maintenance tick
+__tick = 0
+-- number of messages run by the handler, exposed as `ctx.seq` while it runs
+__seq = 0
 
 ctx = { state = {} }
 
+-- use the registered formatter for userdata, if any
+local __tostring = tostring
+function tostring(v)
+    if type(v) == "userdata" then
+        for _, format in pairs(userdata_formatters) do
+            local repr = format(v)
+            if repr ~= nil then
+                return repr
+            end
+        end
+    end
+    return __tostring(v)
+end
+
 function __load(script, name)
     local f, err = load(script, name, "bt")
     if f == nil then
         error(err)
     end
     __scripts[name] = f
+    __sources[name] = script
 end
 
--- create a new coroutine from given script
-function __run(script_name, msg, thread_id)
-    ctx.thread_id = __thread_id_seq
-    __thread_id_seq = __thread_id_seq + 1
+function __unload(name)
+    __scripts[name] = nil
+    __sources[name] = nil
+end
+
+-- host functions registered as globals, see `__track_globals`
+__injected = {}
+__accessed = {}
+function __inject(name, value)
+    __injected[name] = value
+    if not __tracking_globals then
+        rawset(_G, name, value)
+    end
+end
+
+-- make `string.rep` fail rather than build a string over `max` bytes, see `with_string_sandbox`
+function __cap_string_rep(max)
+    local rep = string.rep
+    string.rep = function(s, n, sep)
+        if n > 0 then
+            -- computed with floats, so a huge `n` can't overflow
+            local len = #tostring(s) * (n + 0.0) + #tostring(sep or "") * (n - 1.0)
+            if len > max then
+                error("string.rep result too large (" .. string.format("%.0f", len) .. " bytes, limit " .. max .. ")", 2)
+            end
+        end
+        return rep(s, n, sep)
+    end
+end
+
+local function __raise_if_failed(failed, ...)
+    if failed then
+        error((...), 0)
+    end
+    return ...
+end
+
+-- wrap a host function returning whether it failed followed by its results, to raise its errors
+-- a failed function returns its error as its only result
+function __raising(f)
+    return function(...)
+        return __raise_if_failed(f(...))
+    end
+end
+
+-- serve host functions through the metatable of _G to record which ones are accessed
+function __track_globals()
+    __tracking_globals = true
+    for name in pairs(__injected) do
+        rawset(_G, name, nil)
+    end
+    setmetatable(_G, {
+        __index = function(_, name)
+            local value = __injected[name]
+            if value ~= nil then
+                __accessed[name] = true
+            end
+            return value
+        end,
+    })
+end
+
+function __unused_globals()
+    local unused = {}
+    for name in pairs(__injected) do
+        if not __accessed[name] then
+            unused[#unused + 1] = name
+        end
+    end
+    table.sort(unused)
+    return unused
+end
+
+-- make a loaded script the active handler, keeping the current one for rollback
+function __activate(name)
+    local f = __scripts[name]
+    if f == nil then
+        error("script not loaded: " .. name)
+    end
+    __previous_handle = __scripts["handle"]
+    __previous_source = __sources["handle"]
+    __scripts["handle"] = f
+    __sources["handle"] = __sources[name]
+end
+
+-- swap the active handler with the previous one
+function __rollback()
+    if __previous_handle == nil then
+        error("no previous handler to roll back to")
+    end
+    __scripts["handle"], __previous_handle = __previous_handle, __scripts["handle"]
+    __sources["handle"], __previous_source = __previous_source, __sources["handle"]
+end
+
+-- make writes to the top-level fields of ctx.state raise an error until `__unlock_state`
+-- `__newindex` only catches missing keys, so the fields are moved behind the metatable meanwhile;
+-- nested tables stay mutable
+function __lock_state()
+    local state = ctx.state
+    if __locked_state ~= nil or type(state) ~= "table" then
+        return
+    end
+    local fields = {}
+    for k, v in next, state do
+        fields[k] = v
+    end
+    for k in next, fields do
+        rawset(state, k, nil)
+    end
+    __locked_state = { state = state, fields = fields, metatable = getmetatable(state) }
+    setmetatable(state, {
+        __index = fields,
+        __newindex = function()
+            error("state is read-only", 2)
+        end,
+        __pairs = function() return next, fields, nil end,
+        __len = function() return #fields end,
+    })
+end
 
+-- put back the fields moved by `__lock_state`
+function __unlock_state()
+    local locked = __locked_state
+    if locked == nil then
+        return
+    end
+    __locked_state = nil
+    setmetatable(locked.state, locked.metatable)
+    for k, v in next, locked.fields do
+        rawset(locked.state, k, v)
+    end
+end
+
+-- expose the host functions of the current invocation on ctx
+function __bind_ctx()
     ctx.notify = notify
     ctx.notify_later = notify_later
     ctx.send = function (recipient_name, msg)
+        if __in_generator then
+            error("ctx.send isn't available in generators", 2)
+        end
+        -- set by the host when the actor has a coroutine limit
+        if __acquire_coroutine ~= nil then
+            __acquire_coroutine()
+        end
         send(recipient_name, msg, ctx.thread_id)
-        return coroutine.yield("__suspended__" .. ctx.thread_id)
+        local ret, failed = coroutine.yield("__suspended__" .. ctx.thread_id)
+        if failed then
+            return nil, ret
+        end
+        return ret
     end
     ctx.do_send = do_send
+    ctx.stop = stop
     ctx.terminate = terminate
+end
+
+-- whether a coroutine yielded `ret` from `ctx.send`
+local function __is_suspension(ret)
+    return type(ret) == "string" and ret:sub(1, 13) == "__suspended__"
+end
+
+-- the coroutine running `handle` across messages, when `__long_lived` is set by the host
+__handler = nil
+-- set while `__handler` waits for the reply to a `ctx.send`
+__handler_waiting = false
+
+-- create a new coroutine from given script
+function __run(script_name, msg, thread_id)
+    ctx.thread_id = __thread_id_seq
+    __thread_id_seq = __thread_id_seq + 1
+
+    __bind_ctx()
 
     ctx.msg = msg
+    if script_name == "handle" then
+        __seq = __seq + 1
+        ctx.seq = __seq
+    end
+
+    local long_lived = __long_lived and script_name == "handle"
+    local thread
+    if long_lived and __handler ~= nil and coroutine.status(__handler) == "suspended"
+        and not __handler_waiting then
+        thread = __handler
+    else
+        local script = __scripts[script_name]
+        -- the on_message hook can answer a message before the handler
+        local on_message = __scripts["on_message"]
+        if script_name == "handle" and on_message ~= nil then
+            local handle = script
+            script = function(...)
+                local ret = on_message()
+                if ret ~= nil then
+                    return ret
+                end
+                return handle(...)
+            end
+        end
 
-    local thread = coroutine.create(__scripts[script_name])
+        thread = coroutine.create(script)
+        if long_lived then
+            __handler = thread
+        end
+    end
+
+    if script_name == "handle" then
+        __accessed = {}
+    end
 
-    local ok, ret = coroutine.resume(thread)
+    -- set by the host when the actor has a read-only state, or for a `ReadOnly` message
+    local read_only = (__read_only_state or __read_only_message) and script_name == "handle"
+    if read_only then
+        __lock_state()
+    end
+    local ok, ret = coroutine.resume(thread, msg)
+    __unlock_state()
     if not ok then
-        error(ret)
+        -- set by the host when the actor has a crash reporter
+        if script_name == "handle" and __report_crash ~= nil then
+            __report_crash(msg, tostring(ret))
+        end
+        -- the on_error hook can answer a message the handler failed on
+        local on_error = __scripts["on_error"]
+        if script_name ~= "handle" or on_error == nil then
+            error(ret)
+        end
+        local handled
+        handled, ret = pcall(on_error, ret)
+        if not handled then
+            ret = __error_hook_failed(tostring(ret))
+        end
     end
-    -- save the thread and its context if the thread yielded
-    if coroutine.status(thread) == "suspended" then
-        __threads[ctx.thread_id] = { thread = thread, msg = msg }
+    if long_lived then
+        __handler_waiting = __is_suspension(ret)
+    end
+    -- save the thread and its context if the thread yielded, unless the handler yielded its response
+    if coroutine.status(thread) == "suspended" and (not long_lived or __handler_waiting) then
+        -- `request_id` is set by the host for the current message, if any
+        __threads[ctx.thread_id] = {
+            thread = thread,
+            msg = msg,
+            handle = script_name == "handle",
+            read_only = read_only,
+            long_lived = long_lived,
+            request_id = request_id,
+            seq = ctx.seq,
+            tick = __tick,
+        }
     end
     ctx.msg = nil
     ctx.thread_id = nil
+    ctx.seq = nil
+    return ret
+end
+
+-- run a script compiled for `Eval`, returning its error as an `Error` message instead of raising it
+function __eval(script_name)
+    local ok, ret = pcall(__run, script_name)
+    if not ok then
+        ctx.thread_id = nil
+        return __call_failed(ret)
+    end
     return ret
 end
 
 -- resume a existing coroutine
-function __resume(thread_id, args)
+-- if `failed` is true, `args` is the error of the pending `ctx.send`
+function __resume(thread_id, args, failed)
     local thread = __threads[thread_id]
+    -- the thread was evicted by a maintenance tick
+    if thread == nil then
+        return nil
+    end
+    -- the functions bound by `__run` were only valid during the call that suspended the thread
+    __bind_ctx()
     ctx.thread_id = thread_id
     ctx.msg = thread.msg
-    local ok, ret = coroutine.resume(thread.thread, args)
-    if coroutine.status(thread.thread) == "dead" then
+    request_id = thread.request_id
+    ctx.seq = thread.seq
+    if thread.read_only then
+        __lock_state()
+    end
+    local ok, ret = coroutine.resume(thread.thread, args, failed)
+    __unlock_state()
+    request_id = nil
+    -- set by the host when the actor has a crash reporter
+    if not ok and thread.handle and __report_crash ~= nil then
+        __report_crash(thread.msg, tostring(ret))
+    end
+    -- the on_error hook can answer a message the resumed handler failed on, like in `__run`
+    local on_error = __scripts["on_error"]
+    if not ok and thread.handle and on_error ~= nil then
+        local handled
+        handled, ret = pcall(on_error, ret)
+        if not handled then
+            ret = __error_hook_failed(tostring(ret))
+        end
+    end
+    local waiting = coroutine.status(thread.thread) == "suspended"
+    -- a long-lived handler yielding its response is no longer waiting,
+    -- and is dropped if a later message replaced it with a new coroutine
+    if thread.long_lived then
+        waiting = waiting and __is_suspension(ret)
+        if thread.thread == __handler then
+            __handler_waiting = waiting
+        end
+    end
+    if not waiting then
         __threads[ctx.thread_id] = nil
     end
     ctx.msg = nil
     ctx.thread_id = nil
+    ctx.seq = nil
+    return ret
+end
+
+-- `require` against the host's module resolver, set by the host as `__resolve_module`
+__modules = {}
+-- names of the modules being loaded, outermost first, to detect cycles
+__loading = {}
+function __require(name)
+    local module = __modules[name]
+    if module ~= nil then
+        return module
+    end
+    local src = __resolve_module(name)
+    if src == nil then
+        error("module '" .. name .. "' not found", 2)
+    end
+    local f, err = load(src, "=" .. name, "t")
+    if f == nil then
+        error(err, 2)
+    end
+    for i, loading in ipairs(__loading) do
+        if loading == name then
+            error("cyclic require: " .. table.concat(__loading, " -> ", i) .. " -> " .. name, 2)
+        end
+    end
+    __loading[#__loading + 1] = name
+    local ok
+    ok, module = pcall(f, name)
+    __loading[#__loading] = nil
+    if not ok then
+        error(module, 0)
+    end
+    if module == nil then
+        module = true
+    end
+    __modules[name] = module
+    return module
+end
+
+-- evict threads suspended since before the previous tick and collect garbage
+-- returns the ids of the evicted threads
+function __maintain()
+    local evicted = {}
+    for id, thread in pairs(__threads) do
+        if thread.tick < __tick then
+            __threads[id] = nil
+            evicted[#evicted + 1] = id
+        end
+    end
+    __tick = __tick + 1
+    collectgarbage()
+    return evicted
+end
+
+-- drop the suspended threads and return how many there were
+function __drop_threads()
+    local n = 0
+    for id in pairs(__threads) do
+        __threads[id] = nil
+        n = n + 1
+    end
+    return n
+end
+
+-- call a function returned by a script earlier, returning its error as an `Error` message instead of raising it
+function __call(f, msg)
+    __bind_ctx()
+    local ok, ret = pcall(f, msg)
+    if not ok then
+        return __call_failed(ret)
+    end
+    return ret
+end
+
+-- call `f` with positional arguments, returning its error as an `Error` message instead of raising it
+function __call_multi(f, ...)
+    __bind_ctx()
+    local ok, ret = pcall(f, ...)
+    if not ok then
+        return __call_failed(ret)
+    end
     return ret
 end
+
+-- start a generator, a coroutine whose yielded values are streamed to the host
+function __generate(script_name, msg)
+    local id = __generator_id_seq
+    __generator_id_seq = __generator_id_seq + 1
+    __generators[id] = {
+        thread = coroutine.create(__scripts[script_name]),
+        msg = msg,
+        read_only = __read_only_state and script_name == "handle",
+    }
+    return id
+end
+
+-- set while a generator runs, since its yields can't suspend it for `ctx.send`
+__in_generator = false
+
+-- resume a generator until it yields its next value, returns or raises an error
+function __step(id)
+    local generator = __generators[id]
+    __bind_ctx()
+    ctx.msg = generator.msg
+    if generator.read_only then
+        __lock_state()
+    end
+    __in_generator = true
+    local ok, ret = coroutine.resume(generator.thread)
+    __in_generator = false
+    __unlock_state()
+    ctx.msg = nil
+    local done = coroutine.status(generator.thread) == "dead"
+    if done then
+        __generators[id] = nil
+    end
+    if not ok then
+        return { done = true, failed = true, value = __call_failed(ret) }
+    end
+    return { done = done, value = ret }
+end
+
+-- forget a generator which won't be resumed, e.g. because its sink failed
+function __drop_generator(id)
+    __generators[id] = nil
+end
+
+return userdata_formatters
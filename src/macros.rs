@@ -0,0 +1,107 @@
+/// Build a `LuaMessage::Table`, converting each value with `LuaMessage::from`.
+///
+/// ```
+/// # #[macro_use] extern crate actix_lua;
+/// # fn main() {
+/// let msg = lua_table! { "name" => "foo", "scores" => lua_array![1, 2] };
+/// # }
+/// ```
+#[macro_export]
+macro_rules! lua_table {
+    ($($key:expr => $value:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut table = ::std::collections::HashMap::new();
+        $(
+            table.insert(
+                ::std::string::String::from($key),
+                $crate::LuaMessage::from($value),
+            );
+        )*
+        $crate::LuaMessage::Table(table)
+    }};
+}
+
+/// Build a `LuaMessage::Array`, converting each element with `LuaMessage::from`.
+#[macro_export]
+macro_rules! lua_array {
+    ($($value:expr),* $(,)?) => {
+        $crate::LuaMessage::Array(vec![$($crate::LuaMessage::from($value)),*])
+    };
+}
+
+/// Assert that a `LuaMessage` equals a Lua literal, parsed with `LuaMessage::parse_lua`.
+///
+/// Both sides are printed as Lua literals on failure. Enabled with the `parse` feature.
+///
+/// ```
+/// # #[macro_use] extern crate actix_lua;
+/// # fn main() {
+/// assert_lua_eq!(lua_table! { "a" => 1 }, "{a = 1}");
+/// # }
+/// ```
+#[cfg(feature = "parse")]
+#[macro_export]
+macro_rules! assert_lua_eq {
+    ($left:expr, $literal:expr $(,)?) => {{
+        let left: &$crate::LuaMessage = &$left;
+        let literal: &str = $literal;
+        let right = $crate::LuaMessage::parse_lua(literal)
+            .unwrap_or_else(|e| panic!("invalid Lua literal {:?}: {}", literal, e));
+        if *left != right {
+            panic!(
+                "assertion failed: `(left == right)`\n  left: `{}`,\n right: `{}`",
+                left, right
+            );
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::LuaMessage;
+    use std::collections::HashMap;
+
+    #[test]
+    fn array_macro() {
+        assert_eq!(
+            lua_array![1, 2],
+            LuaMessage::Array(vec![LuaMessage::from(1), LuaMessage::from(2)])
+        );
+        assert_eq!(lua_array![], LuaMessage::Array(vec![]));
+    }
+
+    #[cfg(feature = "parse")]
+    #[test]
+    fn assert_lua_eq() {
+        let result = lua_table! { "a" => 1 };
+        assert_lua_eq!(result, "{a=1}");
+        assert_lua_eq!(lua_array!["x", true], r#"{"x", true}"#);
+    }
+
+    #[cfg(feature = "parse")]
+    #[test]
+    #[should_panic(expected = "right: `{a = 2}`")]
+    fn assert_lua_eq_mismatch() {
+        assert_lua_eq!(lua_table! { "a" => 1 }, "{a = 2}");
+    }
+
+    #[test]
+    fn nested_macros() {
+        let mut inner = HashMap::new();
+        inner.insert("x".to_string(), LuaMessage::from(1.5));
+        let mut t = HashMap::new();
+        t.insert("name".to_string(), LuaMessage::from("foo"));
+        t.insert(
+            "items".to_string(),
+            LuaMessage::Array(vec![LuaMessage::from(true), LuaMessage::Table(inner)]),
+        );
+
+        assert_eq!(
+            lua_table! {
+                "name" => "foo",
+                "items" => lua_array![true, lua_table! { "x" => 1.5 }],
+            },
+            LuaMessage::Table(t)
+        );
+    }
+}
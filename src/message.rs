@@ -1,12 +1,62 @@
 use ::actix::dev::{MessageResponse, ResponseChannel};
 use ::actix::prelude::*;
-use regex::Regex;
 use rlua::Result as LuaResult;
-use rlua::{Context, FromLua, ToLua, Value};
+use rlua::{AnyUserData, Context, Error as LuaError, FromLua, Function, Table, ToLua, Value};
 
-use std::collections::HashMap;
+use crate::error::LuaActorError;
 
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::error::Error as StdError;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A Rust error raised by a host function, kept so the host can recover its original type.
+///
+/// Two `HostError`s are equal only if they share the same underlying error.
+#[derive(Clone)]
+pub struct HostError(Arc<dyn StdError + Send + Sync>);
+
+impl HostError {
+    /// Returns a reference to the original error if it is of type `T`.
+    pub fn downcast_ref<T: StdError + 'static>(&self) -> Option<&T> {
+        self.0.downcast_ref::<T>()
+    }
+}
+
+impl fmt::Debug for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl PartialEq for HostError {
+    fn eq(&self, other: &HostError) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// A Lua function returned by a script, kept alive by the Lua state that returned it.
+///
+/// The function can be called with `CallFunction` until it's released with `ReleaseFunction`, or its Lua state,
+/// e.g. its `LuaActor`, is dropped. Every function converted to a `LuaMessage` is kept, including the ones in
+/// tables and in results nobody asked a handle for, so long-running actors should release the handles
+/// they are done with. A handle is meaningless to other actors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FunctionHandle(i64);
+
+/// A message exchanged with Lua actors.
+///
+/// The enum is non-exhaustive, as features add variants, e.g. `WideInteger` with `i128`.
+/// Matches need a wildcard arm, so enabling a feature doesn't break other crates.
 #[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum LuaMessage {
     String(String),
     Integer(i64),
@@ -15,6 +65,32 @@ pub enum LuaMessage {
     Nil,
     Table(HashMap<String, LuaMessage>),
     ThreadYield(String),
+    Array(Vec<LuaMessage>),
+    /// An error, optionally with a numeric code such as an HTTP status.
+    ///
+    /// A Lua table with exactly an integer `code` and a string `message` field is converted to an `Error`
+    /// with a code, and back.
+    Error {
+        code: Option<i64>,
+        message: String,
+        source: Option<HostError>,
+    },
+    /// A userdata of a type registered with `LuaActor::add_userdata_formatter`, as displayed by its formatter.
+    ///
+    /// This is a snapshot for display and dispatch on `type_name` only: the userdata itself isn't kept,
+    /// so converting a `UserData` back to Lua is an error.
+    UserData {
+        type_name: String,
+        repr: String,
+    },
+    Bytes(Vec<u8>),
+    Function(FunctionHandle),
+    /// An integer outside of the `i64` range, enabled with the `i128` feature.
+    ///
+    /// Lua integers are 64-bit, so converting a `WideInteger` to Lua is an error.
+    /// Values in range are always stored as `Integer`, see `From<i128>`.
+    #[cfg(feature = "i128")]
+    WideInteger(i128),
 }
 
 impl<A, M> MessageResponse<A, M> for LuaMessage
@@ -33,6 +109,855 @@ impl Message for LuaMessage {
     type Result = LuaMessage;
 }
 
+/// The reserved key of the single-key table representing an `Error` outside of Lua, e.g. in JSON.
+///
+/// In Lua, it marks a table with `code` and `message` fields as an `Error`,
+/// e.g. `{__lua_error = true, code = 404, message = "not found"}`.
+pub const ERROR_KEY: &str = "__lua_error";
+
+/// The conventional field holding the type tag of a table message.
+pub const TAG_FIELD: &str = "__type";
+
+/// How `LuaMessage::to_integer` rounds a `Number`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundMode {
+    /// Toward negative infinity.
+    Floor,
+    /// Toward positive infinity.
+    Ceil,
+    /// To the nearest integer, half-way cases away from zero.
+    Round,
+    /// Toward zero.
+    Truncate,
+}
+
+impl LuaMessage {
+    /// Create an `Error` message without a host error source.
+    pub fn error<S: Into<String>>(message: S) -> LuaMessage {
+        LuaMessage::Error {
+            code: None,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Create an `Error` message with a numeric code, such as an HTTP status.
+    pub fn error_with_code<S: Into<String>>(code: i64, message: S) -> LuaMessage {
+        LuaMessage::Error {
+            code: Some(code),
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Returns the code of an `Error` message, if any.
+    pub fn error_code(&self) -> Option<i64> {
+        match self {
+            LuaMessage::Error { code, .. } => *code,
+            _ => None,
+        }
+    }
+
+    /// Create an `Error` message from a Rust error. The error can be recovered with `downcast_error`.
+    pub fn from_error<E: StdError + Send + Sync + 'static>(err: E) -> LuaMessage {
+        LuaMessage::Error {
+            code: None,
+            message: err.to_string(),
+            source: Some(HostError(Arc::new(err))),
+        }
+    }
+
+    /// Returns the original host error if `self` is an `Error` raised by a host function with an error of type `T`.
+    pub fn downcast_error<T: StdError + 'static>(&self) -> Option<&T> {
+        match self {
+            LuaMessage::Error {
+                source: Some(source),
+                ..
+            } => source.downcast_ref::<T>(),
+            _ => None,
+        }
+    }
+
+    /// Converts the path to `Bytes` without loss.
+    ///
+    /// On non-unix platforms the path is converted lossily, the same as `LuaMessage::from`.
+    pub fn from_path_bytes(path: &Path) -> LuaMessage {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            LuaMessage::Bytes(path.as_os_str().as_bytes().to_vec())
+        }
+        #[cfg(not(unix))]
+        {
+            LuaMessage::Bytes(path.to_string_lossy().into_owned().into_bytes())
+        }
+    }
+
+    /// Returns the truthiness of the message as Lua sees it: only `Nil` and `Boolean(false)` are false.
+    ///
+    /// Unlike many languages, empty tables and arrays are truthy. Use `is_empty` to check for emptiness.
+    pub fn truthy(&self) -> bool {
+        !matches!(self, LuaMessage::Nil | LuaMessage::Boolean(false))
+    }
+
+    /// Returns `true` if `self` is a `Table` or `Array` without entries.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            LuaMessage::Table(x) => x.is_empty(),
+            LuaMessage::Array(x) => x.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Estimate the bytes used by the message, including nested values, strings and table keys.
+    ///
+    /// It's a cheap approximation meant for limits, not an exact measure of allocated memory.
+    pub fn approx_size(&self) -> usize {
+        let content = match self {
+            LuaMessage::String(x) | LuaMessage::ThreadYield(x) => x.len(),
+            LuaMessage::Bytes(x) => x.len(),
+            LuaMessage::Table(x) => x
+                .iter()
+                .map(|(k, v)| mem::size_of::<String>() + k.len() + v.approx_size())
+                .sum(),
+            LuaMessage::Array(x) => x.iter().map(LuaMessage::approx_size).sum(),
+            LuaMessage::Error { message, .. } => message.len(),
+            LuaMessage::UserData { type_name, repr } => type_name.len() + repr.len(),
+            _ => 0,
+        };
+        mem::size_of::<LuaMessage>() + content
+    }
+
+    /// Count the nodes of the message: itself and every nested value, scalars and containers alike.
+    ///
+    /// Useful to reject overly complex results, where `approx_size` measures their size instead.
+    pub fn deep_len(&self) -> usize {
+        let children = match self {
+            LuaMessage::Table(x) => x.values().map(LuaMessage::deep_len).sum(),
+            LuaMessage::Array(x) => x.iter().map(LuaMessage::deep_len).sum(),
+            _ => 0,
+        };
+        1 + children
+    }
+
+    /// Returns the value of an `Integer` or `WideInteger`, so computations on it can't overflow.
+    #[cfg(feature = "i128")]
+    pub fn as_i128(&self) -> Option<i128> {
+        match self {
+            LuaMessage::Integer(x) => Some(i128::from(*x)),
+            LuaMessage::WideInteger(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Convert a `Number` to an `Integer` rounded with `mode`. An `Integer` is returned as is.
+    ///
+    /// Returns `None` for other variants and for numbers out of the `i64` range once rounded, including NaN.
+    pub fn to_integer(&self, mode: RoundMode) -> Option<LuaMessage> {
+        let x = match self {
+            LuaMessage::Integer(x) => return Some(LuaMessage::Integer(*x)),
+            LuaMessage::Number(x) => *x,
+            _ => return None,
+        };
+        let rounded = match mode {
+            RoundMode::Floor => x.floor(),
+            RoundMode::Ceil => x.ceil(),
+            RoundMode::Round => x.round(),
+            RoundMode::Truncate => x.trunc(),
+        };
+        // `i64::MIN` is exactly representable, `i64::MAX` rounds up to 2^63
+        if rounded >= i64::MIN as f64 && rounded < -(i64::MIN as f64) {
+            Some(LuaMessage::Integer(rounded as i64))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the bits of an `Integer` reinterpreted as an unsigned integer, or `None` for other variants.
+    ///
+    /// Lua integers are signed 64-bit, so a `u64` such as a hash or bit mask with the high bit set travels
+    /// through Lua as a negative integer. This reverses that two's complement cast (`x as u64`) rather than
+    /// converting the value: `Integer(-1)` reads as `u64::MAX`, not as `None`.
+    pub fn as_u64_bits(&self) -> Option<u64> {
+        match self {
+            LuaMessage::Integer(x) => Some(*x as u64),
+            _ => None,
+        }
+    }
+
+    /// Returns the string form of a `String`, `Integer`, `Number` or `Boolean`, or `None` for other variants.
+    pub fn scalar_to_string(&self) -> Option<String> {
+        match self {
+            LuaMessage::String(x) => Some(x.clone()),
+            LuaMessage::Integer(x) => Some(x.to_string()),
+            #[cfg(feature = "i128")]
+            LuaMessage::WideInteger(x) => Some(x.to_string()),
+            LuaMessage::Number(x) => Some(format!("{:?}", x)),
+            LuaMessage::Boolean(x) => Some(x.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Returns a displayable string, borrowing the content of a `String` and formatting other variants
+    /// with `Display`. Useful for logging without allocating in the common string case.
+    ///
+    /// Unlike `Display`, which writes a `String` as a quoted Lua literal, the content of a `String` is returned
+    /// as is. Strings nested in tables are still quoted.
+    pub fn as_display_cow(&self) -> Cow<'_, str> {
+        match self {
+            LuaMessage::String(x) => Cow::Borrowed(x),
+            x => Cow::Owned(x.to_string()),
+        }
+    }
+
+    /// Export a flat `Table` as a map of strings.
+    ///
+    /// Scalar values are stringified with `scalar_to_string`. Other values, such as nested tables, are an error.
+    pub fn to_string_map(&self) -> Result<HashMap<String, String>, LuaActorError> {
+        let table = match self {
+            LuaMessage::Table(x) => x,
+            _ => return Err(conversion_error("a table", self)),
+        };
+
+        table
+            .iter()
+            .map(|(k, v)| match v.scalar_to_string() {
+                Some(s) => Ok((k.clone(), s)),
+                None => Err(conversion_error(&format!("a scalar for key {}", k), v)),
+            })
+            .collect()
+    }
+
+    /// Export a flat `Table` as a map of strings sorted by key, like `to_string_map`, for deterministic output.
+    pub fn to_sorted_string_map(&self) -> Result<BTreeMap<String, String>, LuaActorError> {
+        Ok(self.to_string_map()?.into_iter().collect())
+    }
+
+    /// Export an `Array` of strings, such as command-line arguments.
+    ///
+    /// Elements other than strings are an error. An empty table, which Lua can't tell apart from an empty array,
+    /// gives an empty `Vec`.
+    pub fn to_string_vec(&self) -> Result<Vec<String>, LuaActorError> {
+        let array = match self {
+            LuaMessage::Array(x) => x,
+            LuaMessage::Table(x) if x.is_empty() => return Ok(vec![]),
+            _ => return Err(conversion_error("an array", self)),
+        };
+
+        array
+            .iter()
+            .enumerate()
+            .map(|(i, v)| match v {
+                LuaMessage::String(s) => Ok(s.clone()),
+                v => Err(LuaActorError::Conversion(format!(
+                    "element {} is not a string: {}",
+                    i, v
+                ))),
+            })
+            .collect()
+    }
+
+    /// Convert an `Array` of `{key, value}` pairs, as some scripts return, into a `Table`.
+    ///
+    /// Keys are stringified with `scalar_to_string` and a later pair overrides an earlier one with the same key.
+    /// Returns `None` if an element isn't a two-element array with a scalar key.
+    pub fn pairs_to_table(&self) -> Option<LuaMessage> {
+        let array = match self {
+            LuaMessage::Array(x) => x,
+            LuaMessage::Table(x) if x.is_empty() => return Some(LuaMessage::Table(HashMap::new())),
+            _ => return None,
+        };
+
+        array
+            .iter()
+            .map(|pair| match pair {
+                LuaMessage::Array(kv) if kv.len() == 2 => {
+                    Some((kv[0].scalar_to_string()?, kv[1].clone()))
+                }
+                _ => None,
+            })
+            .collect::<Option<HashMap<_, _>>>()
+            .map(LuaMessage::Table)
+    }
+
+    /// Export a flat `Table` as environment variable pairs, sorted by key, see `to_string_map`.
+    pub fn to_env_pairs(&self) -> Result<Vec<(String, String)>, LuaActorError> {
+        let mut pairs: Vec<_> = self.to_string_map()?.into_iter().collect();
+        pairs.sort();
+        Ok(pairs)
+    }
+
+    /// Release excess capacity of tables and arrays, recursively.
+    ///
+    /// Useful before storing a large result for a long time, e.g. after filtering a table.
+    pub fn compact(&mut self) {
+        match self {
+            LuaMessage::Table(x) => {
+                x.values_mut().for_each(LuaMessage::compact);
+                x.shrink_to_fit();
+            }
+            LuaMessage::Array(x) => {
+                x.iter_mut().for_each(LuaMessage::compact);
+                x.shrink_to_fit();
+            }
+            LuaMessage::Bytes(x) => x.shrink_to_fit(),
+            _ => {}
+        }
+    }
+
+    /// Round a `Number` to the given number of decimal places. Other variants are returned unchanged.
+    ///
+    /// Halfway cases are rounded away from zero, like `f64::round`. Rounding happens on the binary value,
+    /// so a number such as `2.675`, stored as `2.67499...`, rounds to `2.67`.
+    /// Numbers too large to be scaled by `10^decimals` have no decimals to round and are returned unchanged.
+    pub fn round_to(self, decimals: u32) -> LuaMessage {
+        match self {
+            LuaMessage::Number(x) => {
+                // 10^308 is the largest finite power of ten
+                let scale = 10f64.powi(decimals.min(308) as i32);
+                let scaled = x * scale;
+                if !scaled.is_finite() {
+                    return LuaMessage::Number(x);
+                }
+                LuaMessage::Number(scaled.round() / scale)
+            }
+            x => x,
+        }
+    }
+
+    /// Returns the type tag of a table message, read from its `__type` field.
+    ///
+    /// Tags let Rust route heterogeneous messages, e.g. `match msg.tag() { Some("ping") => ... }`.
+    pub fn tag(&self) -> Option<&str> {
+        self.tag_in(TAG_FIELD)
+    }
+
+    /// Returns the type name of a `UserData` message, as registered with `LuaActor::add_userdata_formatter`.
+    ///
+    /// Lets Rust dispatch on the type of userdata returned by scripts, like `tag` for tables.
+    pub fn userdata_type(&self) -> Option<&str> {
+        match self {
+            LuaMessage::UserData { type_name, .. } => Some(type_name),
+            _ => None,
+        }
+    }
+
+    /// Returns the type tag of a table message, read from the given field.
+    pub fn tag_in(&self, field: &str) -> Option<&str> {
+        match self {
+            LuaMessage::Table(x) => match x.get(field) {
+                Some(LuaMessage::String(tag)) => Some(tag),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Set the type tag of a table message in its `__type` field. Other variants are returned unchanged.
+    pub fn with_tag(self, tag: &str) -> LuaMessage {
+        self.with_tag_in(TAG_FIELD, tag)
+    }
+
+    /// Set the type tag of a table message in the given field. Other variants are returned unchanged.
+    pub fn with_tag_in(self, field: &str, tag: &str) -> LuaMessage {
+        match self {
+            LuaMessage::Table(mut x) => {
+                x.insert(field.to_string(), LuaMessage::from(tag));
+                LuaMessage::Table(x)
+            }
+            x => x,
+        }
+    }
+
+    /// The keys of a table message, in arbitrary order. Returns `None` for non-table messages.
+    pub fn keys(&self) -> Option<Vec<&String>> {
+        match self {
+            LuaMessage::Table(x) => Some(x.keys().collect()),
+            _ => None,
+        }
+    }
+
+    /// The values of a table message, in arbitrary order. Returns `None` for non-table messages.
+    pub fn values(&self) -> Option<Vec<&LuaMessage>> {
+        match self {
+            LuaMessage::Table(x) => Some(x.values().collect()),
+            _ => None,
+        }
+    }
+
+    /// Look up a key of a table message, ignoring ASCII case. Useful for header-style tables.
+    ///
+    /// If several keys match, an exact match wins; otherwise the first matching key in sorted order is used.
+    /// Returns `None` for non-table messages.
+    pub fn get_ci(&self, key: &str) -> Option<&LuaMessage> {
+        let table = match self {
+            LuaMessage::Table(x) => x,
+            _ => return None,
+        };
+
+        if let Some(v) = table.get(key) {
+            return Some(v);
+        }
+        table
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, v)| v)
+    }
+
+    /// Look up a nested value by a dot separated path, such as `user.name` or `items.0.name`.
+    ///
+    /// Segments index tables by key; numeric segments index arrays, starting from 0 like the underlying `Vec`.
+    /// Returns `None` if a key is missing, an index is out of range or a segment can't be applied.
+    pub fn path_get(&self, path: &str) -> Option<&LuaMessage> {
+        path.split('.').try_fold(self, |msg, segment| match msg {
+            LuaMessage::Table(x) => x.get(segment),
+            LuaMessage::Array(x) => segment.parse::<usize>().ok().and_then(|i| x.get(i)),
+            _ => None,
+        })
+    }
+
+    /// List every scalar leaf with its dot separated path, in the format of `path_get`.
+    ///
+    /// Table entries are listed by key order, and empty tables and arrays have no leaves.
+    /// A scalar message is its own leaf, with an empty path.
+    pub fn leaves(&self) -> Vec<(String, &LuaMessage)> {
+        let mut leaves = vec![];
+        self.collect_leaves(String::new(), &mut leaves);
+        leaves
+    }
+
+    fn collect_leaves<'a>(&'a self, path: String, leaves: &mut Vec<(String, &'a LuaMessage)>) {
+        let join = |segment: &str| {
+            if path.is_empty() {
+                segment.to_string()
+            } else {
+                format!("{}.{}", path, segment)
+            }
+        };
+        match self {
+            LuaMessage::Table(x) => {
+                let mut entries: Vec<_> = x.iter().collect();
+                entries.sort_by_key(|(k, _)| *k);
+                for (k, v) in entries {
+                    v.collect_leaves(join(k), leaves);
+                }
+            }
+            LuaMessage::Array(x) => {
+                for (i, v) in x.iter().enumerate() {
+                    v.collect_leaves(join(&i.to_string()), leaves);
+                }
+            }
+            leaf => leaves.push((path, leaf)),
+        }
+    }
+
+    /// Compare two messages, skipping table entries whose key is in `ignore_keys` at any depth.
+    ///
+    /// Useful for asserting on results with volatile fields, such as timestamps or ids.
+    pub fn eq_ignoring(&self, other: &LuaMessage, ignore_keys: &[&str]) -> bool {
+        match (self, other) {
+            (LuaMessage::Table(a), LuaMessage::Table(b)) => {
+                let kept = |t: &HashMap<String, LuaMessage>| {
+                    t.keys()
+                        .filter(|k| !ignore_keys.contains(&k.as_str()))
+                        .count()
+                };
+                kept(a) == kept(b)
+                    && a.iter()
+                        .filter(|(k, _)| !ignore_keys.contains(&k.as_str()))
+                        .all(|(k, v)| match b.get(k) {
+                            Some(w) => v.eq_ignoring(w, ignore_keys),
+                            None => false,
+                        })
+            }
+            (LuaMessage::Array(a), LuaMessage::Array(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(v, w)| v.eq_ignoring(w, ignore_keys))
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// Compare two messages, treating an `Array` and a `Table` keyed by the 1-based indexes of its elements as equal.
+    ///
+    /// Such tables are produced by `into_indexed_tables` and by round-trips through formats without arrays.
+    pub fn structural_eq(&self, other: &LuaMessage) -> bool {
+        match (self, other) {
+            (LuaMessage::Table(a), LuaMessage::Table(b)) => {
+                a.len() == b.len()
+                    && a.iter().all(|(k, v)| match b.get(k) {
+                        Some(w) => v.structural_eq(w),
+                        None => false,
+                    })
+            }
+            (LuaMessage::Array(a), LuaMessage::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(v, w)| v.structural_eq(w))
+            }
+            (LuaMessage::Array(a), LuaMessage::Table(t))
+            | (LuaMessage::Table(t), LuaMessage::Array(a)) => {
+                a.len() == t.len()
+                    && a.iter()
+                        .enumerate()
+                        .all(|(i, v)| match t.get(&(i + 1).to_string()) {
+                            Some(w) => v.structural_eq(w),
+                            None => false,
+                        })
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// Hash the message by value, for caching and change detection: equal messages have equal hashes,
+    /// whatever the order their tables were built in.
+    ///
+    /// The hash is stable for a given build, but may change with the Rust version, so it shouldn't be persisted.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash_content(&mut hasher);
+        hasher.finish()
+    }
+
+    fn hash_content<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            LuaMessage::String(x) | LuaMessage::ThreadYield(x) => x.hash(state),
+            LuaMessage::Integer(x) => x.hash(state),
+            #[cfg(feature = "i128")]
+            LuaMessage::WideInteger(x) => x.hash(state),
+            // `-0.0 == 0.0`, so both hash the same
+            LuaMessage::Number(x) => (if *x == 0.0 { 0.0 } else { *x }).to_bits().hash(state),
+            LuaMessage::Boolean(x) => x.hash(state),
+            LuaMessage::Nil => {}
+            LuaMessage::Table(x) => {
+                // entries are hashed on their own and summed, as the sum doesn't depend on the iteration order
+                let sum = x.iter().fold(0u64, |sum, (k, v)| {
+                    let mut entry = DefaultHasher::new();
+                    k.hash(&mut entry);
+                    v.hash_content(&mut entry);
+                    sum.wrapping_add(entry.finish())
+                });
+                x.len().hash(state);
+                sum.hash(state);
+            }
+            LuaMessage::Array(x) => {
+                x.len().hash(state);
+                x.iter().for_each(|v| v.hash_content(state));
+            }
+            LuaMessage::Error { code, message, .. } => {
+                code.hash(state);
+                message.hash(state);
+            }
+            LuaMessage::UserData { type_name, repr } => {
+                type_name.hash(state);
+                repr.hash(state);
+            }
+            LuaMessage::Bytes(x) => x.hash(state),
+            LuaMessage::Function(x) => x.hash(state),
+        }
+    }
+
+    /// Clone the message, replacing the values of table entries whose key is in `sensitive_keys`
+    /// at any depth with `"***"`. Useful for logging results containing secrets.
+    pub fn redact(&self, sensitive_keys: &[&str]) -> LuaMessage {
+        match self {
+            LuaMessage::Table(x) => LuaMessage::Table(
+                x.iter()
+                    .map(|(k, v)| {
+                        let v = if sensitive_keys.contains(&k.as_str()) {
+                            LuaMessage::from("***")
+                        } else {
+                            v.redact(sensitive_keys)
+                        };
+                        (k.clone(), v)
+                    })
+                    .collect(),
+            ),
+            LuaMessage::Array(x) => {
+                LuaMessage::Array(x.iter().map(|v| v.redact(sensitive_keys)).collect())
+            }
+            x => x.clone(),
+        }
+    }
+
+    /// Clone the message, renaming the top-level keys of a table found in `mapping`.
+    ///
+    /// Useful to adapt script output to an external schema. Other messages are cloned as is.
+    pub fn rename_keys(&self, mapping: &HashMap<String, String>) -> LuaMessage {
+        self.rename_keys_with(mapping, false)
+    }
+
+    /// Like `rename_keys`, but renames keys of nested tables as well, including tables in arrays.
+    pub fn rename_keys_recursive(&self, mapping: &HashMap<String, String>) -> LuaMessage {
+        self.rename_keys_with(mapping, true)
+    }
+
+    fn rename_keys_with(&self, mapping: &HashMap<String, String>, recursive: bool) -> LuaMessage {
+        let nested = |v: &LuaMessage| {
+            if recursive {
+                v.rename_keys_with(mapping, true)
+            } else {
+                v.clone()
+            }
+        };
+        match self {
+            LuaMessage::Table(x) => LuaMessage::Table(
+                x.iter()
+                    .map(|(k, v)| (mapping.get(k).unwrap_or(k).clone(), nested(v)))
+                    .collect(),
+            ),
+            LuaMessage::Array(x) if recursive => LuaMessage::Array(x.iter().map(nested).collect()),
+            x => x.clone(),
+        }
+    }
+
+    /// Append the elements of `other` to `self`.
+    ///
+    /// Returns `LuaMessage::Error` if either message is not an `Array`.
+    pub fn concat(self, other: LuaMessage) -> LuaMessage {
+        match (self, other) {
+            (LuaMessage::Array(mut x), LuaMessage::Array(y)) => {
+                x.extend(y);
+                LuaMessage::Array(x)
+            }
+            _ => LuaMessage::error("can only concat two arrays"),
+        }
+    }
+
+    /// Deep-merge `defaults` under `self`: values in `self` win, nested tables are merged recursively.
+    ///
+    /// A `Nil` in `self` is treated as missing and replaced by the default.
+    pub fn with_defaults(self, defaults: LuaMessage) -> LuaMessage {
+        match (self, defaults) {
+            (LuaMessage::Table(mut x), LuaMessage::Table(y)) => {
+                for (k, d) in y {
+                    let v = match x.remove(&k) {
+                        Some(v) => v.with_defaults(d),
+                        None => d,
+                    };
+                    x.insert(k, v);
+                }
+                LuaMessage::Table(x)
+            }
+            (LuaMessage::Nil, d) => d,
+            (x, _) => x,
+        }
+    }
+
+    /// Split an `Array` into arrays of `size` elements, the last one possibly shorter.
+    ///
+    /// Returns `None` if `self` isn't an `Array` or `size` is 0.
+    pub fn chunks(&self, size: usize) -> Option<Vec<LuaMessage>> {
+        match self {
+            LuaMessage::Array(x) if size > 0 => Some(
+                x.chunks(size)
+                    .map(|chunk| LuaMessage::Array(chunk.to_vec()))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Take the entries of a `Table` in a deterministic order, sorted by key. Other variants have no entries.
+    ///
+    /// Tables are backed by a `HashMap`, which doesn't keep the insertion order, so the keys are sorted instead.
+    pub fn into_ordered_pairs(self) -> Vec<(String, LuaMessage)> {
+        match self {
+            LuaMessage::Table(x) => {
+                let mut pairs: Vec<_> = x.into_iter().collect();
+                pairs.sort_by(|a, b| a.0.cmp(&b.0));
+                pairs
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Convert `Array`s into `Table`s keyed by their 1-based Lua indexes, recursively.
+    ///
+    /// Useful when exporting to systems that should see Lua's indexes, e.g. as JSON objects rather than arrays.
+    pub fn into_indexed_tables(self) -> LuaMessage {
+        match self {
+            LuaMessage::Array(x) => LuaMessage::Table(
+                x.into_iter()
+                    .enumerate()
+                    .map(|(i, v)| ((i + 1).to_string(), v.into_indexed_tables()))
+                    .collect(),
+            ),
+            LuaMessage::Table(x) => LuaMessage::Table(
+                x.into_iter()
+                    .map(|(k, v)| (k, v.into_indexed_tables()))
+                    .collect(),
+            ),
+            x => x,
+        }
+    }
+
+    /// Transpose an `Array` of tables into columns keyed by field name.
+    ///
+    /// A row missing a field has `Nil` in that column. Returns `None` if `self` isn't an `Array` of `Table`s.
+    pub fn to_columns(&self) -> Option<HashMap<String, Vec<LuaMessage>>> {
+        let rows = match self {
+            LuaMessage::Array(x) => x,
+            _ => return None,
+        };
+
+        let mut columns: HashMap<String, Vec<LuaMessage>> = HashMap::new();
+        for (i, row) in rows.iter().enumerate() {
+            let row = match row {
+                LuaMessage::Table(x) => x,
+                _ => return None,
+            };
+            for (k, v) in row {
+                columns
+                    .entry(k.clone())
+                    .or_insert_with(|| vec![LuaMessage::Nil; i])
+                    .push(v.clone());
+            }
+            // pad the columns this row doesn't have
+            for column in columns.values_mut() {
+                column.resize(i + 1, LuaMessage::Nil);
+            }
+        }
+        Some(columns)
+    }
+
+    /// Map each row of an `Array` with `f`, skipping rows for which it returns `None`.
+    ///
+    /// Returns an empty `Vec` if `self` isn't an `Array`.
+    pub fn map_rows<T, F>(&self, f: F) -> Vec<T>
+    where
+        F: FnMut(&LuaMessage) -> Option<T>,
+    {
+        match self {
+            LuaMessage::Array(x) => x.iter().filter_map(f).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Split an `Array` into an `Array` of the elements for which `f` returns `true` and one of the others,
+    /// keeping their order, e.g. to route elements.
+    ///
+    /// Other variants are returned as the first value, with an empty `Array`.
+    pub fn partition<F>(self, f: F) -> (LuaMessage, LuaMessage)
+    where
+        F: FnMut(&LuaMessage) -> bool,
+    {
+        match self {
+            LuaMessage::Array(x) => {
+                let (matching, rest) = x.into_iter().partition(f);
+                (LuaMessage::Array(matching), LuaMessage::Array(rest))
+            }
+            v => (v, LuaMessage::Array(vec![])),
+        }
+    }
+
+    /// Compare two messages like Lua's `<` operator: numbers with numbers, strings with strings.
+    ///
+    /// Returns `None` for other combinations and for `NaN`.
+    pub fn try_cmp(&self, other: &LuaMessage) -> Option<Ordering> {
+        match (self, other) {
+            (LuaMessage::Integer(a), LuaMessage::Integer(b)) => Some(a.cmp(b)),
+            (LuaMessage::Integer(a), LuaMessage::Number(b)) => (*a as f64).partial_cmp(b),
+            (LuaMessage::Number(a), LuaMessage::Integer(b)) => a.partial_cmp(&(*b as f64)),
+            (LuaMessage::Number(a), LuaMessage::Number(b)) => a.partial_cmp(b),
+            (LuaMessage::String(a), LuaMessage::String(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    /// Sort an `Array` in place with `f`. The sort is stable; other variants are left unchanged.
+    pub fn sort_array_by<F>(&mut self, f: F)
+    where
+        F: FnMut(&LuaMessage, &LuaMessage) -> Ordering,
+    {
+        if let LuaMessage::Array(x) = self {
+            x.sort_by(f);
+        }
+    }
+
+    /// Sort an `Array` in ascending order with `try_cmp`. Elements that can't be compared are treated as equal.
+    pub fn sort_array(&mut self) {
+        self.sort_array_by(|a, b| a.try_cmp(b).unwrap_or(Ordering::Equal))
+    }
+}
+
+/// Formats the message as a Lua literal. `UserData` is formatted with its registered formatter.
+impl fmt::Display for LuaMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaMessage::String(x) => write!(f, "{:?}", x),
+            LuaMessage::Integer(x) => write!(f, "{}", x),
+            #[cfg(feature = "i128")]
+            LuaMessage::WideInteger(x) => write!(f, "{}", x),
+            LuaMessage::Number(x) => write!(f, "{:?}", x),
+            LuaMessage::Boolean(x) => write!(f, "{}", x),
+            LuaMessage::Nil => write!(f, "nil"),
+            LuaMessage::Table(x) => {
+                let mut keys: Vec<&String> = x.keys().collect();
+                keys.sort();
+                write!(f, "{{")?;
+                for (i, k) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    if is_lua_identifier(k) {
+                        write!(f, "{} = {}", k, x[k])?;
+                    } else {
+                        write!(f, "[{:?}] = {}", k, x[k])?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            LuaMessage::ThreadYield(x) => write!(f, "{:?}", format!("__suspended__{}", x)),
+            LuaMessage::Array(x) => {
+                write!(f, "{{")?;
+                for (i, v) in x.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "}}")
+            }
+            LuaMessage::Error {
+                code: Some(code),
+                message,
+                ..
+            } => write!(f, "error({{code = {}, message = {:?}}})", code, message),
+            LuaMessage::Error { message, .. } => write!(f, "error({:?})", message),
+            LuaMessage::UserData { repr, .. } => write!(f, "{}", repr),
+            LuaMessage::Function(FunctionHandle(id)) => write!(f, "function: #{}", id),
+            LuaMessage::Bytes(x) => {
+                write!(f, "\"")?;
+                for &b in x {
+                    match b {
+                        b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+                        0x20..=0x7e => write!(f, "{}", b as char)?,
+                        _ => write!(f, "\\x{:02x}", b)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
+
+// Keys that can be written as `key = value` in a Lua table constructor.
+fn is_lua_identifier(k: &str) -> bool {
+    const RESERVED: &[&str] = &[
+        "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+        "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+    ];
+    let mut chars = k.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_') && !RESERVED.contains(&k)
+}
+
 impl From<bool> for LuaMessage {
     fn from(s: bool) -> Self {
         LuaMessage::Boolean(s)
@@ -43,127 +968,1492 @@ impl<'l> From<&'l str> for LuaMessage {
     fn from(s: &'l str) -> Self {
         LuaMessage::String(s.to_string())
     }
-}
+}
+
+impl From<String> for LuaMessage {
+    fn from(s: String) -> Self {
+        LuaMessage::String(s)
+    }
+}
+
+impl<'l> From<Cow<'l, str>> for LuaMessage {
+    fn from(s: Cow<'l, str>) -> Self {
+        LuaMessage::String(s.into_owned())
+    }
+}
+
+impl From<Arc<str>> for LuaMessage {
+    fn from(s: Arc<str>) -> Self {
+        LuaMessage::String(s.to_string())
+    }
+}
+
+macro_rules! lua_message_convert_int {
+    ($x:ty) => {
+        impl From<$x> for LuaMessage {
+            fn from(s: $x) -> Self {
+                LuaMessage::Integer(i64::from(s))
+            }
+        }
+    };
+}
+
+lua_message_convert_int!(i8);
+lua_message_convert_int!(u8);
+lua_message_convert_int!(i16);
+lua_message_convert_int!(u16);
+lua_message_convert_int!(i32);
+lua_message_convert_int!(u32);
+lua_message_convert_int!(i64);
+
+impl From<usize> for LuaMessage {
+    fn from(s: usize) -> Self {
+        LuaMessage::Integer(s as i64)
+    }
+}
+
+impl From<isize> for LuaMessage {
+    fn from(s: isize) -> Self {
+        LuaMessage::Integer(s as i64)
+    }
+}
+
+/// Values in the `i64` range become an `Integer`, others a `WideInteger`.
+#[cfg(feature = "i128")]
+impl From<i128> for LuaMessage {
+    fn from(s: i128) -> Self {
+        match i64::try_from(s) {
+            Ok(x) => LuaMessage::Integer(x),
+            Err(_) => LuaMessage::WideInteger(s),
+        }
+    }
+}
+
+impl From<HashMap<String, LuaMessage>> for LuaMessage {
+    fn from(s: HashMap<String, LuaMessage>) -> Self {
+        LuaMessage::Table(s)
+    }
+}
+
+impl From<Vec<LuaMessage>> for LuaMessage {
+    fn from(s: Vec<LuaMessage>) -> Self {
+        LuaMessage::Array(s)
+    }
+}
+
+/// Convert rows, such as the results of a database query, into an `Array` of `Table`s, preserving order.
+impl From<Vec<HashMap<String, LuaMessage>>> for LuaMessage {
+    fn from(rows: Vec<HashMap<String, LuaMessage>>) -> Self {
+        LuaMessage::Array(rows.into_iter().map(LuaMessage::Table).collect())
+    }
+}
+
+/// The buffer is reused without copying when `s` is its only handle.
+#[cfg(feature = "bytes")]
+impl From<bytes::Bytes> for LuaMessage {
+    fn from(s: bytes::Bytes) -> Self {
+        LuaMessage::Bytes(Vec::from(s))
+    }
+}
+
+/// Build a `Table` from key/value pairs, with keys stringified like Lua table keys are by `from_lua`.
+///
+/// Keys must be strings or numbers other than `NaN`; other keys, such as booleans, are a `LuaActorError::Conversion`.
+/// When a key is repeated, the last value wins.
+impl TryFrom<Vec<(LuaMessage, LuaMessage)>> for LuaMessage {
+    type Error = LuaActorError;
+
+    fn try_from(pairs: Vec<(LuaMessage, LuaMessage)>) -> Result<LuaMessage, LuaActorError> {
+        let mut table = HashMap::with_capacity(pairs.len());
+        for (k, v) in pairs {
+            let key = match &k {
+                LuaMessage::Boolean(_) => None,
+                LuaMessage::Number(x) if x.is_nan() => None,
+                LuaMessage::Number(x) if x.fract() == 0.0 && x.abs() < 2f64.powi(63) => {
+                    Some((*x as i64).to_string())
+                }
+                k => k.scalar_to_string(),
+            };
+            match key {
+                Some(key) => table.insert(key, v),
+                None => return Err(conversion_error("a valid table key", &k)),
+            };
+        }
+        Ok(LuaMessage::Table(table))
+    }
+}
+
+/// Convert batch results into an `Array`, preserving order.
+///
+/// `Ok` entries are converted with `LuaMessage::from`; `Err` entries become `LuaMessage::Error`.
+impl<T, E> From<Vec<Result<T, E>>> for LuaMessage
+where
+    T: Into<LuaMessage>,
+    E: ToString,
+{
+    fn from(s: Vec<Result<T, E>>) -> Self {
+        LuaMessage::Array(
+            s.into_iter()
+                .map(|r| match r {
+                    Ok(v) => v.into(),
+                    Err(e) => LuaMessage::error(e.to_string()),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Converts the path to a `String`. Non UTF-8 sequences are replaced with `U+FFFD`.
+///
+/// Use `LuaMessage::from_path_bytes` to keep the path unchanged.
+impl<'l> From<&'l Path> for LuaMessage {
+    fn from(s: &'l Path) -> Self {
+        LuaMessage::String(s.to_string_lossy().into_owned())
+    }
+}
+
+/// Converts the path to a `String`. Non UTF-8 sequences are replaced with `U+FFFD`.
+///
+/// Use `LuaMessage::from_path_bytes` to keep the path unchanged.
+impl From<PathBuf> for LuaMessage {
+    fn from(s: PathBuf) -> Self {
+        LuaMessage::from(s.as_path())
+    }
+}
+
+macro_rules! lua_message_convert_float {
+    ($x:ty) => {
+        impl From<$x> for LuaMessage {
+            fn from(s: $x) -> Self {
+                LuaMessage::Number(f64::from(s))
+            }
+        }
+    };
+}
+
+lua_message_convert_float!(f32);
+lua_message_convert_float!(f64);
+
+/// A failed delivery becomes an `Error` carrying `LuaActorError::Mailbox`.
+impl From<MailboxError> for LuaMessage {
+    fn from(err: MailboxError) -> LuaMessage {
+        LuaMessage::from_error(LuaActorError::Mailbox(err.to_string()))
+    }
+}
+
+pub(crate) fn conversion_error(expected: &str, msg: &LuaMessage) -> LuaActorError {
+    LuaActorError::Conversion(format!("expect {}, got {}", expected, msg))
+}
+
+impl TryFrom<LuaMessage> for i64 {
+    type Error = LuaActorError;
+
+    fn try_from(msg: LuaMessage) -> Result<i64, LuaActorError> {
+        match msg {
+            LuaMessage::Integer(x) => Ok(x),
+            msg => Err(conversion_error("an integer", &msg)),
+        }
+    }
+}
+
+/// Integers are accepted as well.
+impl TryFrom<LuaMessage> for f64 {
+    type Error = LuaActorError;
+
+    fn try_from(msg: LuaMessage) -> Result<f64, LuaActorError> {
+        match msg {
+            LuaMessage::Number(x) => Ok(x),
+            LuaMessage::Integer(x) => Ok(x as f64),
+            msg => Err(conversion_error("a number", &msg)),
+        }
+    }
+}
+
+impl TryFrom<LuaMessage> for bool {
+    type Error = LuaActorError;
+
+    fn try_from(msg: LuaMessage) -> Result<bool, LuaActorError> {
+        match msg {
+            LuaMessage::Boolean(x) => Ok(x),
+            msg => Err(conversion_error("a boolean", &msg)),
+        }
+    }
+}
+
+impl TryFrom<LuaMessage> for String {
+    type Error = LuaActorError;
+
+    fn try_from(msg: LuaMessage) -> Result<String, LuaActorError> {
+        match msg {
+            LuaMessage::String(x) => Ok(x),
+            msg => Err(conversion_error("a string", &msg)),
+        }
+    }
+}
+
+/// Lua integers are converted to `Integer` and floats to `Number`, following Lua's number subtypes.
+///
+/// Integer division (`//`) and bitwise operators on integers produce integers, so their results stay `Integer`.
+/// Floats with an integral value, such as `7.0 // 2`, stay `Number`.
+impl<'lua> FromLua<'lua> for LuaMessage {
+    fn from_lua(v: Value<'lua>, ctx: Context<'lua>) -> LuaResult<LuaMessage> {
+        match v {
+            Value::String(x) if x.to_str().is_err() => Ok(LuaMessage::Bytes(x.as_bytes().to_vec())),
+            Value::String(x) => {
+                let s = String::from_lua(Value::String(x), ctx)?;
+                match s.strip_prefix("__suspended__") {
+                    Some(tid) if !tid.is_empty() => Ok(LuaMessage::ThreadYield(tid.to_string())),
+                    _ => Ok(LuaMessage::String(s)),
+                }
+            }
+            Value::Integer(n) => Ok(LuaMessage::Integer(n)),
+            Value::Number(n) => Ok(LuaMessage::Number(n)),
+            Value::Boolean(b) => Ok(LuaMessage::Boolean(b)),
+            Value::Nil => Ok(LuaMessage::Nil),
+            Value::Table(t) => table_from_lua(t, ctx),
+            Value::UserData(ud) => userdata_from_lua(ud, ctx),
+            Value::Error(err) => Ok(error_from_lua(err)),
+            Value::Function(f) => function_from_lua(f, ctx),
+            Value::LightUserData(_) => Err(LuaError::FromLuaConversionError {
+                from: "lightuserdata",
+                to: "LuaMessage",
+                message: None,
+            }),
+            Value::Thread(_) => Err(LuaError::FromLuaConversionError {
+                from: "thread",
+                to: "LuaMessage",
+                message: None,
+            }),
+        }
+    }
+}
+
+/// `Error` values are converted the same way at any depth, e.g. inside a result table:
+/// an error with a code becomes a `{code = ..., message = ...}` table marked with an `ERROR_KEY` field,
+/// and other errors become Lua error values, which scripts can inspect with `tostring` or raise again with `error`.
+/// Both keep the host error of the message, if any, so it survives a round trip through Lua.
+impl<'lua> ToLua<'lua> for LuaMessage {
+    fn to_lua(self, ctx: Context<'lua>) -> LuaResult<Value<'lua>> {
+        match self {
+            LuaMessage::String(x) => Ok(Value::String(ctx.create_string(&x)?)),
+            LuaMessage::Integer(x) => Ok(Value::Integer(x)),
+            #[cfg(feature = "i128")]
+            LuaMessage::WideInteger(x) => Err(LuaError::ToLuaConversionError {
+                from: "LuaMessage::WideInteger",
+                to: "integer",
+                message: Some(format!("{} is out of the range of Lua integers", x)),
+            }),
+            LuaMessage::Number(x) => Ok(Value::Number(x)),
+            LuaMessage::Boolean(x) => Ok(Value::Boolean(x)),
+            LuaMessage::Nil => Ok(Value::Nil),
+            LuaMessage::Table(x) => Ok(Value::Table(ctx.create_table_from(x)?)),
+            LuaMessage::Array(x) => Ok(Value::Table(ctx.create_sequence_from(x)?)),
+            LuaMessage::Error {
+                code: Some(code),
+                message,
+                source,
+            } => {
+                let t = ctx.create_table()?;
+                t.set("code", code)?;
+                t.set("message", message)?;
+                // the marker holds the host error, if any
+                match source {
+                    Some(HostError(err)) => {
+                        t.set(ERROR_KEY, Value::Error(LuaError::ExternalError(err)))?
+                    }
+                    None => t.set(ERROR_KEY, true)?,
+                }
+                Ok(Value::Table(t))
+            }
+            LuaMessage::Error {
+                message, source, ..
+            } => Ok(Value::Error(match source {
+                Some(HostError(err)) => LuaError::ExternalError(err),
+                None => LuaError::RuntimeError(message),
+            })),
+            LuaMessage::Bytes(x) => Ok(Value::String(ctx.create_string(&x)?)),
+            LuaMessage::UserData { type_name, .. } => Err(LuaError::ToLuaConversionError {
+                from: "LuaMessage::UserData",
+                to: "userdata",
+                message: Some(format!("can't recreate userdata of type {}", type_name)),
+            }),
+            LuaMessage::Function(FunctionHandle(id)) => functions_table(ctx)?.raw_get(id),
+
+            _ => unimplemented!(),
+        }
+    }
+}
+
+impl LuaMessage {
+    /// Convert a Lua value without converting a table as a whole.
+    ///
+    /// If `value` is a table, `f` is called with the key and value of each top-level entry and `Nil` is returned.
+    /// Other values are converted and returned as is.
+    pub fn from_lua_streaming<'lua, F>(
+        value: Value<'lua>,
+        ctx: Context<'lua>,
+        mut f: F,
+    ) -> LuaResult<LuaMessage>
+    where
+        F: FnMut(LuaMessage, LuaMessage),
+    {
+        match value {
+            Value::Table(t) => {
+                for pair in t.pairs::<LuaMessage, LuaMessage>() {
+                    let (k, v) = pair?;
+                    f(k, v);
+                }
+                Ok(LuaMessage::Nil)
+            }
+            v => LuaMessage::from_lua(v, ctx),
+        }
+    }
+}
+
+// Keep the innermost host error, if any, so its type can be recovered with `downcast_error`.
+pub(crate) fn error_from_lua(err: LuaError) -> LuaMessage {
+    match err {
+        LuaError::CallbackError { cause, .. } => error_from_lua((*cause).clone()),
+        LuaError::ExternalError(err) => LuaMessage::Error {
+            code: None,
+            message: err.to_string(),
+            source: Some(HostError(err)),
+        },
+        // `to_string` would prefix the message with "runtime error: ", and rlua appends a traceback
+        LuaError::RuntimeError(message) => LuaMessage::error(
+            message
+                .split("\nstack traceback:")
+                .next()
+                .unwrap_or_default(),
+        ),
+        err => LuaMessage::error(err.to_string()),
+    }
+}
+
+// Userdata is converted with the formatters registered by `LuaActor::add_userdata_formatter`.
+fn userdata_from_lua<'lua>(ud: AnyUserData<'lua>, ctx: Context<'lua>) -> LuaResult<LuaMessage> {
+    let formatters: Table = ctx.named_registry_value(USERDATA_FORMATTERS)?;
+    for pair in formatters.pairs::<String, Function>() {
+        let (type_name, format) = pair?;
+        if let Some(repr) = format.call::<_, Option<String>>(ud.clone())? {
+            return Ok(LuaMessage::UserData { type_name, repr });
+        }
+    }
+
+    Err(LuaError::FromLuaConversionError {
+        from: "userdata",
+        to: "LuaMessage",
+        message: Some("userdata type has no registered formatter".to_string()),
+    })
+}
+
+// The formatters table of the prelude, as `type name => function`.
+pub(crate) const USERDATA_FORMATTERS: &str = "actix_lua.userdata_formatters";
+
+const FUNCTIONS: &str = "actix_lua.functions";
+
+// Functions are kept in a registry table as `id => function` and `function => id`,
+// so returning the same function again gives the same handle.
+fn functions_table(ctx: Context) -> LuaResult<Table> {
+    match ctx.named_registry_value::<_, Option<Table>>(FUNCTIONS)? {
+        Some(t) => Ok(t),
+        None => {
+            let t = ctx.create_table()?;
+            ctx.set_named_registry_value(FUNCTIONS, t.clone())?;
+            Ok(t)
+        }
+    }
+}
+
+// Forget the function of `handle`, so it can be garbage collected.
+// Returns whether the handle was known.
+pub(crate) fn release_function(ctx: Context, handle: FunctionHandle) -> LuaResult<bool> {
+    let functions = functions_table(ctx)?;
+    match functions.raw_get::<_, Option<Function>>(handle.0)? {
+        Some(f) => {
+            functions.raw_set(handle.0, Value::Nil)?;
+            functions.raw_set(f, Value::Nil)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn function_from_lua<'lua>(f: Function<'lua>, ctx: Context<'lua>) -> LuaResult<LuaMessage> {
+    let functions = functions_table(ctx)?;
+    if let Some(id) = functions.raw_get::<_, Option<i64>>(f.clone())? {
+        return Ok(LuaMessage::Function(FunctionHandle(id)));
+    }
+
+    let id = functions.raw_get::<_, Option<i64>>("next_id")?.unwrap_or(1);
+    functions.raw_set("next_id", id + 1)?;
+    functions.raw_set(id, f.clone())?;
+    functions.raw_set(f, id)?;
+    Ok(LuaMessage::Function(FunctionHandle(id)))
+}
+
+// With sequence arrays enabled, a non-empty table whose keys are exactly `1..=n` is converted to `Array`.
+// Everything else is converted to a string-keyed `Table`.
+fn table_from_lua<'lua>(t: Table<'lua>, ctx: Context<'lua>) -> LuaResult<LuaMessage> {
+    match t.raw_get::<_, Value>(ERROR_KEY)? {
+        Value::Nil => {}
+        marker => return error_table_from_lua(&t, marker, ctx),
+    }
+
+    // only tables with a border can be sequences, so most tables skip the registry lookup
+    if t.raw_len() > 0
+        && ctx
+            .named_registry_value::<_, Option<bool>>(SEQUENCE_ARRAYS)?
+            .unwrap_or(false)
+    {
+        if let Some(arr) = sequence_from_lua(&t, ctx)? {
+            return Ok(LuaMessage::Array(arr));
+        }
+    }
+
+    let mut map = HashMap::new();
+    for pair in t.clone().pairs::<Value, Value>() {
+        let (k, v) = pair?;
+        let k = String::from_lua(k, ctx)?;
+        let v = LuaMessage::from_lua(v, ctx).map_err(|e| at_key(e, &k))?;
+        map.insert(k, v);
+    }
+    // only tables with a metatable can inherit fields, so the others skip the registry lookup
+    if t.get_metatable().is_some()
+        && ctx
+            .named_registry_value::<_, Option<bool>>(INHERITED_FIELDS)?
+            .unwrap_or(false)
+    {
+        inherited_from_lua(&t, &mut map, ctx)?;
+    }
+    Ok(LuaMessage::Table(map))
+}
+
+// A table marked with `ERROR_KEY` is an `Error`, and the marker may hold its host error.
+fn error_table_from_lua<'lua>(
+    t: &Table<'lua>,
+    marker: Value<'lua>,
+    ctx: Context<'lua>,
+) -> LuaResult<LuaMessage> {
+    let code = t.raw_get::<_, Option<i64>>("code")?;
+    let message = match t.raw_get::<_, Value>("message")? {
+        Value::Nil => String::new(),
+        v => String::from_lua(v, ctx)?,
+    };
+    let source = match marker {
+        Value::Error(err) => match error_from_lua(err) {
+            LuaMessage::Error { source, .. } => source,
+            _ => None,
+        },
+        _ => None,
+    };
+    Ok(LuaMessage::Error {
+        code,
+        message,
+        source,
+    })
+}
+
+/// The error of a value nested in a table, with the path to the value, such as `items[2].price`.
+///
+/// Table keys are separated by dots and array indexes are 1-based, like in Lua.
+#[derive(Debug)]
+struct ConversionPathError {
+    path: String,
+    cause: LuaError,
+}
+
+impl fmt::Display for ConversionPathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed converting key '{}': {}", self.path, self.cause)
+    }
+}
+
+impl StdError for ConversionPathError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+// Prepend `segment` to the path of `err`, so the path is only built when a conversion fails.
+fn prepend_path(err: LuaError, segment: String) -> LuaError {
+    let (path, cause) = match &err {
+        LuaError::ExternalError(e) => match e.downcast_ref::<ConversionPathError>() {
+            Some(e) if e.path.starts_with('[') => (segment + &e.path, e.cause.clone()),
+            Some(e) => (format!("{}.{}", segment, e.path), e.cause.clone()),
+            None => (segment, err),
+        },
+        _ => (segment, err),
+    };
+    LuaError::external(ConversionPathError { path, cause })
+}
+
+fn at_key(err: LuaError, key: &str) -> LuaError {
+    prepend_path(err, key.to_string())
+}
+
+fn at_index(err: LuaError, i: i64) -> LuaError {
+    prepend_path(err, format!("[{}]", i))
+}
+
+pub(crate) const INHERITED_FIELDS: &str = "actix_lua.inherited_fields";
+
+// The same bound as Lua's own `__index` lookups, which also stop cyclic chains.
+const MAX_INDEX_CHAIN: usize = 2000;
+
+// Add the fields of the `__index` tables up the metatable chain of `t` which aren't set closer to `t`.
+// `__index` functions can't be enumerated and end the chain.
+fn inherited_from_lua<'lua>(
+    t: &Table<'lua>,
+    map: &mut HashMap<String, LuaMessage>,
+    ctx: Context<'lua>,
+) -> LuaResult<()> {
+    let mut t = t.clone();
+    for _ in 0..MAX_INDEX_CHAIN {
+        let parent = match t.get_metatable() {
+            Some(mt) => match mt.raw_get::<_, Value>("__index")? {
+                Value::Table(parent) => parent,
+                _ => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        for pair in parent.clone().pairs::<Value, Value>() {
+            let (k, v) = pair?;
+            let k = String::from_lua(k, ctx)?;
+            if let Entry::Vacant(e) = map.entry(k) {
+                let v = LuaMessage::from_lua(v, ctx).map_err(|err| at_key(err, e.key()))?;
+                e.insert(v);
+            }
+        }
+        t = parent;
+    }
+    Err(LuaError::FromLuaConversionError {
+        from: "table",
+        to: "LuaMessage",
+        message: Some("'__index' chain too long; possible loop".to_string()),
+    })
+}
+
+pub(crate) const SEQUENCE_ARRAYS: &str = "actix_lua.sequence_arrays";
+
+const COUNT_KEYS: &str = "actix_lua.count_keys";
+
+// The function counting the keys of a table, created on first use.
+//
+// It keeps the `next` function it was created with, so scripts replacing the global `next` can't change
+// how tables are converted. `LuaActor` creates it before running any script.
+pub(crate) fn count_keys(ctx: Context) -> LuaResult<Function> {
+    match ctx.named_registry_value::<_, Option<Function>>(COUNT_KEYS)? {
+        Some(f) => Ok(f),
+        None => {
+            let f: Function = ctx
+                .load(
+                    "local next = next \
+                     return function(t) local n = 0 for _ in next, t do n = n + 1 end return n end",
+                )
+                .eval()?;
+            ctx.set_named_registry_value(COUNT_KEYS, f.clone())?;
+            Ok(f)
+        }
+    }
+}
+
+// Fast path for arrays: use the length operator and raw access instead of enumerating keys from Rust.
+//
+// A table is an array if it has exactly `#t` keys and `t[1]..t[#t]` are all non-nil.
+// Keys are counted in Lua, which is much cheaper than converting every key to a Rust value.
+fn sequence_from_lua<'lua>(
+    t: &Table<'lua>,
+    ctx: Context<'lua>,
+) -> LuaResult<Option<Vec<LuaMessage>>> {
+    let len = t.raw_len();
+    if len <= 0 {
+        return Ok(None);
+    }
+
+    if count_keys(ctx)?.call::<_, i64>(t.clone())? != len {
+        return Ok(None);
+    }
+
+    let mut arr = Vec::with_capacity(len as usize);
+    for i in 1..=len {
+        match t.raw_get::<_, Value>(i)? {
+            Value::Nil => return Ok(None),
+            v => arr.push(LuaMessage::from_lua(v, ctx).map_err(|e| at_index(e, i))?),
+        }
+    }
+    Ok(Some(arr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+    use std::mem::discriminant;
+
+    #[test]
+    fn constructors() {
+        assert_eq!(LuaMessage::from(42), LuaMessage::Integer(42));
+        assert_eq!(LuaMessage::from(0), LuaMessage::Integer(0));
+        assert_eq!(
+            LuaMessage::from("foo"),
+            LuaMessage::String("foo".to_string())
+        );
+        assert_eq!(LuaMessage::from(42.5), LuaMessage::Number(42.5));
+        assert_eq!(LuaMessage::from(true), LuaMessage::Boolean(true));
+
+        let mut t = HashMap::new();
+        t.insert("bar".to_string(), LuaMessage::from("abc"));
+        let mut t2 = HashMap::new();
+        t2.insert("bar".to_string(), LuaMessage::from("abc"));
+        assert_eq!(LuaMessage::from(t), LuaMessage::Table(t2));
+    }
+
+    #[test]
+    fn from_shared_str() {
+        let expected = LuaMessage::String("foo".to_string());
+        assert_eq!(LuaMessage::from(Cow::Borrowed("foo")), expected);
+        assert_eq!(
+            LuaMessage::from(Cow::<str>::Owned("foo".to_string())),
+            expected
+        );
+        assert_eq!(LuaMessage::from(Arc::<str>::from("foo")), expected);
+    }
+
+    #[test]
+    fn to_string_vec() {
+        assert_eq!(
+            lua_array!["a", "b"].to_string_vec(),
+            Ok(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(lua_table! {}.to_string_vec(), Ok(vec![]));
+        assert_eq!(
+            lua_array!["a", 1].to_string_vec(),
+            Err(LuaActorError::Conversion(
+                "element 1 is not a string: 1".to_string()
+            ))
+        );
+        assert!(LuaMessage::from("a").to_string_vec().is_err());
+    }
+
+    #[test]
+    fn to_integer() {
+        let x = LuaMessage::from(2.5);
+        assert_eq!(x.to_integer(RoundMode::Floor), Some(LuaMessage::from(2)));
+        assert_eq!(x.to_integer(RoundMode::Ceil), Some(LuaMessage::from(3)));
+        assert_eq!(x.to_integer(RoundMode::Round), Some(LuaMessage::from(3)));
+        assert_eq!(x.to_integer(RoundMode::Truncate), Some(LuaMessage::from(2)));
+
+        let x = LuaMessage::from(-2.5);
+        assert_eq!(x.to_integer(RoundMode::Floor), Some(LuaMessage::from(-3)));
+        assert_eq!(x.to_integer(RoundMode::Ceil), Some(LuaMessage::from(-2)));
+        assert_eq!(x.to_integer(RoundMode::Round), Some(LuaMessage::from(-3)));
+        assert_eq!(
+            x.to_integer(RoundMode::Truncate),
+            Some(LuaMessage::from(-2))
+        );
+
+        assert_eq!(
+            LuaMessage::from(7).to_integer(RoundMode::Round),
+            Some(LuaMessage::from(7))
+        );
+        assert_eq!(LuaMessage::from(1e19).to_integer(RoundMode::Floor), None);
+        assert_eq!(
+            LuaMessage::from(f64::NAN).to_integer(RoundMode::Floor),
+            None
+        );
+        assert_eq!(
+            LuaMessage::from(i64::MIN as f64).to_integer(RoundMode::Floor),
+            Some(LuaMessage::from(i64::MIN))
+        );
+        assert_eq!(LuaMessage::from("2").to_integer(RoundMode::Floor), None);
+    }
+
+    #[test]
+    fn as_u64_bits() {
+        assert_eq!(LuaMessage::Integer(-1).as_u64_bits(), Some(u64::MAX));
+        assert_eq!(LuaMessage::Integer(i64::MIN).as_u64_bits(), Some(1 << 63));
+        assert_eq!(LuaMessage::Integer(42).as_u64_bits(), Some(42));
+        assert_eq!(LuaMessage::Number(1.0).as_u64_bits(), None);
+    }
+
+    #[test]
+    fn pairs_to_table() {
+        let pairs = lua_array![
+            lua_array!["a", 1],
+            lua_array![2, lua_table! { "x" => true }],
+            lua_array!["a", 3]
+        ];
+        assert_eq!(
+            pairs.pairs_to_table(),
+            Some(lua_table! { "a" => 3, "2" => lua_table! { "x" => true } })
+        );
+        assert_eq!(lua_table! {}.pairs_to_table(), Some(lua_table! {}));
+        assert_eq!(lua_array![lua_array!["a"]].pairs_to_table(), None);
+        assert_eq!(
+            lua_array![lua_array![lua_array![], 1]].pairs_to_table(),
+            None
+        );
+        assert_eq!(LuaMessage::from("a").pairs_to_table(), None);
+    }
+
+    #[test]
+    fn from_rows() {
+        let row = |id: i64, name: &str| {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), LuaMessage::from(id));
+            row.insert("name".to_string(), LuaMessage::from(name));
+            row
+        };
+        let rows = LuaMessage::from(vec![row(1, "foo"), row(2, "bar")]);
+        assert_eq!(
+            rows,
+            lua_array![
+                lua_table! { "id" => 1, "name" => "foo" },
+                lua_table! { "id" => 2, "name" => "bar" },
+            ]
+        );
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let names: Function = ctx
+                .load("return function(rows) return #rows, rows[1].name .. rows[2].name end")
+                .eval()
+                .unwrap();
+            let (n, names): (i64, String) = names.call(rows).unwrap();
+            assert_eq!(n, 2);
+            assert_eq!(names, "foobar");
+        })
+    }
+
+    #[test]
+    fn from_results() {
+        let results: Vec<Result<i64, String>> = vec![Ok(1), Err("bad input".to_string()), Ok(3)];
+
+        assert_eq!(
+            LuaMessage::from(results),
+            LuaMessage::Array(vec![
+                LuaMessage::Integer(1),
+                LuaMessage::error("bad input"),
+                LuaMessage::Integer(3),
+            ])
+        );
+    }
+
+    #[test]
+    fn from_path() {
+        let path = PathBuf::from("/tmp/foo.lua");
+        assert_eq!(
+            LuaMessage::from(path.as_path()),
+            LuaMessage::from("/tmp/foo.lua")
+        );
+        assert_eq!(
+            LuaMessage::from(path.clone()),
+            LuaMessage::from("/tmp/foo.lua")
+        );
+        assert_eq!(
+            LuaMessage::from_path_bytes(&path),
+            LuaMessage::Bytes(b"/tmp/foo.lua".to_vec())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn from_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"/tmp/\xffoo"));
+        assert_eq!(LuaMessage::from(path), LuaMessage::from("/tmp/\u{fffd}oo"));
+        assert_eq!(
+            LuaMessage::from_path_bytes(path),
+            LuaMessage::Bytes(b"/tmp/\xffoo".to_vec())
+        );
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let v = LuaMessage::from_path_bytes(path).to_lua(ctx).unwrap();
+            assert_eq!(
+                LuaMessage::from_lua(v, ctx).unwrap(),
+                LuaMessage::Bytes(b"/tmp/\xffoo".to_vec())
+            );
+        })
+    }
+
+    #[test]
+    fn as_display_cow() {
+        let msg = LuaMessage::from("hello");
+        match msg.as_display_cow() {
+            Cow::Borrowed(x) => assert_eq!(x, "hello"),
+            Cow::Owned(_) => panic!("expect a borrowed string"),
+        }
+        assert_eq!(LuaMessage::from(1).as_display_cow(), "1");
+        assert_eq!(LuaMessage::Nil.as_display_cow(), "nil");
+
+        // unquoted, unlike `Display`
+        assert_eq!(LuaMessage::from("a\"b").as_display_cow(), "a\"b");
+        assert_eq!(LuaMessage::from("a\"b").to_string(), r#""a\"b""#);
+        assert_eq!(lua_array!["x"].as_display_cow(), r#"{"x"}"#);
+    }
+
+    #[test]
+    fn approx_size() {
+        let base = mem::size_of::<LuaMessage>();
+        assert_eq!(LuaMessage::from(1).approx_size(), base);
+        assert_eq!(LuaMessage::from("abc").approx_size(), base + 3);
+        assert_eq!(
+            LuaMessage::Array(vec![LuaMessage::from("abc"), LuaMessage::Nil]).approx_size(),
+            3 * base + 3
+        );
+
+        let mut t = HashMap::new();
+        t.insert("key".to_string(), LuaMessage::from("value"));
+        assert_eq!(
+            LuaMessage::Table(t).approx_size(),
+            2 * base + mem::size_of::<String>() + 3 + 5
+        );
+    }
+
+    #[test]
+    fn deep_len() {
+        assert_eq!(LuaMessage::Nil.deep_len(), 1);
+        assert_eq!(lua_array![].deep_len(), 1);
+
+        let msg = lua_table! {
+            "id" => 1,
+            "tags" => lua_array!["a", "b"],
+            "owner" => lua_table! { "name" => "foo", "roles" => lua_array![] },
+        };
+        // the table, `id`, `tags` and its 2 elements, `owner` and its 2 entries
+        assert_eq!(msg.deep_len(), 8);
+    }
+
+    #[test]
+    fn into_indexed_tables() {
+        let msg = lua_table! { "items" => lua_array!["a", lua_array![true]] };
+        assert_eq!(
+            msg.into_indexed_tables(),
+            lua_table! {
+                "items" => lua_table! { "1" => "a", "2" => lua_table! { "1" => true } },
+            }
+        );
+    }
+
+    #[test]
+    fn from_pairs() {
+        let pairs = vec![
+            (LuaMessage::from(1), LuaMessage::from("one")),
+            (LuaMessage::from("name"), LuaMessage::from("foo")),
+            (LuaMessage::from(2.0), LuaMessage::from("two")),
+        ];
+        assert_eq!(
+            LuaMessage::try_from(pairs),
+            Ok(lua_table! { "1" => "one", "name" => "foo", "2" => "two" })
+        );
+
+        for key in [LuaMessage::Nil, LuaMessage::from(f64::NAN), lua_array![1]] {
+            assert!(LuaMessage::try_from(vec![(key, LuaMessage::from(1))]).is_err());
+        }
+        assert_eq!(
+            LuaMessage::try_from(vec![(LuaMessage::from(true), LuaMessage::from(1))]),
+            Err(conversion_error(
+                "a valid table key",
+                &LuaMessage::from(true)
+            ))
+        );
+    }
+
+    #[test]
+    fn to_string_map() {
+        let msg = lua_table! {
+            "name" => "foo",
+            "count" => 2,
+            "ratio" => 0.5,
+            "enabled" => true,
+        };
+        let map = msg.to_string_map().unwrap();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map["name"], "foo");
+        assert_eq!(map["count"], "2");
+        assert_eq!(map["ratio"], "0.5");
+        assert_eq!(map["enabled"], "true");
+
+        let nested = lua_table! { "items" => lua_array![1] };
+        assert_eq!(
+            nested.to_string_map(),
+            Err(conversion_error("a scalar for key items", &lua_array![1]))
+        );
+        assert_eq!(
+            LuaMessage::from(1).to_string_map(),
+            Err(conversion_error("a table", &LuaMessage::from(1)))
+        );
+    }
+
+    #[test]
+    fn to_sorted_string_map() {
+        let msg = lua_table! { "PORT" => 5432, "HOST" => "localhost", "DEBUG" => false };
+        let map = msg.to_sorted_string_map().unwrap();
+        assert_eq!(
+            map.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("DEBUG".to_string(), "false".to_string()),
+                ("HOST".to_string(), "localhost".to_string()),
+                ("PORT".to_string(), "5432".to_string()),
+            ]
+        );
+        assert!(lua_table! { "a" => lua_array![] }
+            .to_sorted_string_map()
+            .is_err());
+    }
+
+    #[test]
+    fn to_env_pairs() {
+        let mut t = HashMap::new();
+        t.insert("HOST".to_string(), LuaMessage::from("localhost"));
+        t.insert("PORT".to_string(), LuaMessage::from(5432));
+        t.insert("RATIO".to_string(), LuaMessage::from(0.5));
+        assert_eq!(
+            LuaMessage::Table(t.clone()).to_env_pairs(),
+            Ok(vec![
+                ("HOST".to_string(), "localhost".to_string()),
+                ("PORT".to_string(), "5432".to_string()),
+                ("RATIO".to_string(), "0.5".to_string()),
+            ])
+        );
+
+        t.insert("NESTED".to_string(), LuaMessage::Table(HashMap::new()));
+        assert!(LuaMessage::Table(t).to_env_pairs().is_err());
+        assert!(LuaMessage::from(1).to_env_pairs().is_err());
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn round_to() {
+        assert_eq!(
+            LuaMessage::from(3.14159).round_to(2),
+            LuaMessage::from(3.14)
+        );
+        assert_eq!(LuaMessage::from(2.5).round_to(0), LuaMessage::from(3.0));
+        assert_eq!(
+            LuaMessage::from(-0.125).round_to(2),
+            LuaMessage::from(-0.13)
+        );
+        assert_eq!(LuaMessage::from(7).round_to(2), LuaMessage::from(7));
+        assert_eq!(
+            LuaMessage::from(3.14159).round_to(400),
+            LuaMessage::from(3.14159)
+        );
+        assert_eq!(
+            LuaMessage::from(3.14159).round_to(u32::MAX),
+            LuaMessage::from(3.14159)
+        );
+        assert_eq!(
+            LuaMessage::from(1e300).round_to(10),
+            LuaMessage::from(1e300)
+        );
+        assert_eq!(
+            LuaMessage::from(-1e300).round_to(10),
+            LuaMessage::from(-1e300)
+        );
+        assert_eq!(
+            LuaMessage::from("3.14159").round_to(2),
+            LuaMessage::from("3.14159")
+        );
+    }
+
+    #[test]
+    fn compact() {
+        let mut inner = Vec::with_capacity(1024);
+        inner.push(LuaMessage::from(1));
+        let mut t = HashMap::with_capacity(1024);
+        t.insert("inner".to_string(), LuaMessage::Array(inner));
+        let mut msg = LuaMessage::Table(t);
+
+        msg.compact();
+
+        if let LuaMessage::Table(t) = &msg {
+            assert!(t.capacity() < 1024);
+            if let LuaMessage::Array(inner) = &t["inner"] {
+                assert!(inner.capacity() < 1024);
+            } else {
+                panic!("should be an array");
+            }
+        } else {
+            panic!("should be a table");
+        }
+    }
+
+    #[test]
+    fn tag() {
+        let mut t = HashMap::new();
+        t.insert("__type".to_string(), LuaMessage::from("ping"));
+        t.insert("seq".to_string(), LuaMessage::from(1));
+        let msg = LuaMessage::Table(t);
+        assert_eq!(msg.tag(), Some("ping"));
+        assert_eq!(msg.tag_in("kind"), None);
+        assert_eq!(LuaMessage::from("ping").tag(), None);
+
+        let msg = LuaMessage::Table(HashMap::new()).with_tag_in("kind", "pong");
+        assert_eq!(msg.tag_in("kind"), Some("pong"));
+        assert_eq!(msg.tag(), None);
+        assert_eq!(msg.with_tag("ping").tag(), Some("ping"));
+    }
+
+    #[test]
+    fn keys_and_values() {
+        let mut t = HashMap::new();
+        t.insert("a".to_string(), LuaMessage::from(1));
+        t.insert("b".to_string(), LuaMessage::from(2));
+        t.insert("c".to_string(), LuaMessage::from(3));
+        let msg = LuaMessage::Table(t);
+
+        let mut keys = msg.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+
+        let mut values: Vec<i64> = msg
+            .values()
+            .unwrap()
+            .into_iter()
+            .map(|v| match v {
+                LuaMessage::Integer(x) => *x,
+                _ => panic!("unexpected value {:?}", v),
+            })
+            .collect();
+        values.sort();
+        assert_eq!(values, vec![1, 2, 3]);
+
+        assert_eq!(LuaMessage::from("x").keys(), None);
+        assert_eq!(LuaMessage::from("x").values(), None);
+    }
+
+    #[test]
+    fn get_ci() {
+        let mut t = HashMap::new();
+        t.insert("Content-Type".to_string(), LuaMessage::from("text/html"));
+        t.insert("X-Id".to_string(), LuaMessage::from("a"));
+        t.insert("x-id".to_string(), LuaMessage::from("b"));
+        let headers = LuaMessage::Table(t);
+
+        assert_eq!(
+            headers.get_ci("content-type"),
+            Some(&LuaMessage::from("text/html"))
+        );
+        assert_eq!(headers.get_ci("x-id"), Some(&LuaMessage::from("b")));
+        assert_eq!(headers.get_ci("X-ID"), Some(&LuaMessage::from("a")));
+        assert_eq!(headers.get_ci("accept"), None);
+        assert_eq!(LuaMessage::from("x").get_ci("x"), None);
+    }
+
+    #[test]
+    fn path_get() {
+        let item = |name: &str| {
+            let mut t = HashMap::new();
+            t.insert("name".to_string(), LuaMessage::from(name));
+            LuaMessage::Table(t)
+        };
+        let mut t = HashMap::new();
+        t.insert(
+            "items".to_string(),
+            LuaMessage::Array(vec![item("apple"), item("banana")]),
+        );
+        let msg = LuaMessage::Table(t);
+
+        assert_eq!(
+            msg.path_get("items.1.name"),
+            Some(&LuaMessage::from("banana"))
+        );
+        assert_eq!(
+            msg.path_get("items.0.name"),
+            Some(&LuaMessage::from("apple"))
+        );
+        assert_eq!(msg.path_get("items.2.name"), None);
+        assert_eq!(msg.path_get("items.first"), None);
+        assert_eq!(msg.path_get("items.0.name.x"), None);
+        assert_eq!(msg.path_get("missing"), None);
+    }
+
+    #[test]
+    fn leaves() {
+        assert_eq!(
+            LuaMessage::from(1).leaves(),
+            vec![(String::new(), &LuaMessage::from(1))]
+        );
+
+        let msg = lua_table! {
+            "id" => 1,
+            "user" => lua_table! { "name" => "foo", "tags" => lua_array!["a", "b"] },
+            "items" => lua_array![lua_table! { "price" => 2.5 }, lua_array![]],
+        };
+        let leaves = msg.leaves();
+        let paths: Vec<&str> = leaves.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "id",
+                "items.0.price",
+                "user.name",
+                "user.tags.0",
+                "user.tags.1"
+            ]
+        );
+        for (path, value) in leaves {
+            assert_eq!(msg.path_get(&path), Some(value));
+        }
+    }
+
+    #[test]
+    fn eq_ignoring() {
+        let event = |id: i64, ts: i64| {
+            let mut inner = HashMap::new();
+            inner.insert("ts".to_string(), LuaMessage::from(ts));
+            inner.insert("name".to_string(), LuaMessage::from("login"));
+            let mut t = HashMap::new();
+            t.insert("id".to_string(), LuaMessage::from(id));
+            t.insert(
+                "events".to_string(),
+                LuaMessage::Array(vec![LuaMessage::Table(inner)]),
+            );
+            LuaMessage::Table(t)
+        };
+
+        assert!(event(1, 100).eq_ignoring(&event(1, 200), &["ts"]));
+        assert!(!event(1, 100).eq_ignoring(&event(1, 200), &[]));
+        assert!(!event(1, 100).eq_ignoring(&event(2, 200), &["ts"]));
+        assert!(event(1, 100).eq_ignoring(&event(2, 200), &["ts", "id"]));
+    }
+
+    #[test]
+    fn into_ordered_pairs() {
+        let msg = lua_table! { "b" => 2, "c" => lua_array![3], "a" => 1 };
+        assert_eq!(
+            msg.into_ordered_pairs(),
+            vec![
+                ("a".to_string(), LuaMessage::from(1)),
+                ("b".to_string(), LuaMessage::from(2)),
+                ("c".to_string(), lua_array![3]),
+            ]
+        );
+        assert!(lua_array![1].into_ordered_pairs().is_empty());
+    }
+
+    #[test]
+    fn content_hash() {
+        let mut a = HashMap::new();
+        let mut b = HashMap::new();
+        for i in 0..32 {
+            a.insert(i.to_string(), lua_array![i, lua_table! { "x" => 0.0 }]);
+        }
+        for i in (0..32).rev() {
+            b.insert(i.to_string(), lua_array![i, lua_table! { "x" => -0.0 }]);
+        }
+        let (a, b) = (LuaMessage::Table(a), LuaMessage::Table(b));
+        assert_eq!(a, b);
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        assert_ne!(
+            lua_table! { "a" => 1, "b" => 2 }.content_hash(),
+            lua_table! { "a" => 2, "b" => 1 }.content_hash()
+        );
+        assert_ne!(
+            lua_array![1, 2].content_hash(),
+            lua_array![2, 1].content_hash()
+        );
+        assert_ne!(
+            LuaMessage::from(1).content_hash(),
+            LuaMessage::from(1.0).content_hash()
+        );
+    }
+
+    #[test]
+    fn structural_eq() {
+        let arr = lua_array![1, 2];
+        let table = lua_table! { "1" => 1, "2" => 2 };
+        assert_ne!(arr, table);
+        assert!(arr.structural_eq(&table));
+        assert!(table.structural_eq(&arr));
+        assert!(lua_table! { "xs" => arr.clone() }.structural_eq(&lua_table! { "xs" => table }));
+
+        assert!(!arr.structural_eq(&lua_table! { "1" => 1, "2" => 3 }));
+        assert!(!arr.structural_eq(&lua_table! { "0" => 1, "1" => 2 }));
+        assert!(!arr.structural_eq(&lua_table! { "1" => 1, "2" => 2, "x" => 3 }));
+    }
+
+    #[test]
+    fn redact() {
+        let mut credentials = HashMap::new();
+        credentials.insert("user".to_string(), LuaMessage::from("alice"));
+        credentials.insert("password".to_string(), LuaMessage::from("hunter2"));
+        let mut t = HashMap::new();
+        t.insert(
+            "logins".to_string(),
+            LuaMessage::Array(vec![LuaMessage::Table(credentials)]),
+        );
+        let msg = LuaMessage::Table(t);
+
+        let redacted = msg.redact(&["password"]);
+        assert_eq!(
+            redacted.path_get("logins.0.password"),
+            Some(&LuaMessage::from("***"))
+        );
+        assert_eq!(
+            redacted.path_get("logins.0.user"),
+            Some(&LuaMessage::from("alice"))
+        );
+        assert_eq!(
+            msg.path_get("logins.0.password"),
+            Some(&LuaMessage::from("hunter2"))
+        );
+    }
+
+    #[test]
+    fn rename_keys() {
+        let mut mapping = HashMap::new();
+        mapping.insert("user_id".to_string(), "userId".to_string());
+        let msg = lua_table! {
+            "user_id" => 1,
+            "name" => "foo",
+            "friends" => lua_array![lua_table! { "user_id" => 2 }],
+        };
+
+        assert_eq!(
+            msg.rename_keys(&mapping),
+            lua_table! {
+                "userId" => 1,
+                "name" => "foo",
+                "friends" => lua_array![lua_table! { "user_id" => 2 }],
+            }
+        );
+        assert_eq!(
+            msg.rename_keys_recursive(&mapping),
+            lua_table! {
+                "userId" => 1,
+                "name" => "foo",
+                "friends" => lua_array![lua_table! { "userId" => 2 }],
+            }
+        );
+    }
 
-impl From<String> for LuaMessage {
-    fn from(s: String) -> Self {
-        LuaMessage::String(s)
+    #[test]
+    fn truthy() {
+        assert!(!LuaMessage::Nil.truthy());
+        assert!(!LuaMessage::from(false).truthy());
+        assert!(LuaMessage::from(0).truthy());
+        assert!(LuaMessage::from("").truthy());
+
+        let empty_table = LuaMessage::Table(HashMap::new());
+        let empty_array = LuaMessage::Array(vec![]);
+        assert!(empty_table.truthy());
+        assert!(empty_table.is_empty());
+        assert!(empty_array.truthy());
+        assert!(empty_array.is_empty());
     }
-}
 
-macro_rules! lua_message_convert_int {
-    ($x:ty) => {
-        impl From<$x> for LuaMessage {
-            fn from(s: $x) -> Self {
-                LuaMessage::Integer(i64::from(s))
+    #[test]
+    fn to_columns() {
+        let row = |name: &str, age: Option<i64>| {
+            let mut t = HashMap::new();
+            t.insert("name".to_string(), LuaMessage::from(name));
+            if let Some(age) = age {
+                t.insert("age".to_string(), LuaMessage::from(age));
             }
-        }
-    };
-}
+            LuaMessage::Table(t)
+        };
+        let rows = LuaMessage::Array(vec![row("a", Some(1)), row("b", None), row("c", Some(3))]);
 
-lua_message_convert_int!(i8);
-lua_message_convert_int!(u8);
-lua_message_convert_int!(i16);
-lua_message_convert_int!(u16);
-lua_message_convert_int!(i32);
-lua_message_convert_int!(u32);
-lua_message_convert_int!(i64);
+        let columns = rows.to_columns().unwrap();
+        assert_eq!(columns.len(), 2);
+        assert_eq!(
+            columns["name"],
+            vec![
+                LuaMessage::from("a"),
+                LuaMessage::from("b"),
+                LuaMessage::from("c")
+            ]
+        );
+        assert_eq!(
+            columns["age"],
+            vec![LuaMessage::from(1), LuaMessage::Nil, LuaMessage::from(3)]
+        );
 
-impl From<usize> for LuaMessage {
-    fn from(s: usize) -> Self {
-        LuaMessage::Integer(s as i64)
+        assert_eq!(
+            LuaMessage::Array(vec![row("a", None), LuaMessage::from(1)]).to_columns(),
+            None
+        );
+        assert_eq!(row("a", None).to_columns(), None);
     }
-}
 
-impl From<isize> for LuaMessage {
-    fn from(s: isize) -> Self {
-        LuaMessage::Integer(s as i64)
+    #[test]
+    fn try_cmp() {
+        assert_eq!(
+            LuaMessage::from(1).try_cmp(&LuaMessage::from(1.5)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            LuaMessage::from("b").try_cmp(&LuaMessage::from("a")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(LuaMessage::from(1).try_cmp(&LuaMessage::from("1")), None);
+        assert_eq!(
+            LuaMessage::from(f64::NAN).try_cmp(&LuaMessage::from(1)),
+            None
+        );
     }
-}
 
-impl From<HashMap<String, LuaMessage>> for LuaMessage {
-    fn from(s: HashMap<String, LuaMessage>) -> Self {
-        LuaMessage::Table(s)
-    }
-}
+    #[test]
+    fn sort_array() {
+        let ints =
+            |xs: &[i64]| LuaMessage::Array(xs.iter().map(|&x| LuaMessage::from(x)).collect());
 
-macro_rules! lua_message_convert_float {
-    ($x:ty) => {
-        impl From<$x> for LuaMessage {
-            fn from(s: $x) -> Self {
-                LuaMessage::Number(f64::from(s))
-            }
-        }
-    };
-}
+        let mut msg = ints(&[3, 1, 2]);
+        msg.sort_array_by(|a, b| b.try_cmp(a).unwrap());
+        assert_eq!(msg, ints(&[3, 2, 1]));
 
-lua_message_convert_float!(f32);
-lua_message_convert_float!(f64);
+        msg.sort_array();
+        assert_eq!(msg, ints(&[1, 2, 3]));
 
-impl<'lua> FromLua<'lua> for LuaMessage {
-    fn from_lua(v: Value<'lua>, ctx: Context<'lua>) -> LuaResult<LuaMessage> {
-        match v {
-            Value::String(x) => {
-                let re = Regex::new(r"__suspended__(.+)").unwrap();
-                let s = Value::String(x);
-                if let Some(cap) = re.captures(&String::from_lua(s.clone(), ctx)?) {
-                    let tid = cap.get(1).unwrap().as_str();
-                    Ok(LuaMessage::ThreadYield(tid.to_string()))
-                } else {
-                    Ok(LuaMessage::String(String::from_lua(s.clone(), ctx)?))
-                }
-            }
-            Value::Integer(n) => Ok(LuaMessage::Integer(n as i64)),
-            Value::Number(n) => Ok(LuaMessage::Number(n as f64)),
-            Value::Boolean(b) => Ok(LuaMessage::Boolean(b)),
-            Value::Nil => Ok(LuaMessage::Nil),
-            Value::Table(t) => Ok(LuaMessage::Table(HashMap::from_lua(Value::Table(t), ctx)?)),
-            Value::Error(err) => {
-                panic!("Lua error: {:?}", err);
-            }
-            _ => unimplemented!(),
-        }
+        let mut s = LuaMessage::from("cba");
+        s.sort_array();
+        assert_eq!(s, LuaMessage::from("cba"));
     }
-}
 
-impl<'lua> ToLua<'lua> for LuaMessage {
-    fn to_lua(self, ctx: Context<'lua>) -> LuaResult<Value<'lua>> {
-        match self {
-            LuaMessage::String(x) => Ok(Value::String(ctx.create_string(&x)?)),
-            LuaMessage::Integer(x) => Ok(Value::Integer(x)),
-            LuaMessage::Number(x) => Ok(Value::Number(x)),
-            LuaMessage::Boolean(x) => Ok(Value::Boolean(x)),
-            LuaMessage::Nil => Ok(Value::Nil),
-            LuaMessage::Table(x) => Ok(Value::Table(ctx.create_table_from(x)?)),
+    #[test]
+    fn concat() {
+        let a = LuaMessage::Array(vec![1.into(), 2.into()]);
+        let b = LuaMessage::Array(vec![3.into(), 4.into()]);
+        assert_eq!(
+            a.concat(b),
+            LuaMessage::Array(vec![1.into(), 2.into(), 3.into(), 4.into()])
+        );
 
-            // TODO: passing rust error to lua error?
-            _ => unimplemented!(),
-        }
+        assert_eq!(
+            LuaMessage::Array(vec![]).concat(LuaMessage::from(1)),
+            LuaMessage::error("can only concat two arrays")
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rlua::Lua;
-    use std::mem::discriminant;
+    #[test]
+    fn with_defaults() {
+        let table = |pairs: Vec<(&str, LuaMessage)>| {
+            LuaMessage::Table(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+        };
+        let defaults = table(vec![
+            ("name", "app".into()),
+            (
+                "server",
+                table(vec![("host", "localhost".into()), ("port", 80.into())]),
+            ),
+        ]);
+        let config = table(vec![("server", table(vec![("port", 8080.into())]))]);
+
+        assert_eq!(
+            config.with_defaults(defaults),
+            table(vec![
+                ("name", "app".into()),
+                (
+                    "server",
+                    table(vec![("host", "localhost".into()), ("port", 8080.into())]),
+                ),
+            ])
+        );
+
+        assert_eq!(
+            LuaMessage::from(1).with_defaults(table(vec![])),
+            LuaMessage::from(1)
+        );
+        assert_eq!(
+            LuaMessage::Nil.with_defaults(LuaMessage::from(1)),
+            LuaMessage::from(1)
+        );
+    }
 
     #[test]
-    fn constructors() {
-        assert_eq!(LuaMessage::from(42), LuaMessage::Integer(42));
-        assert_eq!(LuaMessage::from(0), LuaMessage::Integer(0));
+    fn chunks() {
+        let msg = LuaMessage::Array((1..=5).map(LuaMessage::from).collect());
         assert_eq!(
-            LuaMessage::from("foo"),
-            LuaMessage::String("foo".to_string())
+            msg.chunks(2),
+            Some(vec![
+                LuaMessage::Array(vec![1.into(), 2.into()]),
+                LuaMessage::Array(vec![3.into(), 4.into()]),
+                LuaMessage::Array(vec![5.into()]),
+            ])
         );
-        assert_eq!(LuaMessage::from(42.5), LuaMessage::Number(42.5));
-        assert_eq!(LuaMessage::from(true), LuaMessage::Boolean(true));
+        assert_eq!(msg.chunks(0), None);
+        assert_eq!(LuaMessage::from(1).chunks(2), None);
+    }
+
+    #[test]
+    fn partition() {
+        let is_even = |v: &LuaMessage| matches!(v, LuaMessage::Integer(x) if x % 2 == 0);
+        let (evens, odds) = lua_array![1, 2, 3, 4, 5].partition(is_even);
+        assert_eq!(evens, lua_array![2, 4]);
+        assert_eq!(odds, lua_array![1, 3, 5]);
+
+        let (v, rest) = LuaMessage::from(2).partition(is_even);
+        assert_eq!(v, LuaMessage::from(2));
+        assert_eq!(rest, lua_array![]);
+    }
+
+    #[test]
+    fn map_rows() {
+        let row = |id: i64, name: &str| {
+            let mut t = HashMap::new();
+            t.insert("id".to_string(), LuaMessage::from(id));
+            t.insert("name".to_string(), LuaMessage::from(name));
+            LuaMessage::Table(t)
+        };
+        let rows = LuaMessage::Array(vec![row(1, "a"), LuaMessage::from("skip"), row(2, "b")]);
+
+        let mapped: Vec<(i64, String)> =
+            rows.map_rows(|row| match (row.path_get("id")?, row.path_get("name")?) {
+                (LuaMessage::Integer(id), LuaMessage::String(name)) => Some((*id, name.clone())),
+                _ => None,
+            });
+        assert_eq!(mapped, vec![(1, "a".to_string()), (2, "b".to_string())]);
+        assert!(LuaMessage::Nil.map_rows(|_| Some(())).is_empty());
+    }
 
+    #[test]
+    fn display() {
         let mut t = HashMap::new();
-        t.insert("bar".to_string(), LuaMessage::from("abc"));
-        let mut t2 = HashMap::new();
-        t2.insert("bar".to_string(), LuaMessage::from("abc"));
-        assert_eq!(LuaMessage::from(t), LuaMessage::Table(t2));
+        t.insert("b".to_string(), LuaMessage::from("x"));
+        t.insert(
+            "a".to_string(),
+            LuaMessage::Array(vec![1.into(), 2.5.into()]),
+        );
+        assert_eq!(
+            LuaMessage::Table(t).to_string(),
+            r#"{a = {1, 2.5}, b = "x"}"#
+        );
+        assert_eq!(
+            lua_table! { "first name" => "x", "end" => 1, "_id2" => 2, "2" => 3 }.to_string(),
+            r#"{["2"] = 3, _id2 = 2, ["end"] = 1, ["first name"] = "x"}"#
+        );
+        assert_eq!(
+            LuaMessage::UserData {
+                type_name: "Point".to_string(),
+                repr: "Point(1, 2)".to_string()
+            }
+            .to_string(),
+            "Point(1, 2)"
+        );
+    }
+
+    #[test]
+    fn to_lua_userdata() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let msg = LuaMessage::UserData {
+                type_name: "Point".to_string(),
+                repr: "Point(1, 2)".to_string(),
+            };
+            let err = msg.to_lua(ctx).unwrap_err().to_string();
+            assert!(
+                err.contains("can't recreate userdata of type Point"),
+                "{}",
+                err
+            );
+        })
     }
 
     #[test]
@@ -201,6 +2491,62 @@ mod tests {
         })
     }
 
+    #[test]
+    fn to_lua_nested_error() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            ctx.set_named_registry_value(SEQUENCE_ARRAYS, true).unwrap();
+            let msg = lua_table! {
+                "ok" => 1,
+                "err" => LuaMessage::error("boom"),
+                "items" => lua_array![LuaMessage::error_with_code(404, "not found")],
+            };
+            let inspect: Function = ctx
+                .load(
+                    r#"
+                return function(t)
+                    return {type(t.err), tostring(t.err), t.items[1].code, t.items[1].message}
+                end
+                "#,
+                )
+                .eval()
+                .unwrap();
+            let res: LuaMessage = inspect.call(msg.clone()).unwrap();
+            assert_eq!(
+                res,
+                lua_array!["userdata", "runtime error: boom", 404, "not found"]
+            );
+
+            let back = LuaMessage::from_lua(msg.to_lua(ctx).unwrap(), ctx).unwrap();
+            assert_eq!(
+                back.path_get("items.0"),
+                Some(&LuaMessage::error_with_code(404, "not found"))
+            );
+            assert_eq!(back.path_get("err"), Some(&LuaMessage::error("boom")));
+        })
+    }
+
+    #[test]
+    fn from_lua_callback_error() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let fail = ctx
+                .create_function(|_, ()| -> LuaResult<()> {
+                    Err(LuaError::RuntimeError("bad input".to_string()))
+                })
+                .unwrap();
+            ctx.globals().set("fail", fail).unwrap();
+            let err: Value = ctx
+                .load("local _, err = pcall(fail) return err")
+                .eval()
+                .unwrap();
+            assert_eq!(
+                LuaMessage::from_lua(err, ctx).unwrap(),
+                LuaMessage::error("bad input")
+            );
+        })
+    }
+
     #[test]
     fn from_lua() {
         // we only check if they have the correct variant
@@ -241,15 +2587,246 @@ mod tests {
         })
     }
 
-    #[should_panic]
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn from_bytes() {
+        let msg = LuaMessage::from(bytes::Bytes::from_static(b"\xffab"));
+        assert_eq!(msg, LuaMessage::Bytes(vec![0xff, b'a', b'b']));
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let len: Function = ctx.load("return function(s) return #s end").eval().unwrap();
+            assert_eq!(len.call::<_, i64>(msg).unwrap(), 3);
+        });
+    }
+
+    #[cfg(feature = "i128")]
+    #[test]
+    fn wide_integer() {
+        let values = [LuaMessage::from(i64::MAX), LuaMessage::from(i64::MAX)];
+        let sum: i128 = values.iter().filter_map(LuaMessage::as_i128).sum();
+        let sum = LuaMessage::from(sum);
+        assert_eq!(sum, LuaMessage::WideInteger(2 * i128::from(i64::MAX)));
+        assert_eq!(sum.to_string(), "18446744073709551614");
+
+        // back in range, the result is an `Integer` again
+        let half = LuaMessage::from(sum.as_i128().unwrap() / 2);
+        assert_eq!(half, LuaMessage::Integer(i64::MAX));
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            assert!(sum.to_lua(ctx).is_err());
+            assert!(matches!(half.to_lua(ctx), Ok(Value::Integer(i64::MAX))));
+        });
+    }
+
+    #[test]
+    fn from_lua_thread_yield() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let from = |s: &str| {
+                LuaMessage::from_lua(Value::String(ctx.create_string(s).unwrap()), ctx).unwrap()
+            };
+            assert_eq!(
+                from("__suspended__7"),
+                LuaMessage::ThreadYield("7".to_string())
+            );
+            assert_eq!(from("__suspended__"), LuaMessage::from("__suspended__"));
+            assert_eq!(from("x__suspended__7"), LuaMessage::from("x__suspended__7"));
+        });
+    }
+
+    #[test]
+    fn from_lua_number_subtypes() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let eval = |src: &str| {
+                LuaMessage::from_lua(ctx.load(src).eval::<Value>().unwrap(), ctx).unwrap()
+            };
+            assert_eq!(eval("7 // 2"), LuaMessage::Integer(3));
+            assert_eq!(eval("7.0 // 2"), LuaMessage::Number(3.0));
+            assert_eq!(eval("6 / 2"), LuaMessage::Number(3.0));
+            assert_eq!(eval("5 & 3"), LuaMessage::Integer(1));
+            assert_eq!(eval("1 << 62"), LuaMessage::Integer(1 << 62));
+            assert_eq!(eval("~0"), LuaMessage::Integer(-1));
+        })
+    }
+
+    #[test]
+    fn array_round_trip() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let seq: Value = ctx.load("{ 1, 2 }").eval().unwrap();
+            assert_eq!(
+                LuaMessage::from_lua(seq, ctx).unwrap(),
+                lua_table! { "1" => 1, "2" => 2 }
+            );
+
+            ctx.set_named_registry_value(SEQUENCE_ARRAYS, true).unwrap();
+            let arr = LuaMessage::Array(vec![LuaMessage::from(1), LuaMessage::from("foo")]);
+            let v = arr.clone().to_lua(ctx).unwrap();
+            assert_eq!(LuaMessage::from_lua(v, ctx).unwrap(), arr);
+
+            let sparse: Value = ctx.load("{ [1] = 1, [3] = 3 }").eval().unwrap();
+            let mut t = HashMap::new();
+            t.insert("1".to_string(), LuaMessage::from(1));
+            t.insert("3".to_string(), LuaMessage::from(3));
+            assert_eq!(
+                LuaMessage::from_lua(sparse, ctx).unwrap(),
+                LuaMessage::Table(t)
+            );
+        })
+    }
+
+    #[test]
+    fn from_lua_error_path() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            ctx.set_named_registry_value(SEQUENCE_ARRAYS, true).unwrap();
+            let v: Value = ctx
+                .load("return {items = {{price = 1}, {price = coroutine.create(print)}}}")
+                .eval()
+                .unwrap();
+            let err = LuaMessage::from_lua(v, ctx).unwrap_err().to_string();
+            assert!(
+                err.contains("failed converting key 'items[2].price': error converting Lua thread"),
+                "{}",
+                err
+            );
+
+            let v: Value = ctx.load("return {[{}] = 1}").eval().unwrap();
+            let err = LuaMessage::from_lua(v, ctx).unwrap_err().to_string();
+            assert!(!err.contains("failed converting key"), "{}", err);
+        })
+    }
+
     #[test]
     fn from_lua_error() {
         use rlua::Error;
 
         let lua = Lua::new();
         lua.context(|ctx| {
-            &LuaMessage::from_lua(Value::Error(Error::RuntimeError("foo".to_string())), ctx)
+            assert_eq!(
+                LuaMessage::from_lua(Value::Error(Error::RuntimeError("foo".to_string())), ctx)
+                    .unwrap(),
+                LuaMessage::error("foo")
+            );
+        })
+    }
+
+    #[test]
+    fn from_lua_host_error() {
+        #[derive(Debug, PartialEq)]
+        enum DbError {
+            NotFound,
+        }
+        impl fmt::Display for DbError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "not found")
+            }
+        }
+        impl StdError for DbError {}
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let find = ctx
+                .create_function(|_, ()| -> LuaResult<()> {
+                    Err(LuaError::external(DbError::NotFound))
+                })
+                .unwrap();
+            ctx.globals().set("find", find).unwrap();
+
+            let err: Value = ctx
+                .load("local ok, err = pcall(find); return err")
+                .eval()
+                .unwrap();
+            let msg = LuaMessage::from_lua(err, ctx).unwrap();
+            assert_eq!(msg.downcast_error::<DbError>(), Some(&DbError::NotFound));
+
+            // the host error survives a trip back into Lua
+            let back = msg.clone().to_lua(ctx).unwrap();
+            assert_eq!(LuaMessage::from_lua(back, ctx).unwrap(), msg);
+
+            // with a code as well
+            let coded = match LuaMessage::from_error(DbError::NotFound) {
+                LuaMessage::Error {
+                    message, source, ..
+                } => LuaMessage::Error {
+                    code: Some(404),
+                    message,
+                    source,
+                },
+                _ => unreachable!(),
+            };
+            let back = LuaMessage::from_lua(coded.clone().to_lua(ctx).unwrap(), ctx).unwrap();
+            assert_eq!(back, coded);
+            assert_eq!(back.downcast_error::<DbError>(), Some(&DbError::NotFound));
+        })
+    }
+
+    #[test]
+    fn error_with_code() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let v: Value = ctx
+                .load(r#"return {__lua_error = true, code = 404, message = "not found"}"#)
+                .eval()
+                .unwrap();
+            let err = LuaMessage::from_lua(v, ctx).unwrap();
+            assert_eq!(err, LuaMessage::error_with_code(404, "not found"));
+            assert_eq!(err.error_code(), Some(404));
+            assert_eq!(
+                err.to_string(),
+                r#"error({code = 404, message = "not found"})"#
+            );
+
+            // scripts can read both fields
+            let f: Function = ctx
+                .load("return function(e) return e.code, e.message end")
+                .eval()
+                .unwrap();
+            let (code, message) = f.call::<_, (i64, String)>(err.clone()).unwrap();
+            assert_eq!((code, message.as_str()), (404, "not found"));
+
+            // and it converts back to the same error
+            let back = err.clone().to_lua(ctx).unwrap();
+            assert_eq!(LuaMessage::from_lua(back, ctx).unwrap(), err);
+
+            // a table without the marker is a plain table
+            let v: Value = ctx
+                .load(r#"return {code = 404, message = "not found"}"#)
+                .eval()
+                .unwrap();
+            assert_eq!(
+                LuaMessage::from_lua(v, ctx).unwrap(),
+                lua_table! { "code" => 404, "message" => "not found" }
+            );
+        })
+    }
+
+    #[test]
+    fn function_round_trip() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let f: Value = ctx
+                .load("return function(x) return x * 2 end")
+                .eval()
+                .unwrap();
+            let msg = LuaMessage::from_lua(f.clone(), ctx).unwrap();
+            assert!(matches!(msg, LuaMessage::Function(_)));
+            // the same function gets the same handle
+            assert_eq!(LuaMessage::from_lua(f, ctx).unwrap(), msg);
+
+            let f = match msg.to_lua(ctx).unwrap() {
+                Value::Function(f) => f,
+                v => panic!("expect a function, got {:?}", v),
+            };
+            assert_eq!(f.call::<_, i64>(21).unwrap(), 42);
+
+            let unknown = LuaMessage::Function(FunctionHandle(100))
+                .to_lua(ctx)
                 .unwrap();
+            assert!(matches!(unknown, Value::Nil));
         })
     }
 }
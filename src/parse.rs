@@ -0,0 +1,44 @@
+//! Lua literal parsing for `LuaMessage`, enabled with the `parse` feature.
+use rlua::{Error as LuaError, Lua, StdLib};
+
+use crate::message::{LuaMessage, SEQUENCE_ARRAYS};
+
+impl LuaMessage {
+    /// Parse a Lua literal such as `{a = 1, b = {"x"}}`, the format produced by `Display`.
+    ///
+    /// The literal is evaluated as an expression in a fresh Lua state with an empty environment,
+    /// so it can't reach the host. Sequences such as `{"x", 2.5}` are parsed as `Array`s.
+    pub fn parse_lua(literal: &str) -> Result<LuaMessage, LuaError> {
+        let lua = Lua::new_with(StdLib::BASE);
+        lua.context(|ctx| {
+            ctx.set_named_registry_value(SEQUENCE_ARRAYS, true)?;
+            ctx.load(&format!("return {}", literal))
+                .set_name("literal")?
+                .set_environment(ctx.create_table()?)?
+                .eval()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lua() {
+        assert_eq!(
+            LuaMessage::parse_lua(r#"{a = 1, b = {"x", 2.5}, ["c d"] = true}"#).unwrap(),
+            lua_table! {
+                "a" => 1,
+                "b" => lua_array!["x", 2.5],
+                "c d" => true,
+            }
+        );
+
+        let msg = lua_table! { "name" => "foo", "items" => lua_array![1, 2], "for" => "x y" };
+        assert_eq!(LuaMessage::parse_lua(&msg.to_string()).unwrap(), msg);
+
+        assert!(LuaMessage::parse_lua("{a = ").is_err());
+        assert!(LuaMessage::parse_lua("os.exit()").is_err());
+    }
+}
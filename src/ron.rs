@@ -0,0 +1,44 @@
+//! RON conversions for `LuaMessage`, enabled with the `ron-format` feature.
+//!
+//! Tables are RON maps with string keys, arrays are sequences and `Nil` is the unit value `()`.
+//! `Bytes` are written as base64 strings by RON and read back as a `String`.
+use ron::error::SpannedError;
+
+use crate::message::LuaMessage;
+
+impl LuaMessage {
+    /// Serialize the message into a RON string.
+    pub fn to_ron_string(&self) -> Result<String, ron::Error> {
+        ron::to_string(self)
+    }
+
+    /// Deserialize a message from a RON string.
+    pub fn from_ron_str(s: &str) -> Result<LuaMessage, SpannedError> {
+        ron::from_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ron_round_trip() {
+        let msg = lua_table! {
+            "name" => "foo",
+            "count" => 2,
+            "ratio" => 0.5,
+            "items" => lua_array![true, LuaMessage::Nil, lua_table! { "id" => -1 }],
+            "nested" => lua_table! { "empty" => lua_table! {} },
+        };
+
+        let ron = msg.to_ron_string().unwrap();
+        assert_eq!(LuaMessage::from_ron_str(&ron).unwrap(), msg);
+
+        assert_eq!(
+            LuaMessage::from_ron_str(r#"{"xs": [1, 2.5, "a", ()]}"#).unwrap(),
+            lua_table! { "xs" => lua_array![1, 2.5, "a", LuaMessage::Nil] }
+        );
+        assert!(LuaMessage::from_ron_str("{").is_err());
+    }
+}
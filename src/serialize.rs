@@ -0,0 +1,167 @@
+//! `serde` support for `LuaMessage`.
+//!
+//! `Table` is mapped to a map, `Array` to a sequence and `Nil` to unit (`null` in JSON).
+//! `Boolean` is mapped to a boolean and never coerced to or from a number.
+//!
+//! Together with the conversion from Lua, this gives a canonical rule for Lua tables: a table with
+//! contiguous `1..n` integer keys becomes a JSON array, any other table becomes a JSON object with
+//! stringified keys. Decoding gives back an `Array` or a `Table` with the same string keys, so repeated
+//! round trips through Lua and JSON are stable. An empty table is an object. Table keys are serialized in sorted order.
+//!
+//! `Error` is mapped to a map with the single reserved key `__lua_error` holding the error message,
+//! e.g. `{"__lua_error": "not found"}` in JSON, so errors can travel through systems that only transport tables.
+//! An error with a code holds a `code`/`message` map instead, e.g. `{"__lua_error": {"code": 404, "message": "not found"}}`.
+//! Such a map is deserialized back to `Error`; the host error source, if any, is not preserved.
+//!
+//! `ThreadYield`, `UserData` and `Function` only make sense inside a Lua actor and can't be serialized.
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, Serializer};
+
+use crate::message::{LuaMessage, ERROR_KEY};
+
+use std::collections::HashMap;
+use std::fmt;
+
+impl Serialize for LuaMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LuaMessage::String(x) => serializer.serialize_str(x),
+            LuaMessage::Integer(x) => serializer.serialize_i64(*x),
+            #[cfg(feature = "i128")]
+            LuaMessage::WideInteger(x) => serializer.serialize_i128(*x),
+            LuaMessage::Number(x) => serializer.serialize_f64(*x),
+            LuaMessage::Boolean(x) => serializer.serialize_bool(*x),
+            LuaMessage::Nil => serializer.serialize_unit(),
+            LuaMessage::Table(x) => {
+                // sort keys so equal tables always serialize the same way
+                let mut entries: Vec<_> = x.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+            LuaMessage::Array(x) => {
+                let mut seq = serializer.serialize_seq(Some(x.len()))?;
+                for v in x {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            LuaMessage::Bytes(x) => serializer.serialize_bytes(x),
+            LuaMessage::ThreadYield(_) => Err(ser::Error::custom("can't serialize ThreadYield")),
+            LuaMessage::Error { code, message, .. } => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                match code {
+                    Some(code) => {
+                        let mut coded = HashMap::new();
+                        coded.insert("code".to_string(), LuaMessage::Integer(*code));
+                        coded.insert("message".to_string(), LuaMessage::from(message.as_str()));
+                        map.serialize_entry(ERROR_KEY, &LuaMessage::Table(coded))?;
+                    }
+                    None => map.serialize_entry(ERROR_KEY, message)?,
+                }
+                map.end()
+            }
+            LuaMessage::UserData { .. } => Err(ser::Error::custom("can't serialize UserData")),
+            LuaMessage::Function(_) => Err(ser::Error::custom("can't serialize Function")),
+        }
+    }
+}
+
+struct LuaMessageVisitor;
+
+impl<'de> Visitor<'de> for LuaMessageVisitor {
+    type Value = LuaMessage;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a value representable in Lua")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Boolean(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Integer(v))
+    }
+
+    // Lua integers are signed 64-bit, larger values become a `Number`.
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<LuaMessage, E> {
+        if v <= i64::MAX as u64 {
+            Ok(LuaMessage::Integer(v as i64))
+        } else {
+            Ok(LuaMessage::Number(v as f64))
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Number(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::String(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::String(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Bytes(v))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Nil)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<LuaMessage, D::Error> {
+        LuaMessage::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<LuaMessage, A::Error> {
+        let mut arr = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(v) = seq.next_element()? {
+            arr.push(v);
+        }
+        Ok(LuaMessage::Array(arr))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<LuaMessage, A::Error> {
+        let mut table = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((k, v)) = map.next_entry::<String, LuaMessage>()? {
+            table.insert(k, v);
+        }
+        if table.len() == 1 {
+            match table.get(ERROR_KEY) {
+                Some(LuaMessage::String(message)) => {
+                    return Ok(LuaMessage::error(message.as_str()))
+                }
+                Some(LuaMessage::Table(coded)) if coded.len() == 2 => {
+                    if let (Some(LuaMessage::Integer(code)), Some(LuaMessage::String(message))) =
+                        (coded.get("code"), coded.get("message"))
+                    {
+                        return Ok(LuaMessage::error_with_code(*code, message.as_str()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(LuaMessage::Table(table))
+    }
+}
+
+impl<'de> Deserialize<'de> for LuaMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LuaMessage, D::Error> {
+        deserializer.deserialize_any(LuaMessageVisitor)
+    }
+}
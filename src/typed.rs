@@ -0,0 +1,115 @@
+use ::actix::prelude::*;
+use futures::Future;
+
+use crate::actor::LuaActor;
+use crate::error::LuaActorError;
+use crate::message::LuaMessage;
+
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+
+/// A `LuaActor` address sending `Req` requests and expecting `Resp` replies.
+///
+/// Requests are converted with `Into<LuaMessage>` and replies with `TryFrom<LuaMessage>`,
+/// so a reply of the wrong shape is reported as an error at the call site.
+pub struct TypedLuaActor<Req, Resp> {
+    addr: Addr<LuaActor>,
+    marker: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<Req, Resp> TypedLuaActor<Req, Resp>
+where
+    Req: Into<LuaMessage>,
+    Resp: TryFrom<LuaMessage> + 'static,
+    Resp::Error: Into<LuaActorError>,
+{
+    pub fn new(addr: Addr<LuaActor>) -> Self {
+        TypedLuaActor {
+            addr,
+            marker: PhantomData,
+        }
+    }
+
+    /// The underlying address, e.g. to send other messages.
+    pub fn addr(&self) -> &Addr<LuaActor> {
+        &self.addr
+    }
+
+    /// Send `req` and convert the reply.
+    ///
+    /// Mailbox errors are returned as `LuaActorError::Mailbox`.
+    pub fn ask(&self, req: Req) -> impl Future<Item = Resp, Error = LuaActorError> {
+        self.addr
+            .send(req.into())
+            .map_err(|e| LuaActorError::Mailbox(e.to_string()))
+            .and_then(|res| Resp::try_from(res).map_err(Into::into))
+    }
+}
+
+impl<Req, Resp> Clone for TypedLuaActor<Req, Resp> {
+    fn clone(&self) -> Self {
+        TypedLuaActor {
+            addr: self.addr.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use std::collections::HashMap;
+
+    struct Add {
+        a: i64,
+        b: i64,
+    }
+
+    impl From<Add> for LuaMessage {
+        fn from(req: Add) -> LuaMessage {
+            let mut t = HashMap::new();
+            t.insert("a".to_string(), LuaMessage::from(req.a));
+            t.insert("b".to_string(), LuaMessage::from(req.b));
+            LuaMessage::Table(t)
+        }
+    }
+
+    #[test]
+    fn typed_ask() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg.a < 0 then
+                return "negative"
+            end
+            return ctx.msg.a + ctx.msg.b
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+        let adder: TypedLuaActor<Add, i64> = TypedLuaActor::new(addr);
+
+        let l = adder
+            .ask(Add { a: 1, b: 2 })
+            .join(adder.ask(Add { a: -1, b: 2 }).then(Ok));
+        Arbiter::spawn(
+            l.map(|(sum, mismatch)| {
+                assert_eq!(sum, 3);
+                assert_eq!(
+                    mismatch,
+                    Err(LuaActorError::Conversion(
+                        r#"expect an integer, got "negative""#.to_string()
+                    ))
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+}
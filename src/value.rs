@@ -0,0 +1,42 @@
+//! `serde_value::Value` conversions for `LuaMessage`, enabled with the `value` feature.
+use serde_value::{DeserializerError, SerializerError, Value};
+
+use crate::message::LuaMessage;
+
+impl LuaMessage {
+    /// Convert the message into a format-agnostic `serde_value::Value`.
+    pub fn to_serde_value(&self) -> Result<Value, SerializerError> {
+        serde_value::to_value(self)
+    }
+
+    /// Convert a `serde_value::Value`, e.g. deserialized from any serde format, into a message.
+    pub fn from_serde_value(v: Value) -> Result<LuaMessage, DeserializerError> {
+        v.deserialize_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serde_value_round_trip() {
+        let msg = lua_table! {
+            "name" => "foo",
+            "count" => 2,
+            "ratio" => 0.5,
+            "items" => lua_array![true, LuaMessage::Nil, LuaMessage::Bytes(vec![0xff])],
+            "nested" => lua_table! { "err" => LuaMessage::error_with_code(404, "not found") },
+        };
+
+        let value = msg.to_serde_value().unwrap();
+        match &value {
+            Value::Map(m) => assert_eq!(
+                m.get(&Value::String("count".to_string())),
+                Some(&Value::I64(2))
+            ),
+            v => panic!("unexpected value {:?}", v),
+        }
+        assert_eq!(LuaMessage::from_serde_value(value).unwrap(), msg);
+    }
+}
@@ -0,0 +1,73 @@
+//! `actix_web::HttpResponse` conversions for `LuaMessage`, enabled with the `actix-web` feature.
+use std::convert::TryFrom;
+
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+use crate::error::LuaActorError;
+use crate::message::LuaMessage;
+
+impl LuaMessage {
+    /// Convert a `{status = ..., headers = ..., body = ...}` result table into an `HttpResponse`.
+    ///
+    /// `status` defaults to 200 and `headers`, converted with `to_header_map`, to none.
+    /// A string or `Bytes` body is sent as is and a missing body is empty; other bodies are an error.
+    pub fn to_http_response(&self) -> Result<HttpResponse, LuaActorError> {
+        let table = match self {
+            LuaMessage::Table(x) => x,
+            _ => return Err(LuaActorError::Conversion("expect a table".to_string())),
+        };
+
+        let status = match table.get("status") {
+            None => StatusCode::OK,
+            Some(LuaMessage::Integer(x)) => u16::try_from(*x)
+                .ok()
+                .and_then(|x| StatusCode::from_u16(x).ok())
+                .ok_or_else(|| LuaActorError::Conversion(format!("invalid status {}", x)))?,
+            Some(v) => return Err(LuaActorError::Conversion(format!("invalid status {}", v))),
+        };
+
+        let mut res = HttpResponse::build(status);
+        if let Some(headers) = table.get("headers") {
+            for (name, value) in headers.to_header_map()?.iter() {
+                res.append_header((name.clone(), value.clone()));
+            }
+        }
+        match table.get("body") {
+            None => Ok(res.finish()),
+            Some(LuaMessage::String(x)) => Ok(res.body(x.clone())),
+            Some(LuaMessage::Bytes(x)) => Ok(res.body(x.clone())),
+            Some(v) => Err(LuaActorError::Conversion(format!("invalid body {}", v))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+
+    #[test]
+    fn to_http_response() {
+        let msg = lua_table! {
+            "status" => 201,
+            "headers" => lua_table! { "location" => "/items/1" },
+            "body" => "created"
+        };
+        let res = msg.to_http_response().unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get("location").unwrap(), "/items/1");
+        assert_eq!(&res.into_body().try_into_bytes().unwrap()[..], b"created");
+
+        let res = lua_table! {}.to_http_response().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().is_empty());
+        assert!(res.into_body().try_into_bytes().unwrap().is_empty());
+
+        assert!(lua_table! { "status" => 1000 }.to_http_response().is_err());
+        assert!(lua_table! { "body" => lua_array![1] }
+            .to_http_response()
+            .is_err());
+        assert!(LuaMessage::from("x").to_http_response().is_err());
+    }
+}